@@ -1,5 +1,7 @@
 pub mod structs;
 pub mod game_lib;
+pub mod prediction;
+pub mod interpolation;
 
 pub mod splash_screen;
 pub mod intro_screen;
@@ -7,6 +9,7 @@ pub mod menu_screen;
 pub mod select_char_screen;
 pub mod loading_screen;
 pub mod game_screen;
+pub mod connect_error_screen;
 
 #[cfg(test_character)]
 pub mod test_character;
\ No newline at end of file
@@ -0,0 +1,50 @@
+use heredian_lib::allegro_safe::*;
+use super::structs::*;
+
+pub struct ConnectErrorScreen;
+
+impl ConnectErrorScreen {
+    /// Shows what went wrong with the server, and why, until a key is pressed or the window is closed.
+    pub fn show(state: &GameState, title: &str, reason: &str) {
+        let fonte = al_load_font("assets/Fonts/font_info.ttf", 16, 0);
+
+        let mut evento = AlEvent::default();
+
+        loop {
+            al_wait_for_event(state.event_queue, &mut evento);
+
+            match evento.get_type() {
+                AlEventType::ALLEGRO_EVENT_TIMER => {
+                    if !al_is_event_queue_empty(state.event_queue) {
+                        continue;
+                    }
+
+                    al_clear_to_color(al_map_rgb(0, 0, 0));
+
+                    al_draw_text(
+                        fonte,
+                        al_map_rgb(255, 255, 255),
+                        (state.width / 2) as f32,
+                        (state.height / 2 - 30) as f32,
+                        ALLEGRO_ALIGN_CENTRE,
+                        title);
+
+                    al_draw_text(
+                        fonte,
+                        al_map_rgb(255, 80, 80),
+                        (state.width / 2) as f32,
+                        (state.height / 2) as f32,
+                        ALLEGRO_ALIGN_CENTRE,
+                        reason);
+
+                    al_flip_display();
+                },
+                AlEventType::ALLEGRO_EVENT_KEY_DOWN => break,
+                AlEventType::ALLEGRO_EVENT_DISPLAY_CLOSE => break,
+                _ => ()
+            }
+        }
+
+        al_destroy_font(fonte);
+    }
+}
@@ -1,9 +1,12 @@
 use std::ptr;
+use std::time::Duration;
 
 use heredian_lib::{LIFELESS, CHARS};
 use heredian_lib::file_manager::*;
+use heredian_lib::net::Conditions;
 use heredian_lib::allegro_safe::*;
 use super::structs::*;
+use super::interpolation::DEFAULT_DELAY;
 
 const WIDTH: i32 = 800;
 const HEIGHT: i32 = 600;
@@ -23,6 +26,9 @@ pub fn gdp_init() -> GameState {
         total_enemies: 0,
         scale: 1.5,
         boss_char_id: config_file.get("boss_char_id").expect("boss_char_id não encontrado."),
+        net_conditions: Conditions::from_config(&config_file),
+        prediction: Default::default(),
+        interp_delay: config_file.get("interp_delay").map(Duration::from_millis).unwrap_or(DEFAULT_DELAY),
         screen: ptr::null(),
         event_queue: ptr::null(),
         timer: ptr::null(),
@@ -34,7 +40,7 @@ pub fn gdp_init() -> GameState {
         height: HEIGHT,
         title: TITLE,
 
-        local_char_id: 0,
+        local_char_id: Default::default(),
         nclose_game: 0,
         
         opchar: None,
@@ -1,65 +1,114 @@
 use std::cmp::{Ord, Ordering};
 use std::net::{SocketAddr, SocketAddrV4, Ipv4Addr};
 use std::time::{Duration, Instant};
-use std::thread;
 
-use heredian_lib::*;
-use heredian_lib::net::{Client};
+use heredian_lib::net::{Client, NetError, Tcp, Simulated};
+use heredian_lib::protocol::{DeltaDecoder, GameMessage, handshake};
 use heredian_lib::allegro_safe::*;
 use crate::heredian::structs::*;
+use crate::heredian::connect_error_screen::*;
 
 pub struct GameScreen {
-    client: Option<Client<PacketCharInfo>>
+    client: Option<Client<GameMessage>>,
+    /// Set when the connection to the server is lost during the game.
+    error: Option<NetError>,
+    /// Reason the server gave for dropping the player during the game.
+    dropped: Option<String>,
+    /// Messages that came along with the welcome, handled on the first update.
+    backlog: Vec<GameMessage>,
+    /// Rebuilds the entity states the server sends as deltas.
+    deltas: DeltaDecoder
 }
 
 impl GameScreen {
 
     pub fn new() -> GameScreen {
         GameScreen {
-            client: None
+            client: None,
+            error: None,
+            dropped: None,
+            backlog: Vec::new(),
+            deltas: DeltaDecoder::new()
         }
     }
 
-    fn init(&mut self, state: &mut GameState) {
+    fn init(&mut self, state: &mut GameState) -> Result<(), NetError> {
         let address = SocketAddr::V4(SocketAddrV4::new(Ipv4Addr::new(127, 0, 0, 1), 34000));
-        let mut client = Client::<PacketCharInfo>::connect(&address);
-        client.start();
-
-        let now = Instant::now();
-
-        let char_info =
-            loop {
-                if now.elapsed() < Duration::from_secs(30) {
-                    match client.try_recv() {
-                        Ok(info) if info.numchar == 0 => break info,
-                        Ok(_) | Err(_) => ()
-                    }
-                } else {
-                    panic!("Character creation timed out.");
-                }
-
-                thread::yield_now();
+        let mut client =
+            match state.net_conditions.clone() {
+                Some(conditions) => Client::<GameMessage>::connect_with(&Simulated::new(Tcp, conditions), &address, handshake())?,
+                None => Client::<GameMessage>::connect(&address, handshake())?,
             };
+        client.start();
 
         let ambient = Scene::load(state.opmap, state.width, state.height);
-        state.local_char_id = char_info.idchar as usize;
-        
-        println!("New ID: {:?}", char_info);
 
         let mut local_char = Char::load(state.opchar.unwrap() as i32);
         local_char.idmap = state.opmap;
-        local_char.obj.id = char_info.idchar as i32;
-        local_char.obj.idchar = char_info.idchar as i32;
         local_char.obj.x = ambient.ex as f32;
         local_char.obj.y = ambient.ey as f32;
+
+        client.send(GameMessage::Join {
+            numchar: local_char.obj.r#type as i16,
+            idmap: local_char.idmap as i16,
+            interp_delay: state.interp_delay.as_millis() as u16,
+        });
+
+        let deadline = Instant::now() + Duration::from_secs(30);
+
+        // the welcome, then the snapshot of the map, then updates
+        let mut welcome = None;
+        let mut snapshot = None;
+
+        while snapshot.is_none() {
+            let now = Instant::now();
+            if now >= deadline {
+                return Err(NetError::Timeout);
+            }
+
+            let msgs =
+                match client.recv_timeout(deadline - now)? {
+                    Some(GameMessage::Batch(msgs)) => msgs,
+                    Some(msg) => vec![msg],
+                    None => continue,
+                };
+
+            for msg in msgs {
+                match msg {
+                    GameMessage::Welcome { idchar, .. } => welcome = Some(idchar),
+                    // an invalid join, or a full server
+                    GameMessage::Disconnect { reason } => return Err(NetError::Rejected(reason)),
+                    // the snapshot may come in chunks, the first one ends the handshake
+                    msg @ GameMessage::Snapshot { .. } if welcome.is_some() && snapshot.is_none() => snapshot = Some(msg),
+                    msg if snapshot.is_some() => self.backlog.push(msg),
+                    _ => (),
+                }
+            }
+        }
+
+        let idchar = welcome.unwrap();
+        self.backlog.insert(0, snapshot.unwrap());
+
+        state.local_char_id = idchar;
+        
+        println!("New ID: {}", idchar);
+
+        local_char.obj.id = idchar.index as i32;
+        local_char.obj.idchar = idchar;
         
         state.list_chars.push(local_char);
         state.ambient = Some(ambient);
 
         self.client = Some(client);
+
+        Ok(())
     }
 
     fn close(&mut self, _state: &mut GameState) {
+        if let Some(client) = self.client.as_ref() {
+            client.send(GameMessage::Disconnect { reason: "Player left the game.".to_owned() });
+        }
+
         /*
         al_destroy_font(self.fonte);
         al_destroy_bitmap(self.image);
@@ -154,6 +203,29 @@ impl GameScreen {
             5.0,
             0,
             &format!("FPS: {:.3}", fps));
+
+        // writes the network stats below it
+        if let Some(client) = self.client.as_ref() {
+            let stats = client.stats();
+            let rtt = stats.rtt.map_or("?".to_owned(), |rtt| rtt.as_millis().to_string());
+
+            let lines = [
+                format!("RTT: {} ms", rtt),
+                format!("In: {:.0}/s {} KB", stats.received_per_sec, stats.bytes_received / 1024),
+                format!("Out: {:.0}/s {} KB", stats.sent_per_sec, stats.bytes_sent / 1024),
+                format!("Fila: {} Perdas: {}", stats.queue_depth, stats.dropped_sends),
+            ];
+
+            for (i, line) in lines.iter().enumerate() {
+                al_draw_text(
+                    fonte,
+                    al_map_rgb(255,255,255),
+                    630.0,
+                    20.0 + 15.0 * i as f32,
+                    0,
+                    line);
+            }
+        }
     }
 
     fn reset_camera(&self) {
@@ -198,6 +270,10 @@ impl GameScreen {
 
                     //self.recv_once(state);
                     self.update(state);
+                    if self.error.is_some() || self.dropped.is_some() {
+                        break;
+                    }
+
                     self.draw(state);
                 },
                 AlEventType::ALLEGRO_EVENT_DISPLAY_CLOSE => break,
@@ -224,17 +300,39 @@ impl GameScreen {
 
         match self.client.as_ref() {
             Some(client) => {
-                while let Ok(char_info) = client.try_recv() {
-                    //println!("id: {:#?}", &char_info);
-                    state.update_char(char_info);
+                for msg in self.backlog.drain(..) {
+                    on_message(state, &mut self.deltas, &mut self.dropped, msg);
+                }
+
+                loop {
+                    let msg =
+                        match client.try_recv() {
+                            Ok(Some(msg)) => msg,
+                            Ok(None) => break,
+                            Err(e) => {
+                                self.error = Some(e);
+                                return;
+                            }
+                        };
+
+                    on_message(state, &mut self.deltas, &mut self.dropped, msg);
+                }
+
+                if let Some(ack) = self.deltas.ack() {
+                    client.send(ack);
                 }
 
-                let mut should_send = state.update_local_char(client);
+                state.interpolate_chars();
+                state.update_lifeless();
+                state.remove_faded();
+
+                let mut should_send = state.update_local_char();
                 should_send |= self.try_ambient_change(state);
 
                 if should_send {
-                    let local_char = state.get_localchar_mut().expect("Cannot find local char.");
-                    local_char.send(client);
+                    let seq = state.push_input();
+                    let local_char = state.get_localchar().expect("Cannot find local char.");
+                    local_char.send(client, seq);
                 }
             },
             None => panic!("No channel available for propagation of local char's changes.")
@@ -246,8 +344,65 @@ impl GameScreen {
     }
 
     pub fn show(&mut self, state: &mut GameState) {
-        self.init(state);
-        self.run_loop(state);
-        self.close(state);
+        match self.init(state) {
+            Ok(()) => {
+                self.run_loop(state);
+
+                match (self.error.take(), self.dropped.take()) {
+                    (Some(e), _) => {
+                        state.connect_erro = true;
+                        ConnectErrorScreen::show(state, "Conexão com o servidor perdida.", &e.to_string());
+                    },
+                    (None, Some(reason)) => {
+                        state.connect_erro = true;
+                        ConnectErrorScreen::show(state, "Desconectado pelo servidor.", &reason);
+                    },
+                    (None, None) => self.close(state)
+                }
+            },
+            Err(e) => {
+                state.connect_erro = true;
+                ConnectErrorScreen::show(state, "Não foi possível conectar ao servidor.", &e.to_string());
+            }
+        }
     }
-}
\ No newline at end of file
+}
+
+/// Applies `msg` to the game; a `Disconnect` leaves its reason in `dropped`.
+fn on_message(state: &mut GameState, deltas: &mut DeltaDecoder, dropped: &mut Option<String>, msg: GameMessage) {
+    match msg {
+        GameMessage::EntityState(char_info) => {
+            //println!("id: {:#?}", &char_info);
+            state.update_char(char_info);
+        },
+        GameMessage::EntityDelta { seq, baseline, delta } => {
+            match deltas.decode(seq, baseline, &delta) {
+                Some(char_info) => state.update_char(char_info),
+                None => println!("Missing baseline {:?} for entity {}.", baseline, delta.idchar),
+            }
+        },
+        GameMessage::Snapshot { entities, projectiles } => {
+            for char_info in entities {
+                state.update_char(char_info);
+            }
+
+            for p in projectiles {
+                state.spawn_lifeless(p.idchar, p.lifelessid, (p.x, p.y, p.d));
+            }
+        },
+        GameMessage::EntityDespawn { idchar, reason } => {
+            deltas.forget(idchar);
+            state.despawn_char(idchar, reason);
+        },
+        GameMessage::InputAck { seq } => state.prediction.ack(seq),
+        GameMessage::ProjectileSpawn { idchar, lifelessid, x, y, d } => state.spawn_lifeless(idchar, lifelessid, (x, y, d)),
+        GameMessage::Chat { idchar, text } => println!("[{}] {}", idchar, text),
+        GameMessage::Disconnect { reason } => *dropped = Some(reason),
+        GameMessage::Batch(msgs) => {
+            for msg in msgs {
+                on_message(state, deltas, dropped, msg);
+            }
+        },
+        _ => ()
+    }
+}
@@ -0,0 +1,116 @@
+use std::collections::VecDeque;
+use std::time::{Duration, Instant};
+
+/// How far past the newest snapshot a position is extrapolated before it holds still.
+const MAX_EXTRAPOLATION: Duration = Duration::from_millis(100);
+
+/// Snapshots kept at most, in case nothing is sampled for a while.
+const MAX_SNAPSHOTS: usize = 32;
+
+/// Default of `interp_delay` in `assets/Configs/server.txt`.
+pub const DEFAULT_DELAY: Duration = Duration::from_millis(100);
+
+#[derive(Debug, Clone, Copy)]
+struct Snapshot {
+    time: Instant,
+    x: f32,
+    y: f32,
+}
+
+/// Positions of a remote entity, stamped with the time they were received.
+///
+/// Entities are drawn some delay in the past, where there usually are snapshots on
+/// both sides to interpolate between.
+#[derive(Default, Debug)]
+pub struct SnapshotBuffer {
+    snapshots: VecDeque<Snapshot>,
+}
+
+impl SnapshotBuffer {
+    pub fn push(&mut self, time: Instant, x: f32, y: f32) {
+        self.snapshots.push_back(Snapshot { time, x, y });
+
+        if self.snapshots.len() > MAX_SNAPSHOTS {
+            self.snapshots.pop_front();
+        }
+    }
+
+    /// Forgets every snapshot, e.g. when the entity is teleported to another map.
+    pub fn clear(&mut self) {
+        self.snapshots.clear();
+    }
+
+    /// Position at `time`, dropping the snapshots no longer needed.
+    pub fn sample(&mut self, time: Instant) -> Option<(f32, f32)> {
+        // keep the last snapshot before `time`, to interpolate from
+        while self.snapshots.len() > 2 && self.snapshots[1].time <= time {
+            self.snapshots.pop_front();
+        }
+
+        let first = *self.snapshots.front()?;
+        let last = *self.snapshots.back()?;
+
+        if time <= first.time {
+            return Some((first.x, first.y));
+        }
+
+        if time < last.time {
+            // the loop above left `time` between the first two
+            let next = self.snapshots[1];
+            let t = (time - first.time).as_secs_f32() / (next.time - first.time).as_secs_f32();
+            return Some((first.x + (next.x - first.x) * t, first.y + (next.y - first.y) * t));
+        }
+
+        if self.snapshots.len() < 2 {
+            return Some((last.x, last.y));
+        }
+
+        // late snapshot, keep going at the last known speed for a while
+        let span = (last.time - first.time).as_secs_f32();
+        let ahead = (time - last.time).min(MAX_EXTRAPOLATION).as_secs_f32();
+        let t = if span > 0.0 { ahead / span } else { 0.0 };
+
+        Some((last.x + (last.x - first.x) * t, last.y + (last.y - first.y) * t))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ms(millis: u64) -> Duration {
+        Duration::from_millis(millis)
+    }
+
+    #[test]
+    fn interpolates_between_snapshots() {
+        let start = Instant::now();
+        let mut buffer = SnapshotBuffer::default();
+        assert_eq!(buffer.sample(start), None);
+
+        buffer.push(start, 0.0, 0.0);
+        buffer.push(start + ms(100), 10.0, 20.0);
+        buffer.push(start + ms(200), 30.0, 20.0);
+
+        assert_eq!(buffer.sample(start), Some((0.0, 0.0)));
+        assert_eq!(buffer.sample(start + ms(50)), Some((5.0, 10.0)));
+        assert_eq!(buffer.sample(start + ms(150)), Some((20.0, 20.0)));
+        assert_eq!(buffer.snapshots.len(), 2);
+    }
+
+    #[test]
+    fn extrapolates_for_a_while() {
+        let start = Instant::now();
+        let mut buffer = SnapshotBuffer::default();
+
+        buffer.push(start, 0.0, 0.0);
+        assert_eq!(buffer.sample(start + ms(500)), Some((0.0, 0.0)));
+
+        buffer.push(start + ms(100), 10.0, 0.0);
+        assert_eq!(buffer.sample(start + ms(150)), Some((15.0, 0.0)));
+        assert_eq!(buffer.sample(start + ms(1000)), Some((20.0, 0.0)));
+
+        buffer.clear();
+        assert_eq!(buffer.sample(start), None);
+    }
+}
@@ -0,0 +1,73 @@
+use std::collections::VecDeque;
+
+use heredian_lib::protocol::seq_newer;
+
+/// Movement of one input sent to the server.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct PredictedInput {
+    pub seq: u16,
+    pub d2: i32,
+    pub step: i32,
+}
+
+/// Inputs of the local hero that the server has not processed yet.
+///
+/// They are applied locally as soon as they are sent and replayed on top of every
+/// authoritative position, so the hero never waits for the server to move.
+#[derive(Default, Debug)]
+pub struct Prediction {
+    next_seq: u16,
+    pending: VecDeque<PredictedInput>,
+}
+
+impl Prediction {
+    /// Numbers a new input, moving `step` towards `d2` (no movement when `step` is 0).
+    pub fn push(&mut self, d2: i32, step: i32) -> u16 {
+        let seq = self.next_seq;
+        self.next_seq = seq.wrapping_add(1);
+
+        self.pending.push_back(PredictedInput { seq, d2, step });
+        seq
+    }
+
+    /// The server processed every input up to `seq`.
+    pub fn ack(&mut self, seq: u16) {
+        while let Some(input) = self.pending.front() {
+            if seq_newer(input.seq, seq) {
+                break;
+            }
+
+            self.pending.pop_front();
+        }
+    }
+
+    /// Inputs to replay on top of the last authoritative position, oldest first.
+    pub fn pending(&self) -> impl Iterator<Item = &PredictedInput> {
+        self.pending.iter()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn ack_drops_processed_inputs() {
+        let mut prediction = Prediction { next_seq: 65534, ..Prediction::default() };
+
+        for d2 in 1..=4 {
+            prediction.push(d2, 3);
+        }
+
+        prediction.ack(65535);
+        let seqs: Vec<_> = prediction.pending().map(|input| input.seq).collect();
+        assert_eq!(seqs, vec![0, 1]);
+
+        // stale acks change nothing
+        prediction.ack(65534);
+        assert_eq!(prediction.pending().count(), 2);
+
+        prediction.ack(1);
+        assert_eq!(prediction.pending().next(), None);
+    }
+}
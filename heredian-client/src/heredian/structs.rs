@@ -1,15 +1,24 @@
 use std::ptr;
+use std::rc::Rc;
 use std::path::Path;
-use std::time::{Instant};
+use std::time::{Duration, Instant};
 
 use heredian_lib::*;
 use heredian_lib::allegro_safe::*;
+use heredian_lib::collision::{CollisionGrid, Sweep};
+use heredian_lib::entity::EntityId;
 use heredian_lib::file_manager::ConfigFile;
-use heredian_lib::net::{Client};
+use heredian_lib::net::{Client, Conditions};
+use heredian_lib::protocol::{DespawnReason, GameMessage, InputCommand, BUTTON_ATTACK, BUTTON_RUN};
+use crate::heredian::prediction::Prediction;
+use crate::heredian::interpolation::SnapshotBuffer;
 
 pub const VOLUME: f32 = 0.005;
 pub const FPS: f64 = 60.0;
 
+/// How long a killed enemy takes to fade out.
+pub const FADE_OUT: Duration = Duration::from_millis(500);
+
 #[derive(PartialEq)]
 #[derive(Copy, Clone)]
 pub enum OpcaoMenu {
@@ -45,7 +54,13 @@ pub struct GameState {
     pub last_time: f64,
 
     pub boss_char_id: usize,
-    pub local_char_id: usize,
+    pub local_char_id: EntityId,
+    /// Simulated network conditions, when enabled in `assets/Configs/server.txt`.
+    pub net_conditions: Option<Conditions>,
+    /// Inputs of the local hero not yet processed by the server.
+    pub prediction: Prediction,
+    /// How far in the past remote chars and enemies are drawn, from `interp_delay`.
+    pub interp_delay: Duration,
 
     pub list_chars: Vec<Char>,
     pub list_lifeless: Vec<Lifeless>,
@@ -108,35 +123,66 @@ impl GameState {
     }
 
     pub fn get_localchar(&self) -> Option<&Char> {
-        let fn_find = |v: &&Char| v.obj.id as usize == self.local_char_id;
+        let fn_find = |v: &&Char| v.obj.idchar == self.local_char_id;
         self.list_chars.iter().find(fn_find)
     }
 
     pub fn get_localchar_mut(&mut self) -> Option<&mut Char> {
         let local_char_id = self.local_char_id;
-        let fn_find = |v: &&mut Char| v.obj.id as usize == local_char_id;
+        let fn_find = |v: &&mut Char| v.obj.idchar == local_char_id;
         self.list_chars.iter_mut().find(fn_find)
     }
 
-    pub fn update_local_char(&mut self, client: &Client<PacketCharInfo>) -> bool {
-        let ambient = self.ambient.as_ref().unwrap();
+    /// Walls of the current map.
+    fn grid(&self) -> Rc<CollisionGrid> {
+        self.ambient.as_ref().unwrap().grid.clone()
+    }
 
-        let state_data = (
-            self.height,
-            ambient.w,
-            ambient.h,
-            ambient.wd,
-            ambient.hd,
-            ambient.model
-        );
+    pub fn update_local_char(&mut self) -> bool {
+        let grid = self.grid();
 
         let local_char = self.get_localchar_mut().expect("Cannot find local char.");
 
-        local_char.update_local(state_data) && !local_char.dead
+        local_char.update_local(&grid) && !local_char.dead
+    }
+
+    /// Numbers the input about to be sent for the local hero.
+    pub fn push_input(&mut self) -> u16 {
+        let local_char = self.get_localchar().expect("Cannot find local char.");
+
+        let step =
+            if local_char.is_moving() {
+                local_char.act[local_char.obj.a as usize].stepx
+            } else {
+                0
+            };
+
+        let d2 = local_char.obj.d2;
+        self.prediction.push(d2, step)
+    }
+
+    /// Replays the inputs the server has not processed on top of its position.
+    fn reconcile(&mut self) {
+        let grid = self.grid();
+        let inputs: Vec<_> = self.prediction.pending().cloned().collect();
+
+        let local_char = self.get_localchar_mut().expect("Cannot find local char.");
+
+        for input in inputs {
+            local_char.step(input.d2, input.step, &grid);
+        }
     }
 
     pub fn update_char(&mut self, char_info: PacketCharInfo) {
-        let fn_find = |v: &&mut Char| v.obj.idchar == char_info.idchar as i32;
+        let idchar = char_info.idchar;
+        let is_local = idchar == self.local_char_id;
+
+        // a slot the server gave again means its former entity is gone, even if we missed the despawn
+        let fn_stale = |other: EntityId| other != idchar && other.same_slot(idchar);
+        self.list_chars.retain(|c| !fn_stale(c.obj.idchar));
+        self.list_lifeless.retain(|l| !fn_stale(l.obj.idchar));
+
+        let fn_find = |v: &&mut Char| v.obj.idchar == idchar;
         match self.list_chars.iter_mut().find(fn_find) {
             Some(c) => {
                 if c.dead && char_info.numchar <= 4 {
@@ -144,7 +190,19 @@ impl GameState {
                     *c = new_char;
                 }
 
+                let same_map = c.idmap == char_info.idmap as i32;
+                let place = (char_info.idmap as i32, char_info.x as f32, char_info.y as f32);
                 c.update(char_info, self.local_char_id);
+
+                // the server position only applies on the map the hero is on, and the server
+                // decides which one that is once every gate crossing input is processed
+                if is_local && !c.dead {
+                    if same_map {
+                        self.reconcile();
+                    } else if self.prediction.pending().next().is_none() {
+                        self.change_ambient(place);
+                    }
+                }
             },
             None => {
                 let mut new_char = Char::load(char_info.numchar as i32);
@@ -154,6 +212,42 @@ impl GameState {
         }
     }
 
+    /// Forgets a char or enemy the server no longer tells about, with its projectiles.
+    /// The killed ones fade out first.
+    pub fn despawn_char(&mut self, idchar: EntityId, reason: DespawnReason) {
+        if idchar == self.local_char_id {
+            return;
+        }
+
+        self.list_lifeless.retain(|l| l.obj.idchar != idchar);
+
+        match reason {
+            DespawnReason::Killed => {
+                let fn_find = |v: &&mut Char| v.obj.idchar == idchar;
+                if let Some(c) = self.list_chars.iter_mut().find(fn_find) {
+                    c.fading.get_or_insert_with(Instant::now);
+                }
+            },
+            _ => self.list_chars.retain(|c| c.obj.idchar != idchar),
+        }
+    }
+
+    /// Drops the chars done fading out.
+    pub fn remove_faded(&mut self) {
+        self.list_chars.retain(|c| c.fading.is_none_or(|since| since.elapsed() < FADE_OUT));
+    }
+
+    /// Places remote chars and enemies `interp_delay` in the past.
+    pub fn interpolate_chars(&mut self) {
+        let now = Instant::now();
+        let time = now.checked_sub(self.interp_delay).unwrap_or(now);
+
+        for c in self.list_chars.iter_mut() {
+            c.interpolate(time);
+        }
+    }
+
+    /// Predicts the local hero crossing a gate, as the server does with the same input.
     pub fn try_change_ambient(&mut self) -> bool {
         let ambient = self.ambient.as_ref().unwrap();
         let local_char = self.get_localchar().unwrap();
@@ -161,21 +255,61 @@ impl GameState {
         let gate = ambient.crossed_gate(&local_char.obj);
         
         if let Some(gate) = gate {
-            let gate_info = (gate.ex, gate.ey, gate.ambient_id);
-            let local_char = self.get_localchar_mut().unwrap();
-
-            local_char.obj.x = gate_info.0 as f32;
-            local_char.obj.y = gate_info.1 as f32;
-            local_char.idmap = gate_info.2;
-
-            let new_ambient = Scene::load(gate_info.2, self.width, self.height);
-            self.ambient = Some(new_ambient);
+            let gate_info = (gate.ambient_id, gate.ex as f32, gate.ey as f32);
+            self.change_ambient(gate_info);
 
             true
         } else {
             false
         }
     }
+
+    /// Moves the local hero to `(x, y)` on map `idmap`.
+    fn change_ambient(&mut self, (idmap, x, y): (i32, f32, f32)) {
+        let local_char = self.get_localchar_mut().unwrap();
+
+        local_char.obj.x = x;
+        local_char.obj.y = y;
+        local_char.idmap = idmap;
+        local_char.list_lifeless.clear();
+
+        let new_ambient = Scene::load(idmap, self.width, self.height);
+        self.ambient = Some(new_ambient);
+        self.list_lifeless.clear();
+    }
+
+    /// Shows a projectile fired by another hero.
+    pub fn spawn_lifeless(&mut self, idchar: EntityId, lifelessid: i16, (x, y, d): (i16, i16, i16)) {
+        let fn_find = |v: &&Char| v.obj.idchar == idchar;
+        let idmap =
+            match self.list_chars.iter().find(fn_find) {
+                Some(c) => c.idmap,
+                None => return,
+            };
+
+        let mut lifeless = Lifeless::load(lifelessid as i32);
+        lifeless.idmap = idmap;
+        lifeless.obj.idchar = idchar;
+        lifeless.obj.d = d as i32;
+        lifeless.obj.x = x as f32;
+        lifeless.obj.y = y as f32;
+
+        self.list_lifeless.push(lifeless);
+    }
+
+    /// Moves the projectiles of other heroes on the current map, dropping the others.
+    pub fn update_lifeless(&mut self) {
+        let grid = self.grid();
+        let idmap = self.ambient.as_ref().unwrap().id;
+
+        self.list_lifeless.retain(|l| l.idmap == idmap);
+
+        for lifeless in self.list_lifeless.iter_mut() {
+            lifeless.update(&grid);
+        }
+
+        self.list_lifeless.retain(|l| !l.dead);
+    }
 }
 
 #[derive(Default, Debug)]
@@ -192,7 +326,7 @@ pub struct Sprite {
 #[derive(Default, Debug)]
 pub struct Object {
     pub id: i32,
-    pub idchar: i32,
+    pub idchar: EntityId,
     pub x: f32,
     pub y: f32,
     pub w: f32,
@@ -298,6 +432,10 @@ pub struct Char {
     pub idmap: i32,
     pub dead: bool,
     pub list_lifeless: Vec<Lifeless>,
+    /// Received positions, when this is a remote char or an enemy.
+    pub snapshots: SnapshotBuffer,
+    /// When the char was despawned, if it is fading out.
+    pub fading: Option<Instant>,
 }
 
 #[derive(Debug)]
@@ -326,7 +464,8 @@ pub struct Scene {
     pub id: i32,
     pub info: Info,
     pub image: *const AlBitmap,
-    pub model: *const AlBitmap,
+    /// Walls, the same the server has.
+    pub grid: Rc<CollisionGrid>,
     pub musicback: *const AlSample,
     pub w: i32,
     pub h: i32,
@@ -343,10 +482,6 @@ impl Drop for Scene {
             al_destroy_bitmap(self.image);
         }
 
-        if !self.model.is_null() {
-            al_destroy_bitmap(self.model);
-        }
-
         if !self.musicback.is_null() {
             al_destroy_sample(self.musicback);
         }
@@ -468,6 +603,25 @@ impl Sprite {
 }
 
 impl Object {
+    /// Hit box while doing `act`: the drawn size less its rebate, as the server has it.
+    pub fn hitbox(&self, act: &Action) -> (f32, f32, f32, f32) {
+        let sprite = &act.directions[0][0];
+        let (wd, hd) = ((self.w * sprite.w as f32) as i32, (self.h * sprite.h as f32) as i32);
+
+        (self.x, self.y, (wd - act.rebatex) as f32, (hd - act.rebatey) as f32)
+    }
+
+    /// Moves by `mov` while doing `act`, as far as the walls let the hit box go.
+    pub fn sweep(&mut self, act: &Action, mov: (i32, i32), grid: &CollisionGrid) -> Sweep {
+        let (x, y, w, h) = self.hitbox(act);
+        let moved = grid.sweep((x as i32, y as i32, w as i32, h as i32), mov);
+
+        self.x = moved.x as f32;
+        self.y = moved.y as f32;
+
+        moved
+    }
+
     pub fn from_config(config_file: &ConfigFile, r#type: i32) -> Object {
         Object {
             r#type: r#type,
@@ -484,7 +638,7 @@ impl Object {
             
             hd: 0f32,
             wd: 0f32,
-            idchar: 0,
+            idchar: EntityId::default(),
         }
     }
 }
@@ -663,7 +817,7 @@ impl Lifeless {
         al_destroy_bitmap(frame);
     }
 
-    pub fn update(&mut self, state_data: (i32, i32, i32, i32, i32, *const AlBitmap)) {
+    pub fn update(&mut self, grid: &CollisionGrid) {
         let d = match self.obj.d {
             DIRECTION_LEFT => 1,
             DIRECTION_RIGHT => 2,
@@ -676,103 +830,22 @@ impl Lifeless {
         let sprites = &act.directions[d];
         let sprite = sprites.first().unwrap();
 
-        let (old_x, old_y) = (self.obj.x, self.obj.y);
-
         self.obj.wd = self.obj.w * sprite.w as f32;
         self.obj.hd = self.obj.h * sprite.h as f32;
 
-        if self.obj.d == DIRECTION_UP {
-            self.obj.y -= act.stepy as f32;
-        }
-
-        if self.obj.d == DIRECTION_DOWN {
-            self.obj.y += act.stepy as f32;
-        }
-
-        if self.obj.d == DIRECTION_LEFT {
-            self.obj.x -= act.stepx as f32;
-        }
-
-        if self.obj.d == DIRECTION_RIGHT {
-            self.obj.x += act.stepx as f32;
-        }
-
-        // move back if collided
-        if self.collided(state_data) {
-            if self.obj.d == DIRECTION_UP {
-                self.obj.y += act.stepy as f32;
-            }
-
-            if self.obj.d == DIRECTION_DOWN {
-                self.obj.y -= act.stepy as f32;
-            }
-
-            if self.obj.d == DIRECTION_LEFT {
-                self.obj.x += act.stepx as f32;
-            }
-
-            if self.obj.d == DIRECTION_RIGHT {
-                self.obj.x -= act.stepx as f32;
-            }
-        }
+        let mov = match self.obj.d {
+            DIRECTION_UP => (0, -act.stepy),
+            DIRECTION_DOWN => (0, act.stepy),
+            DIRECTION_LEFT => (-act.stepx, 0),
+            DIRECTION_RIGHT => (act.stepx, 0),
+            _ => (0, 0)
+        };
 
-        // collided, because it wasn't able to move - so destroy it
-        if (old_x, old_y) == (self.obj.x, self.obj.y) {
+        // it ran into a wall - so destroy it
+        if self.obj.sweep(act, mov, grid).blocked() {
             self.dead = true;
         }
     }
-
-    pub fn collided(&self, state_data: (i32, i32, i32, i32, i32, *const AlBitmap)) -> bool {
-        let (
-            height, 
-            amb_w, 
-            amb_h, 
-            amb_wd, 
-            amb_hd, 
-            amb_model) = state_data;
-
-        if self.obj.y < 0.0 || self.obj.x < 0.0 {
-            return true;
-        }
-
-        if (self.obj.y + self.obj.hd) > height as f32 {
-            return true;
-        }
-
-        let colorwall = al_map_rgb(0, 0, 0);
-
-        let sx = amb_w as f32 / amb_wd as f32;
-        let sy = amb_h as f32 / amb_hd as f32;
-
-        let we = amb_wd as f32 * sx;
-        let he = amb_hd as f32 * sy;
-
-        let xup   = (self.obj.x + self.obj.wd) * sx;
-        let yup   = self.obj.y * sy;
-
-        let xdown = self.obj.x * sx;
-        let ydown = (self.obj.y + self.obj.hd) * sy;
-
-        if xdown >= 0.0 && ydown >= 0.0 && xup <= we && yup <= he {
-            if self.obj.d != DIRECTION_RIGHT {
-                let color = al_get_pixel(amb_model, xdown as i32, ydown as i32);
-                if colorwall == color {
-                    return true;
-                }
-            }
-
-            if self.obj.d != DIRECTION_LEFT {
-                let color = al_get_pixel(amb_model, xup as i32, ydown as i32);
-                if colorwall == color {
-                    return true;
-                }
-            }
-        } else {
-            return true;
-        }
-
-        return false;
-    }
 }
 
 impl Char {
@@ -794,26 +867,38 @@ impl Char {
             obj: obj,
             info: info,
             list_lifeless: Vec::with_capacity(10),
+            snapshots: SnapshotBuffer::default(),
+            fading: None,
         }
     }
 
-    pub fn update(&mut self, char_info: PacketCharInfo, local_char_id: usize) {
+    pub fn update(&mut self, char_info: PacketCharInfo, local_char_id: EntityId) {
         assert!(self.info.healt >= char_info.healt as i32);
 
         self.info.healt = char_info.healt as i32;
 
         if !char_info.exit {
             self.dead = false;
-            self.obj.id = char_info.idchar as i32;
-            self.obj.idchar = char_info.idchar as i32;
+            self.fading = None;
+            self.obj.id = char_info.idchar.index as i32;
+            self.obj.idchar = char_info.idchar;
             self.obj.a = char_info.a as i32;
             self.obj.d = char_info.d as i32;
-            if self.obj.idchar != local_char_id as i32 || self.idmap == char_info.idmap as i32 {
-                self.obj.x = char_info.x as f32;
-                self.obj.y = char_info.y as f32;
+            if self.obj.idchar == local_char_id {
+                if self.idmap == char_info.idmap as i32 {
+                    self.obj.x = char_info.x as f32;
+                    self.obj.y = char_info.y as f32;
+                }
+            } else {
+                // positioned by `interpolate`
+                if self.idmap != char_info.idmap as i32 {
+                    self.snapshots.clear();
+                }
+
+                self.snapshots.push(Instant::now(), char_info.x as f32, char_info.y as f32);
             }
             
-            if self.obj.idchar != local_char_id as i32 {
+            if self.obj.idchar != local_char_id {
                 self.info.stamina = char_info.stamina as i32;
                 self.idmap = char_info.idmap as i32;
             } else {
@@ -824,7 +909,15 @@ impl Char {
         }
     }
 
-    pub fn update_local(&mut self, state_data: (i32, i32, i32, i32, i32, *const AlBitmap)) -> bool {
+    /// Moves a remote char to where it was at `time`.
+    pub fn interpolate(&mut self, time: Instant) {
+        if let Some((x, y)) = self.snapshots.sample(time) {
+            self.obj.x = x;
+            self.obj.y = y;
+        }
+    }
+
+    pub fn update_local(&mut self, grid: &CollisionGrid) -> bool {
         let mut kb_state = AlKeyboardState::default();
         al_get_keyboard_state(&mut kb_state);
 
@@ -834,44 +927,38 @@ impl Char {
 
         if al_key_down(&mut kb_state, ALLEGRO_KEY_UP) {
             self.obj.d2 |= DIRECTION_UP;
-            self.obj.d = DIRECTION_UP;
         }
 
         if al_key_down(&mut kb_state, ALLEGRO_KEY_DOWN) {
             self.obj.d2 |= DIRECTION_DOWN;
-            self.obj.d = DIRECTION_DOWN;
         }
 
         if al_key_down(&mut kb_state, ALLEGRO_KEY_LEFT) {
             self.obj.d2 |= DIRECTION_LEFT;
-            self.obj.d = DIRECTION_LEFT;
         }
 
         if al_key_down(&mut kb_state, ALLEGRO_KEY_RIGHT) {
             self.obj.d2 |= DIRECTION_RIGHT;
-            self.obj.d = DIRECTION_RIGHT;
         }
 
         if al_key_down(&mut kb_state, ALLEGRO_KEY_D) {
-            self.obj.a2 |= 1;
+            self.obj.a2 |= BUTTON_RUN as u32;
         } else {
-            self.obj.a2 &= !1;
+            self.obj.a2 &= !(BUTTON_RUN as u32);
         }
 
+        // an attack goes on until its animation is over
         if al_key_down(&mut kb_state, ALLEGRO_KEY_F) {
-            self.obj.a2 |= 2;
+            self.obj.a2 |= BUTTON_ATTACK as u32;
         } else if !self.obj.lock {
-            self.obj.a2 &= !2;
+            self.obj.a2 &= !(BUTTON_ATTACK as u32);
         }
 
-        if self.obj.a2 & 2 != 0 {
-            self.obj.a = ACTION_ATTACK;
-        } else if self.obj.d2 == 0 {
-            self.obj.a = ACTION_IDLE;
-        } else if self.obj.a2 & 1 != 0 {
-            self.obj.a = ACTION_RUN;
-        } else {
-            self.obj.a = ACTION_WALK;
+        // the same the server makes of the command
+        let command = self.command();
+        self.obj.a = command.action();
+        if let Some(d) = command.facing() {
+            self.obj.d = d;
         }
 
         // direction to sprite set index
@@ -892,6 +979,11 @@ impl Char {
             self.info.healt = 0;
         }
 
+        if self.is_moving() {
+            let step = self.act[self.obj.a as usize].stepx;
+            self.step(self.obj.d2, step, grid);
+        }
+
         let act = &mut self.act[self.obj.a as usize];
 
         // assure the first is in the front
@@ -917,7 +1009,7 @@ impl Char {
 
         // update lifeless if needed
         for lifeless in self.list_lifeless.iter_mut() {
-            lifeless.update(state_data);
+            lifeless.update(grid);
         }
 
         // clear lifeless dead
@@ -928,46 +1020,25 @@ impl Char {
         old != new || self.list_lifeless.len() > 0
     }
 
-    pub fn send(&self, client: &Client<PacketCharInfo>) {
-        let mut char_info = PacketCharInfo {
-            numchar:        self.obj.r#type as i16,
-            idchar:         self.obj.id as i16,
-            a:              self.obj.a as i16,
-            d:              self.obj.d as i16,
-            d2:             self.obj.d2 as i16,
-            x:              (self.obj.x as i32  + self.act[self.obj.a as usize].rebatex) as i16,
-            w:              (self.obj.wd as i32 - self.act[self.obj.a as usize].rebatex) as i16,
-            y:              (self.obj.y as i32  + self.act[self.obj.a as usize].rebatey) as i16,
-            h:              (self.obj.hd as i32 - self.act[self.obj.a as usize].rebatey) as i16,
-            healt:          self.info.healt as i16,
-            stamina:        self.info.stamina as i16,
-            damage:         self.act[self.obj.a as usize].damage as i16,
-            exit:           self.dead,
-            idmap:          self.idmap as i16,
-            totlifeless:    0i16,
-            listlifeless:   Default::default(),
-
-            dhit: 0i16,
-            step: self.act[self.obj.a as usize].stepx as i16,
-            totchar: 0i16,
-            totenemies: 0i16,
-            vision: 0i16,
-        };
+    pub fn is_moving(&self) -> bool {
+        self.obj.a == ACTION_WALK || self.obj.a == ACTION_RUN
+    }
 
-        for (i, lifeless) in self.list_lifeless.iter().enumerate() {
-            if !lifeless.dead {
-                char_info.listlifeless[i] = Some(PacketLifelessInfo {
-                    x: lifeless.obj.x as i16,
-                    y: lifeless.obj.y as i16,
-                    w: lifeless.obj.w as i16,
-                    h: lifeless.obj.h as i16,
-                    d: lifeless.obj.d as i16,
-                    damage: lifeless.actions[lifeless.obj.a as usize].damage as i16,
-                });
-            }
+    /// Moves one step towards `d2`, sliding along the walls it runs into.
+    pub fn step(&mut self, d2: i32, step: i32, grid: &CollisionGrid) {
+        self.obj.sweep(&self.act[self.obj.a as usize], step_offset(d2, step), grid);
+    }
+
+    /// What the player asks of the hero this frame.
+    pub fn command(&self) -> InputCommand {
+        InputCommand {
+            d2: self.obj.d2 as u8,
+            buttons: self.obj.a2 as u8 & (BUTTON_RUN | BUTTON_ATTACK),
         }
-        
-        client.send(char_info);
+    }
+
+    pub fn send(&self, client: &Client<GameMessage>, seq: u16) {
+        client.send(GameMessage::Input { seq, command: self.command() });
     }
 
     fn cur_sprite_idx(&self, a: usize, d: usize) -> usize {
@@ -1030,9 +1101,13 @@ impl Char {
             sprite.w,
             sprite.h);
 
-        // desenha o sprite
-        al_draw_scaled_bitmap(
+        // desenha o sprite, mais transparente enquanto some
+        let alpha = self.fading.map_or(1.0, |since| 1.0 - (since.elapsed().as_secs_f32() / FADE_OUT.as_secs_f32()).min(1.0));
+        let tint = (alpha * 255.0) as u8;
+
+        al_draw_tinted_scaled_bitmap(
             frame,
+            al_map_rgba(tint, tint, tint, tint),
             0.0,
             0.0,
             sprite.w as f32,
@@ -1128,57 +1203,6 @@ impl Char {
                 &datum.0);
         }
     }
-
-    pub fn collided(&self, state_data: (i32, i32, i32, i32, i32, *const AlBitmap)) -> bool {
-        let (
-            height, 
-            amb_w, 
-            amb_h, 
-            amb_wd, 
-            amb_hd, 
-            amb_model) = state_data;
-
-        if self.obj.y < 0.0 || self.obj.x < 0.0 {
-            return true;
-        }
-
-        if (self.obj.y + self.obj.hd) > height as f32 {
-            return true;
-        }
-
-        let act = &self.act[self.obj.a as usize];
-        let (rebatex, rebatey) = (act.rebatex as f32, act.rebatey as f32);
-
-        let colorwall = al_map_rgb(0, 0, 0);
-
-        let sx = amb_w as f32 / amb_wd as f32;
-        let sy = amb_h as f32 / amb_hd as f32;
-
-        let we = amb_wd as f32 * sx;
-        let he = amb_hd as f32 * sy;
-
-        let xup   = (self.obj.x + self.obj.wd - rebatex) * sx;
-        let yup   = (self.obj.y + rebatey) * sy;
-
-        let xdown = (self.obj.x + rebatex) * sx;
-        let ydown = (self.obj.y + self.obj.hd - rebatey) * sy;
-
-        if xdown >= 0.0 && ydown >= 0.0 && xup <= we && yup <= he {
-            let color = al_get_pixel(amb_model, xdown as i32, ydown as i32);
-            if colorwall == color {
-                return true;
-            }
-
-            let color = al_get_pixel(amb_model, xup as i32, ydown as i32);
-            if colorwall == color {
-                return true;
-            }
-        } else {
-            return true;
-        }
-
-        return false;
-    }
 }
 
 impl Scene {
@@ -1194,10 +1218,7 @@ impl Scene {
         al_lock_bitmap(image, al_get_bitmap_format(image), ALLEGRO_LOCK_READONLY);
 
         let model_path = config_file.get_string("model").expect("model não encontrado.");
-        let flags = al_get_new_bitmap_flags();
-        al_add_new_bitmap_flag(ALLEGRO_MEMORY_BITMAP);
-        let model  = al_load_bitmap(model_path);
-        al_set_new_bitmap_flags(flags);
+        let grid = CollisionGrid::load(model_path, (width as f32, height as f32)).unwrap_or_else(|_| panic!("Não foi possível carregar {}.", model_path));
     
         let sound_path = config_file.get_string("sound").expect("sound não encontrado.");
         let sound = al_load_sample(sound_path);
@@ -1217,7 +1238,7 @@ impl Scene {
             id: id,
             image: image,
             info: info,
-            model: model,
+            grid: Rc::new(grid),
             musicback: sound,
             gates: gates,
         }
@@ -0,0 +1,348 @@
+use std::fs::File;
+use std::io::Read;
+use std::path::Path;
+
+use png::{ColorType, Decoder, DecodingError, Transformations};
+
+/// Walls of a map, one bit per pixel of its model image, where opaque black is a wall.
+///
+/// The model is smaller than the map it describes, so queries take map coordinates and
+/// scale them down to cells. The client and the server build it from the same image and
+/// ask it the same questions, so they always agree on what a move runs into.
+#[derive(Debug, Clone, PartialEq)]
+pub struct CollisionGrid {
+    cols: u32,
+    rows: u32,
+    /// Size of the map in map coordinates.
+    width: f32,
+    height: f32,
+    bits: Vec<u64>,
+}
+
+/// Where `CollisionGrid::sweep` left a hit box, and what it ran into.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct Sweep {
+    pub x: i32,
+    pub y: i32,
+    /// Normal of the wall met on each axis, pointing away from it: `(-1, 0)` for a wall
+    /// on the right, `(0, 1)` for one above. `(0, 0)` when the move went all the way.
+    pub normal: (i32, i32),
+}
+
+impl Sweep {
+    pub fn blocked(&self) -> bool {
+        self.normal != (0, 0)
+    }
+}
+
+impl CollisionGrid {
+    /// Grid of `cols` by `rows` cells covering a map of `size`, from the cells row by row.
+    pub fn from_cells(cols: u32, rows: u32, size: (f32, f32), cells: impl IntoIterator<Item = bool>) -> CollisionGrid {
+        let mut bits = vec![0u64; (cols as usize * rows as usize).div_ceil(64)];
+
+        for (i, blocked) in cells.into_iter().take(cols as usize * rows as usize).enumerate() {
+            if blocked {
+                bits[i / 64] |= 1 << (i % 64);
+            }
+        }
+
+        CollisionGrid { cols, rows, width: size.0, height: size.1, bits }
+    }
+
+    /// Reads the model image at `path` for a map of `size`.
+    pub fn load(path: impl AsRef<Path>, size: (f32, f32)) -> Result<CollisionGrid, DecodingError> {
+        CollisionGrid::decode(File::open(path)?, size)
+    }
+
+    /// Decodes a PNG model image for a map of `size`.
+    pub fn decode(png: impl Read, size: (f32, f32)) -> Result<CollisionGrid, DecodingError> {
+        let mut decoder = Decoder::new(png);
+        // palettes and small depths become plain 8 bit samples
+        decoder.set_transformations(Transformations::EXPAND | Transformations::STRIP_16);
+
+        let mut reader = decoder.read_info()?;
+        let mut buf = vec![0; reader.output_buffer_size()];
+        let frame = reader.next_frame(&mut buf)?;
+
+        let (color_type, _) = reader.output_color_type();
+        let samples = color_type.samples();
+        let has_alpha = color_type == ColorType::Rgba || color_type == ColorType::GrayscaleAlpha;
+
+        let cells = buf[..frame.buffer_size()]
+            .chunks(frame.line_size)
+            .flat_map(|line| line[..frame.width as usize * samples].chunks(samples))
+            .map(|pixel| {
+                let (color, alpha) = if has_alpha { pixel.split_at(samples - 1) } else { (pixel, &[255][..]) };
+
+                color.iter().all(|&c| c == 0) && alpha[0] == 255
+            });
+
+        Ok(CollisionGrid::from_cells(frame.width, frame.height, size, cells))
+    }
+
+    pub fn cols(&self) -> u32 {
+        self.cols
+    }
+
+    pub fn rows(&self) -> u32 {
+        self.rows
+    }
+
+    /// Whether the cell at `(col, row)` is a wall, as is everything outside the grid.
+    pub fn blocked(&self, col: i32, row: i32) -> bool {
+        if col < 0 || row < 0 || col as u32 >= self.cols || row as u32 >= self.rows {
+            return true;
+        }
+
+        let i = row as usize * self.cols as usize + col as usize;
+        self.bits[i / 64] & (1 << (i % 64)) != 0
+    }
+
+    fn contains(&self, x: f32, y: f32) -> bool {
+        (0.0..=self.width).contains(&x) && (0.0..=self.height).contains(&y)
+    }
+
+    /// Cell holding the point `(x, y)` of the map, the far edges counting as the last cells.
+    fn cell(&self, x: f32, y: f32) -> (i32, i32) {
+        let col = (x * self.cols as f32 / self.width) as i32;
+        let row = (y * self.rows as f32 / self.height) as i32;
+
+        (col.min(self.cols as i32 - 1), row.min(self.rows as i32 - 1))
+    }
+
+    /// Whether the point `(x, y)` of the map is in a wall or off the map.
+    pub fn blocked_at(&self, x: f32, y: f32) -> bool {
+        if !self.contains(x, y) {
+            return true;
+        }
+
+        let (col, row) = self.cell(x, y);
+        self.blocked(col, row)
+    }
+
+    /// Whether any part of the box is in a wall or off the map.
+    pub fn box_blocked(&self, x: f32, y: f32, w: f32, h: f32) -> bool {
+        if !self.contains(x, y) || !self.contains(x + w, y + h) {
+            return true;
+        }
+
+        let (col1, row1) = self.cell(x, y);
+        let (col2, row2) = self.cell(x + w, y + h);
+
+        (row1..=row2).any(|row| (col1..=col2).any(|col| self.blocked(col, row)))
+    }
+
+    /// Whether the segment between two points of the map crosses a wall or leaves the map.
+    pub fn segment_blocked(&self, (x1, y1): (f32, f32), (x2, y2): (f32, f32)) -> bool {
+        // the map is convex, so a segment with both ends on it stays on it
+        if !self.contains(x1, y1) || !self.contains(x2, y2) {
+            return true;
+        }
+
+        let (mut col, mut row) = self.cell(x1, y1);
+        let end = self.cell(x2, y2);
+
+        let (cell_w, cell_h) = (self.width / self.cols as f32, self.height / self.rows as f32);
+        let (dx, dy) = (x2 - x1, y2 - y1);
+
+        // how far along the segment, 0 to 1, the next column and row start, and the
+        // distance between two of them
+        let (step_col, mut next_col, delta_col) =
+            if dx > 0.0 {
+                (1, ((col + 1) as f32 * cell_w - x1) / dx, cell_w / dx)
+            } else if dx < 0.0 {
+                (-1, (col as f32 * cell_w - x1) / dx, -cell_w / dx)
+            } else {
+                (0, f32::INFINITY, f32::INFINITY)
+            };
+
+        let (step_row, mut next_row, delta_row) =
+            if dy > 0.0 {
+                (1, ((row + 1) as f32 * cell_h - y1) / dy, cell_h / dy)
+            } else if dy < 0.0 {
+                (-1, (row as f32 * cell_h - y1) / dy, -cell_h / dy)
+            } else {
+                (0, f32::INFINITY, f32::INFINITY)
+            };
+
+        loop {
+            if self.blocked(col, row) {
+                return true;
+            }
+
+            if (col, row) == end || next_col.min(next_row) > 1.0 {
+                return false;
+            }
+
+            if next_col < next_row {
+                col += step_col;
+                next_col += delta_col;
+            } else {
+                row += step_row;
+                next_row += delta_row;
+            }
+        }
+    }
+
+    /// Whether an entity whose hit box is at `(x, y)`, `w` by `h`, can't stand there.
+    ///
+    /// The map is seen from above, so only the feet, the bottom edge of the box, run into
+    /// walls; the rest of the box must still be on the map.
+    pub fn feet_blocked(&self, x: f32, y: f32, w: f32, h: f32) -> bool {
+        if !self.contains(x, y) || !self.contains(x + w, y + h) {
+            return true;
+        }
+
+        self.segment_blocked((x, y + h), (x + w, y + h))
+    }
+
+    /// Moves the hit box `(x, y, w, h)` by `(dx, dy)`, along X then along Y, one pixel at a
+    /// time, stopping each axis right against the first wall its feet sweep into.
+    ///
+    /// A wall thinner than the move still stops it, and one axis stopping doesn't stop the
+    /// other, so diagonal moves slide along walls.
+    pub fn sweep(&self, (x, y, w, h): (i32, i32, i32, i32), (dx, dy): (i32, i32)) -> Sweep {
+        let mut moved = Sweep { x, y, normal: (0, 0) };
+        let (w, h) = (w as f32, h as f32);
+
+        for _ in 0..dx.abs() {
+            let next = moved.x + dx.signum();
+
+            // the feet one pixel on cover the cells they went over
+            if self.feet_blocked(next as f32, y as f32, w, h) {
+                moved.normal.0 = -dx.signum();
+                break;
+            }
+
+            moved.x = next;
+        }
+
+        for _ in 0..dy.abs() {
+            let next = moved.y + dy.signum();
+            let feet = moved.y.min(next) as f32 + h;
+
+            if self.feet_blocked(moved.x as f32, next as f32, w, h) || self.box_blocked(moved.x as f32, feet, w, 1.0) {
+                moved.normal.1 = -dy.signum();
+                break;
+            }
+
+            moved.y = next;
+        }
+
+        moved
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    /// 4 by 3 cells of 10 by 10 on a 40 by 30 map.
+    fn grid(cells: &str) -> CollisionGrid {
+        CollisionGrid::from_cells(4, 3, (40.0, 30.0), cells.chars().filter(|c| !c.is_whitespace()).map(|c| c == '#'))
+    }
+
+    #[test]
+    fn cells_and_points() {
+        let grid = grid("
+            #...
+            ..#.
+            ...#
+        ");
+
+        assert!(grid.blocked(0, 0) && grid.blocked(2, 1) && grid.blocked(3, 2));
+        assert!(!grid.blocked(1, 0) && !grid.blocked(0, 2));
+        assert!(grid.blocked(-1, 0) && grid.blocked(4, 0) && grid.blocked(0, 3));
+
+        assert!(grid.blocked_at(5.0, 5.0));
+        assert!(!grid.blocked_at(15.0, 5.0));
+        // the far edges belong to the last cells
+        assert!(grid.blocked_at(40.0, 30.0));
+        assert!(!grid.blocked_at(0.0, 30.0));
+        assert!(grid.blocked_at(-0.5, 10.0));
+    }
+
+    #[test]
+    fn boxes() {
+        let grid = grid("
+            ....
+            ..#.
+            ....
+        ");
+
+        assert!(!grid.box_blocked(0.0, 0.0, 15.0, 25.0));
+        assert!(grid.box_blocked(15.0, 5.0, 10.0, 10.0));
+        assert!(grid.box_blocked(35.0, 0.0, 10.0, 10.0));
+    }
+
+    #[test]
+    fn segments() {
+        let grid = grid("
+            ....
+            ..#.
+            ....
+        ");
+
+        assert!(!grid.segment_blocked((5.0, 5.0), (35.0, 5.0)));
+        assert!(grid.segment_blocked((5.0, 15.0), (35.0, 15.0)));
+        assert!(grid.segment_blocked((35.0, 15.0), (5.0, 15.0)));
+        // diagonals, passing beside the wall and through it
+        assert!(!grid.segment_blocked((5.0, 25.0), (15.0, 5.0)));
+        assert!(grid.segment_blocked((5.0, 5.0), (35.0, 25.0)));
+        assert!(!grid.segment_blocked((38.0, 2.0), (32.0, 28.0)));
+        assert!(grid.segment_blocked((5.0, 5.0), (45.0, 5.0)));
+    }
+
+    #[test]
+    fn feet() {
+        let grid = grid("
+            ....
+            .#..
+            ....
+        ");
+
+        // the head over the wall doesn't matter, the feet between the corners do
+        assert!(!grid.feet_blocked(5.0, 5.0, 20.0, 20.0));
+        assert!(grid.feet_blocked(5.0, 0.0, 20.0, 15.0));
+        assert!(grid.feet_blocked(25.0, 20.0, 10.0, 15.0));
+    }
+
+    #[test]
+    fn sweeps() {
+        let grid = grid("
+            ..#.
+            ....
+            ....
+        ");
+
+        // too fast to stop on the wall's only cell, yet stopped right against it
+        assert_eq!(grid.sweep((10, 2, 4, 4), (25, 0)), Sweep { x: 15, y: 2, normal: (-1, 0) });
+        // sliding along it
+        assert_eq!(grid.sweep((10, 2, 4, 4), (25, 20)), Sweep { x: 15, y: 22, normal: (-1, 0) });
+        assert_eq!(grid.sweep((22, 20, 4, 4), (0, -20)), Sweep { x: 22, y: 6, normal: (0, 1) });
+        assert_eq!(grid.sweep((3, 20, 4, 4), (-10, 4)), Sweep { x: 0, y: 24, normal: (1, 0) });
+
+        let free = grid.sweep((0, 12, 4, 4), (30, 10));
+        assert_eq!(free, Sweep { x: 30, y: 22, normal: (0, 0) });
+        assert!(!free.blocked());
+    }
+
+    #[test]
+    fn decode_png() {
+        // white, black, gray and transparent black pixels
+        let pixels = [255, 255, 255, 255, 0, 0, 0, 255, 95, 95, 95, 255, 0, 0, 0, 0];
+        let mut png = Vec::new();
+        {
+            let mut encoder = png::Encoder::new(&mut png, 2, 2);
+            encoder.set_color(ColorType::Rgba);
+            encoder.set_depth(png::BitDepth::Eight);
+            encoder.write_header().unwrap().write_image_data(&pixels).unwrap();
+        }
+
+        let grid = CollisionGrid::decode(&png[..], (20.0, 20.0)).unwrap();
+        assert_eq!((grid.cols(), grid.rows()), (2, 2));
+        assert!(!grid.blocked(0, 0) && grid.blocked(1, 0) && !grid.blocked(0, 1) && !grid.blocked(1, 1));
+
+        assert!(CollisionGrid::decode(&b"not a png"[..], (20.0, 20.0)).is_err());
+    }
+}
@@ -0,0 +1,141 @@
+use std::collections::VecDeque;
+use std::fmt;
+
+/// Identity of a hero or enemy, shared by the server and its clients.
+///
+/// `index` is a slot reused once its entity is gone, and `generation` counts the reuses of
+/// that slot, so an id kept after its entity went away never names the one that took its place.
+#[derive(Default, Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct EntityId {
+    pub index: u16,
+    pub generation: u16,
+}
+
+impl EntityId {
+    pub fn new(index: u16, generation: u16) -> EntityId {
+        EntityId { index, generation }
+    }
+
+    /// The id as sent on the wire.
+    pub fn to_bits(self) -> u32 {
+        (self.generation as u32) << 16 | self.index as u32
+    }
+
+    pub fn from_bits(bits: u32) -> EntityId {
+        EntityId { index: bits as u16, generation: (bits >> 16) as u16 }
+    }
+
+    /// Whether both ids use the same slot, whatever their generation.
+    pub fn same_slot(self, other: EntityId) -> bool {
+        self.index == other.index
+    }
+}
+
+impl fmt::Display for EntityId {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}v{}", self.index, self.generation)
+    }
+}
+
+/// Hands out `EntityId`s, reusing the slots of freed ids with a new generation.
+///
+/// Freed slots are reused oldest first, so one slot comes back as late as possible.
+#[derive(Default, Debug)]
+pub struct EntityIds {
+    /// Current generation of each slot.
+    generations: Vec<u16>,
+    alive: Vec<bool>,
+    free: VecDeque<u16>,
+}
+
+impl EntityIds {
+    pub fn new() -> EntityIds {
+        EntityIds::default()
+    }
+
+    /// A new id, unlike any other alive; `None` when every slot is taken.
+    pub fn alloc(&mut self) -> Option<EntityId> {
+        if let Some(index) = self.free.pop_front() {
+            let slot = index as usize;
+            self.generations[slot] = self.generations[slot].wrapping_add(1);
+            self.alive[slot] = true;
+
+            return Some(EntityId::new(index, self.generations[slot]));
+        }
+
+        if self.generations.len() > u16::MAX as usize {
+            return None;
+        }
+
+        let index = self.generations.len() as u16;
+        self.generations.push(0);
+        self.alive.push(true);
+
+        Some(EntityId::new(index, 0))
+    }
+
+    /// Frees `id`, if it is alive; false for a stale or unknown id.
+    pub fn free(&mut self, id: EntityId) -> bool {
+        if !self.is_alive(id) {
+            return false;
+        }
+
+        self.alive[id.index as usize] = false;
+        self.free.push_back(id.index);
+        true
+    }
+
+    pub fn is_alive(&self, id: EntityId) -> bool {
+        let slot = id.index as usize;
+
+        slot < self.generations.len() && self.alive[slot] && self.generations[slot] == id.generation
+    }
+
+    /// How many ids are alive.
+    pub fn len(&self) -> usize {
+        self.generations.len() - self.free.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn bits_roundtrip() {
+        let id = EntityId::new(513, 7);
+
+        assert_eq!(id.to_bits(), 7 << 16 | 513);
+        assert_eq!(EntityId::from_bits(id.to_bits()), id);
+        assert_eq!(id.to_string(), "513v7");
+    }
+
+    #[test]
+    fn freed_slots_come_back_newer() {
+        let mut ids = EntityIds::new();
+
+        let a = ids.alloc().unwrap();
+        let b = ids.alloc().unwrap();
+        assert_eq!((a, b), (EntityId::new(0, 0), EntityId::new(1, 0)));
+        assert_eq!(ids.len(), 2);
+
+        assert!(ids.free(a));
+        assert!(!ids.free(a));
+        assert!(!ids.is_alive(a));
+
+        // the oldest freed slot is reused, one generation later
+        let c = ids.alloc().unwrap();
+        assert_eq!(c, EntityId::new(0, 1));
+        assert!(c.same_slot(a) && c != a);
+        assert!(ids.is_alive(c) && !ids.is_alive(a));
+
+        // a stale id frees nothing
+        assert!(!ids.free(a));
+        assert!(ids.is_alive(c));
+        assert_eq!(ids.len(), 2);
+    }
+}
@@ -1,7 +1,14 @@
 use std::mem;
+use std::fmt;
 use std::convert::{TryInto};
 
+use entity::EntityId;
+
+pub mod collision;
+pub mod entity;
 pub mod net;
+pub mod protocol;
+#[cfg(feature = "allegro")]
 pub mod allegro_safe;
 pub mod file_manager;
 
@@ -23,8 +30,59 @@ pub const CHARS: usize =  30;
 pub const LIFELESS: usize =  20;
 pub const MAXCHARLIFELESS: usize =  5;
 
-pub trait FromBytes {
-    fn from_bytes(buf: &[u8]) -> Self;
+/// Offset of one movement step of `step` pixels towards the directions in `d2`.
+///
+/// The server moves heroes with it and the client predicts its own hero the same way.
+pub fn step_offset(d2: i32, step: i32) -> (i32, i32) {
+    match d2 {
+        DIRECTION_LEFT => (-step, 0),
+        DIRECTION_RIGHT => (step, 0),
+        DIRECTION_UP => (0, -step),
+        DIRECTION_DOWN => (0, step),
+        DIRECTION_LEFTUP => (-step, -step),
+        DIRECTION_RIGHTUP => (step, -step),
+        DIRECTION_LEFTDOWN => (-step, step),
+        DIRECTION_RIGHTDOWN => (step, step),
+        _ => (0, 0)
+    }
+}
+
+pub(crate) fn put_str(buf: &mut Vec<u8>, value: &str) {
+    buf.extend_from_slice(&(value.len() as u16).to_le_bytes());
+    buf.extend_from_slice(value.as_bytes());
+}
+
+pub(crate) fn get_str(buf: &[u8], pos: usize) -> Result<String, DecodeError> {
+    check_len(buf, pos + 2, "string length")?;
+    let len = u16::from_le_bytes(buf[pos..pos+2].try_into().unwrap()) as usize;
+
+    check_len(buf, pos + 2 + len, "string")?;
+    Ok(String::from_utf8_lossy(&buf[pos+2..pos+2+len]).into_owned())
+}
+
+/// Fails unless `buf` has at least `len` bytes.
+pub(crate) fn check_len(buf: &[u8], len: usize, what: &str) -> Result<(), DecodeError> {
+    if buf.len() < len {
+        Err(DecodeError(format!("{} needs {} bytes, got {}.", what, len, buf.len())))
+    } else {
+        Ok(())
+    }
+}
+
+/// Bytes received from the network that don't hold a valid message.
+#[derive(Debug, Clone, PartialEq)]
+pub struct DecodeError(pub String);
+
+impl fmt::Display for DecodeError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Can't decode: {}", self.0)
+    }
+}
+
+impl std::error::Error for DecodeError {}
+
+pub trait FromBytes: Sized {
+    fn from_bytes(buf: &[u8]) -> Result<Self, DecodeError>;
 }
 
 pub trait ToBytes {
@@ -54,7 +112,7 @@ pub struct PacketCharInfo {
     pub d2: i16,
     pub dhit: i16,
     pub numchar: i16,
-    pub idchar: i16,
+    pub idchar: EntityId,
     pub totchar: i16,
     pub totenemies: i16,
     pub exit: bool,
@@ -69,11 +127,13 @@ pub struct PacketCharInfo {
 }
 
 impl FromBytes for PacketCharInfo {
-    fn from_bytes(buf: &[u8]) -> Self {
+    fn from_bytes(buf: &[u8]) -> Result<Self, DecodeError> {
+        check_len(buf, 41 + MAXCHARLIFELESS * mem::size_of::<PacketLifelessInfo>(), "PacketCharInfo")?;
+
         let mut lifeless: [Option<PacketLifelessInfo>; MAXCHARLIFELESS] = [None,None,None,None,None];
 
         for i in 0..MAXCHARLIFELESS {
-            let j = 39 + i * mem::size_of::<PacketLifelessInfo>();
+            let j = 41 + i * mem::size_of::<PacketLifelessInfo>();
 
             let life = PacketLifelessInfo {
                 x: i16::from_le_bytes(buf[j..j+2].try_into().unwrap()),
@@ -89,7 +149,7 @@ impl FromBytes for PacketCharInfo {
             }
         }
 
-        Self {
+        Ok(Self {
             x: i16::from_le_bytes(buf[0..2].try_into().unwrap()),
             y: i16::from_le_bytes(buf[2..4].try_into().unwrap()),
             w: i16::from_le_bytes(buf[4..6].try_into().unwrap()),
@@ -99,19 +159,19 @@ impl FromBytes for PacketCharInfo {
             d2: i16::from_le_bytes(buf[12..14].try_into().unwrap()),
             dhit: i16::from_le_bytes(buf[14..16].try_into().unwrap()),
             numchar: i16::from_le_bytes(buf[16..18].try_into().unwrap()),
-            idchar: i16::from_le_bytes(buf[18..20].try_into().unwrap()),
-            totchar: i16::from_le_bytes(buf[20..22].try_into().unwrap()),
-            totenemies: i16::from_le_bytes(buf[22..24].try_into().unwrap()),
-            exit: buf[24] == 1,
-            healt: i16::from_le_bytes(buf[25..27].try_into().unwrap()),
-            stamina: i16::from_le_bytes(buf[27..29].try_into().unwrap()),
-            damage: i16::from_le_bytes(buf[29..31].try_into().unwrap()),
-            idmap: i16::from_le_bytes(buf[31..33].try_into().unwrap()),
-            totlifeless: i16::from_le_bytes(buf[33..35].try_into().unwrap()),
-            step: i16::from_le_bytes(buf[35..37].try_into().unwrap()),
-            vision: i16::from_le_bytes(buf[37..39].try_into().unwrap()),
+            idchar: EntityId::from_bits(u32::from_le_bytes(buf[18..22].try_into().unwrap())),
+            totchar: i16::from_le_bytes(buf[22..24].try_into().unwrap()),
+            totenemies: i16::from_le_bytes(buf[24..26].try_into().unwrap()),
+            exit: buf[26] == 1,
+            healt: i16::from_le_bytes(buf[27..29].try_into().unwrap()),
+            stamina: i16::from_le_bytes(buf[29..31].try_into().unwrap()),
+            damage: i16::from_le_bytes(buf[31..33].try_into().unwrap()),
+            idmap: i16::from_le_bytes(buf[33..35].try_into().unwrap()),
+            totlifeless: i16::from_le_bytes(buf[35..37].try_into().unwrap()),
+            step: i16::from_le_bytes(buf[37..39].try_into().unwrap()),
+            vision: i16::from_le_bytes(buf[39..41].try_into().unwrap()),
             listlifeless: lifeless,
-        }
+        })
     }
 }
 
@@ -128,21 +188,21 @@ impl ToBytes for PacketCharInfo {
         buf[12..14].copy_from_slice(&self.d2.to_le_bytes());
         buf[14..16].copy_from_slice(&self.dhit.to_le_bytes());
         buf[16..18].copy_from_slice(&self.numchar.to_le_bytes());
-        buf[18..20].copy_from_slice(&self.idchar.to_le_bytes());
-        buf[20..22].copy_from_slice(&self.totchar.to_le_bytes());
-        buf[22..24].copy_from_slice(&self.totenemies.to_le_bytes());
-        buf[24] = self.exit as u8;
-        buf[25..27].copy_from_slice(&self.healt.to_le_bytes());
-        buf[27..29].copy_from_slice(&self.stamina.to_le_bytes());
-        buf[29..31].copy_from_slice(&self.damage.to_le_bytes());
-        buf[31..33].copy_from_slice(&self.idmap.to_le_bytes());
-        buf[33..35].copy_from_slice(&self.totlifeless.to_le_bytes());
-        buf[35..37].copy_from_slice(&self.step.to_le_bytes());
-        buf[37..39].copy_from_slice(&self.vision.to_le_bytes());
+        buf[18..22].copy_from_slice(&self.idchar.to_bits().to_le_bytes());
+        buf[22..24].copy_from_slice(&self.totchar.to_le_bytes());
+        buf[24..26].copy_from_slice(&self.totenemies.to_le_bytes());
+        buf[26] = self.exit as u8;
+        buf[27..29].copy_from_slice(&self.healt.to_le_bytes());
+        buf[29..31].copy_from_slice(&self.stamina.to_le_bytes());
+        buf[31..33].copy_from_slice(&self.damage.to_le_bytes());
+        buf[33..35].copy_from_slice(&self.idmap.to_le_bytes());
+        buf[35..37].copy_from_slice(&self.totlifeless.to_le_bytes());
+        buf[37..39].copy_from_slice(&self.step.to_le_bytes());
+        buf[39..41].copy_from_slice(&self.vision.to_le_bytes());
 
         for (i, lifeless) in self.listlifeless.iter().enumerate() {
             if let Some(lifeless) = lifeless {
-                let j = 39 + i * mem::size_of_val(lifeless);
+                let j = 41 + i * mem::size_of_val(lifeless);
                 buf[j..j+2].copy_from_slice(&lifeless.x.to_le_bytes());
                 buf[j+2..j+4].copy_from_slice(&lifeless.y.to_le_bytes());
                 buf[j+4..j+6].copy_from_slice(&lifeless.w.to_le_bytes());
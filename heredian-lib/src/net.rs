@@ -1,364 +1,659 @@
-use std::convert::{TryInto};
+pub mod packet;
+pub mod handshake;
+pub mod error;
+pub mod transport;
+pub mod heartbeat;
+pub mod stats;
+
 use std::fmt;
-use std::mem;
-use std::time::{Duration};
-use std::io::{Write, Read};
-use std::net::{TcpListener, TcpStream, SocketAddr, SocketAddrV4, Ipv4Addr};
+use std::time::{Duration, Instant};
+use std::net::{SocketAddr, SocketAddrV4, Ipv4Addr};
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::mpsc::{channel, Sender, Receiver, TryRecvError, RecvTimeoutError};
+use std::collections::HashMap;
 use std::thread;
 
 use super::*;
 
-#[derive(Default, Debug)]
-#[repr(C)]
-pub struct Packet<TData>
-    where
-        TData: Sized + Send + ToBytes + FromBytes + Default {
-    
-    pub sign: [u8;3], // "GDP"
-    pub size: u16,
-    pub data: TData
+pub use packet::*;
+pub use handshake::*;
+pub use error::*;
+pub use transport::*;
+pub use heartbeat::*;
+pub use stats::*;
+
+use stats::ConnStats;
+
+/// How long each side waits for the other during the handshake.
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+
+fn control_packet(control: Control) -> Vec<u8> {
+    Packet::with_kind(KIND_CONTROL, control).expect("Control messages fit a packet.").to_bytes()
 }
 
-impl<TData> Packet<TData>
-    where
-        TData: Sized + Send + ToBytes + FromBytes + Default {
+/// Blocks until the next control packet arrives, for at most `HANDSHAKE_TIMEOUT`.
+fn read_control(receiver: &mut dyn FrameReceiver) -> Result<Control, NetError> {
+    let frame = receiver.recv_frame(Some(HANDSHAKE_TIMEOUT))?;
 
-    pub fn new(msg: TData) -> Self {
-        Self {
-            sign: b"GDP".to_owned(),
-            size: mem::size_of::<Self>() as u16,
-            data: msg
+    if frame_kind(&frame) != KIND_CONTROL {
+        return Err(DecodeError("Handshake expected, but data packet received.".to_owned()).into());
+    }
+
+    Ok(Packet::<Control>::from_bytes(&frame)?.data)
+}
+
+/// What the writing thread of a connection is asked to do.
+enum Outgoing {
+    Packet(Arc<[u8]>),
+    Close,
+}
+
+/// Queue of the writing thread of a connection; dropping it closes the connection.
+///
+/// The reading thread keeps a plain sender to answer pings, so the queue alone
+/// never notices the owner is gone.
+struct Writer(Sender<Outgoing>, Arc<ConnStats>);
+
+impl Writer {
+    fn send(&self, bytes: Arc<[u8]>) {
+        // counted before the writing thread can take it, which counts it out
+        self.1.queue();
+
+        // a failed writing thread reports itself
+        if self.0.send(Outgoing::Packet(bytes)).is_err() {
+            self.1.dequeue();
+            self.1.drop_send();
         }
     }
 }
 
-impl<TData> FromBytes for Packet<TData>
-    where
-        TData: Sized + Send + ToBytes + FromBytes + Default {
+impl Drop for Writer {
+    fn drop(&mut self) {
+        let _ = self.0.send(Outgoing::Close);
+    }
+}
 
-    fn from_bytes(buf: &[u8]) -> Self {
-        Self {
-            sign: buf[0..3].try_into().unwrap(),
-            size: u16::from_le_bytes(buf[3..5].try_into().unwrap()),
-            data: TData::from_bytes(&buf[5..]),
+/// Sends every queued packet as soon as it is queued, and a ping every `heartbeat.interval`.
+///
+/// Returns when the connection is closed, a send fails or the peer stays silent
+/// longer than `heartbeat.timeout`, closing the connection.
+fn write_loop(mut sender: Box<dyn FrameSender>, rx: Receiver<Outgoing>, stats: &ConnStats, heartbeat: Heartbeat) -> Result<(), NetError> {
+    let mut next_ping = Instant::now();
+
+    let result =
+        loop {
+            let wait = next_ping.saturating_duration_since(Instant::now());
+
+            let sent =
+                match rx.recv_timeout(wait) {
+                    Ok(Outgoing::Packet(bytes)) => {
+                        stats.dequeue();
+                        sender.send_frame(&bytes).map(|_| stats.sent(bytes.len(), frame_kind(&bytes) == KIND_DATA))
+                    },
+                    Ok(Outgoing::Close) | Err(RecvTimeoutError::Disconnected) => break Ok(()),
+                    Err(RecvTimeoutError::Timeout) => Ok(()),
+                };
+
+            if let Err(e) = sent {
+                break Err(e);
+            }
+
+            if Instant::now() >= next_ping {
+                if stats.silence() > heartbeat.timeout {
+                    break Err(NetError::Timeout);
+                }
+
+                let ping = control_packet(Control::Ping { stamp: stats.stamp() });
+                if let Err(e) = sender.send_frame(&ping) {
+                    break Err(e);
+                }
+
+                stats.sent(ping.len(), false);
+
+                next_ping += heartbeat.interval;
+            }
+        };
+
+    sender.close();
+
+    // whatever is still queued is lost
+    for outgoing in rx.try_iter() {
+        if let Outgoing::Packet(bytes) = outgoing {
+            stats.dequeue();
+
+            if frame_kind(&bytes) == KIND_DATA {
+                stats.drop_send();
+            }
         }
     }
+
+    result
 }
 
-impl<TData> ToBytes for Packet<TData>
-    where
-        TData: Sized + Send + ToBytes + FromBytes + Default {
+/// Blocks until the next data packet, answering the heartbeats received meanwhile.
+fn next_data(receiver: &mut dyn FrameReceiver, pongs: &Sender<Outgoing>, stats: &ConnStats) -> Result<Vec<u8>, NetError> {
+    loop {
+        let frame = receiver.recv_frame(None)?;
+        let data = frame_kind(&frame) == KIND_DATA;
+        stats.received(frame.len(), data);
 
-    fn to_bytes(&self) -> Vec<u8> {
-        let mut buf = vec![0u8;mem::size_of_val(self)];
-        buf[..3].copy_from_slice(&self.sign);
-        buf[3..5].copy_from_slice(&self.size.to_le_bytes());
-        buf[5..115].copy_from_slice(&self.data.to_bytes());
+        if data {
+            return Ok(frame);
+        }
 
-        buf
+        match Packet::<Control>::from_bytes(&frame)?.data {
+            Control::Ping { stamp } => {
+                let pong = Outgoing::Packet(Arc::from(control_packet(Control::Pong { stamp })));
+                stats.queue();
+                if pongs.send(pong).is_err() {
+                    stats.dequeue();
+                }
+            },
+            Control::Pong { stamp } => stats.pong(stamp),
+            // the handshake is over by now
+            _ => (),
+        }
     }
 }
 
-pub struct Client<TMsg: 'static + fmt::Debug + ToBytes + FromBytes + Send + Default> {
-    stream: Option<TcpStream>,
-    chan: Option<(Sender<TMsg>, Receiver<TMsg>)>
+pub struct Client<TMsg: 'static + fmt::Debug + ToBytes + FromBytes + Send> {
+    channel: Option<Channel>,
+    capabilities: u32,
+    heartbeat: Heartbeat,
+    stats: Arc<ConnStats>,
+    chan: Option<(Writer, Receiver<Result<TMsg, NetError>>)>
 }
 
 impl<TMsg> Client<TMsg>
     where
-        TMsg: 'static + fmt::Debug + ToBytes + FromBytes + Send + Default {
+        TMsg: 'static + fmt::Debug + ToBytes + FromBytes + Send {
 
-    pub fn connect(address: &SocketAddr) -> Self {
-        let stream = TcpStream::connect_timeout(address, Duration::from_secs(10)).expect("It must connect to server.");
-        stream.set_nodelay(true).unwrap();
-        stream.set_nonblocking(true).unwrap();
+    /// Connects to the server over TCP and performs the handshake.
+    pub fn connect(address: &SocketAddr, hello: Handshake) -> Result<Self, NetError> {
+        Self::connect_with(&Tcp, address, hello)
+    }
 
-        Self {
-            stream: Some(stream),
+    /// Connects to the server over `transport` and performs the handshake.
+    pub fn connect_with(transport: &dyn Transport, address: &SocketAddr, hello: Handshake) -> Result<Self, NetError> {
+        let mut channel = transport.connect(address)?;
+        channel.sender.send_frame(&control_packet(Control::Hello(hello)))?;
+
+        let capabilities =
+            match read_control(&mut *channel.receiver)? {
+                Control::Accept { capabilities } => capabilities,
+                Control::Reject { reason } => return Err(NetError::Rejected(reason)),
+                control => return Err(DecodeError(format!("Unexpected handshake answer: {:?}", control)).into()),
+            };
+
+        Ok(Self {
+            channel: Some(channel),
+            capabilities,
+            heartbeat: Heartbeat::default(),
+            stats: Arc::new(ConnStats::new()),
             chan: None,
-        }
+        })
     }
 
+    /// Capabilities enabled by the server for this connection.
+    pub fn capabilities(&self) -> u32 {
+        self.capabilities
+    }
+
+    /// Changes how the server is probed; only effective before `start`.
+    pub fn set_heartbeat(&mut self, heartbeat: Heartbeat) {
+        self.heartbeat = heartbeat;
+    }
+
+    /// Last round trip to the server measured by the heartbeats, if any.
+    pub fn rtt(&self) -> Option<Duration> {
+        self.stats.rtt()
+    }
+
+    /// Traffic of the connection so far.
+    pub fn stats(&self) -> NetStats {
+        self.stats.snapshot()
+    }
+
+    /// Queues `msg` to the server.
+    ///
+    /// Messages queued after the connection is lost are dropped; the loss itself
+    /// is reported by the receiving methods. Messages too large for a packet are dropped too.
     pub fn send(&self, msg: TMsg) {
-        self.chan.as_ref().unwrap().0.send(msg).unwrap();
+        match Packet::<TMsg>::new(msg) {
+            Ok(packet) => self.chan.as_ref().unwrap().0.send(Arc::from(packet.to_bytes())),
+            Err(e) => println!("Message dropped: {}", e),
+        }
     }
 
-    pub fn recv(&self) -> TMsg {
-        self.chan.as_ref().unwrap().1.recv().unwrap()
+    /// Blocks until a message arrives or the connection fails.
+    ///
+    /// A server silent for longer than the heartbeat timeout fails with `NetError::Timeout`.
+    pub fn recv(&self) -> Result<TMsg, NetError> {
+        self.chan.as_ref().unwrap().1.recv().unwrap_or(Err(NetError::Closed))
     }
 
-    pub fn try_recv(&self) -> Result<TMsg, TryRecvError> {
-        self.chan.as_ref().unwrap().1.try_recv()
+    /// Returns `Ok(None)` if no message is waiting.
+    pub fn try_recv(&self) -> Result<Option<TMsg>, NetError> {
+        match self.chan.as_ref().unwrap().1.try_recv() {
+            Ok(msg) => msg.map(Some),
+            Err(TryRecvError::Empty) => Ok(None),
+            Err(TryRecvError::Disconnected) => Err(NetError::Closed),
+        }
     }
 
-    pub fn recv_timeout(&self, timeout: Duration) -> Result<TMsg, RecvTimeoutError> {
-        self.chan.as_ref().unwrap().1.recv_timeout(timeout)
+    /// Returns `Ok(None)` if no message arrives within `timeout`.
+    pub fn recv_timeout(&self, timeout: Duration) -> Result<Option<TMsg>, NetError> {
+        match self.chan.as_ref().unwrap().1.recv_timeout(timeout) {
+            Ok(msg) => msg.map(Some),
+            Err(RecvTimeoutError::Timeout) => Ok(None),
+            Err(RecvTimeoutError::Disconnected) => Err(NetError::Closed),
+        }
     }
 
+    /// Starts one thread writing to and one thread reading from the server.
+    ///
+    /// Both block on their side of the connection, so messages are delivered as
+    /// soon as they are sent or arrive. Dropping the client stops them.
     pub fn start(&mut self) {
         let (tx, rx) = channel(); // send channel
         let (r_tx, r_rx) = channel(); // recv channel
 
-        let mut stream = self.stream.take().unwrap();
-        self.chan = Some((tx, r_rx));
+        let Channel { sender, mut receiver, .. } = self.channel.take().unwrap();
+        let pongs = tx.clone();
+        self.chan = Some((Writer(tx, self.stats.clone()), r_rx));
 
+        let w_r_tx = r_tx.clone();
+        let stats = self.stats.clone();
+        let heartbeat = self.heartbeat;
         thread::spawn(move || {
-            let mut buf = vec![0;std::mem::size_of::<Packet<TMsg>>()];
-
-            loop {
-                let mut done_something = false;
-                
-                // sender part
-                match rx.try_recv() {
-                    Ok(msg) => {
-                        let packet = Packet::<TMsg> {
-                            sign: b"GDP".to_owned(),
-                            size: buf.len() as u16,
-                            data: msg
-                        };
-
-                        let packet_bytes = packet.to_bytes();
-                        stream.write_all(&packet_bytes).unwrap();
-
-                        done_something = true;
-                    },
-                    Err(TryRecvError::Disconnected) => println!("Can't send message. Channel disconnected."),
-                    Err(TryRecvError::Empty) => ()
-                }
+            if let Err(e) = write_loop(sender, rx, &stats, heartbeat) {
+                let _ = w_r_tx.send(Err(e));
+            }
+        });
 
-                // receiver part
-                match stream.peek(&mut buf) {
-                    Ok(size) if size >= buf.len() => {
-                        stream.read_exact(&mut buf[0..size]).unwrap();
+        let stats = self.stats.clone();
+        thread::spawn(move || {
+            // the failure is the last thing the client receives
+            if let Err(e) = Self::read_loop(&mut *receiver, &r_tx, &pongs, &stats) {
+                let _ = r_tx.send(Err(e));
+            }
+        });
+    }
 
-                        let packet = Packet::from_bytes(&buf[0..size].to_owned());
-                        r_tx.send(packet.data).unwrap();
-                        
-                        done_something = true;
-                    },
-                    Ok(_) => (),
-                    Err(ref e) if e.kind() == std::io::ErrorKind::WouldBlock => (),
-                    Err(e) => panic!("{}", e)
-                }
+    /// Delivers the messages of the server; returns when the client is dropped or the connection fails.
+    fn read_loop(receiver: &mut dyn FrameReceiver, r_tx: &Sender<Result<TMsg, NetError>>, pongs: &Sender<Outgoing>, stats: &ConnStats) -> Result<(), NetError> {
+        loop {
+            let frame = next_data(receiver, pongs, stats)?;
+            let packet = Packet::<TMsg>::from_bytes(&frame)?;
 
-                if !done_something {
-                    thread::sleep(Duration::from_millis(16));
-                }
+            if r_tx.send(Ok(packet.data)).is_err() {
+                return Ok(());
             }
-        });
+        }
     }
 }
 
 #[derive(Debug)]
 pub enum Message<TMsg>
     where 
-        TMsg: 'static + fmt::Debug + ToBytes + FromBytes + Send + Default {
+        TMsg: 'static + fmt::Debug + ToBytes + FromBytes + Send {
     Accepted(SocketAddr),
     Broadcast(TMsg),
     BroadcastExcept(TMsg, SocketAddr),
     Direct(TMsg, SocketAddr),
-    Disconnected(SocketAddr)
+    Disconnected(SocketAddr, NetError)
 }
 
-pub struct Server<TMsg>
+/// Everything the dispatcher thread of the server reacts to.
+enum Event<TMsg>
     where
-        TMsg: 'static + fmt::Debug + ToBytes + FromBytes + Send + Default {
+        TMsg: 'static + fmt::Debug + ToBytes + FromBytes + Send {
+    /// the game wants to send a message.
+    Send(Message<TMsg>),
+    /// a client passed the handshake; packets for it go to the writer.
+    Accepted(SocketAddr, Writer, Arc<ConnStats>),
+    /// a client sent a message.
+    Received(TMsg, SocketAddr),
+    /// the connection of a client failed.
+    Failed(SocketAddr, NetError),
+    /// the server was dropped.
+    Shutdown,
+}
 
-    chan: Option<(Sender<Message<TMsg>>, Receiver<Message<TMsg>>)>,
+/// Events going to the dispatcher thread, and the messages it hands to the game.
+type Dispatch<TMsg> = (Sender<Event<TMsg>>, Receiver<Message<TMsg>>);
+
+/// Reads the hello of a new connection.
+///
+/// Returns the answer to send if the client is accepted; a refused client is told
+/// why right away.
+fn answer_hello(channel: &mut Channel, hello: &Handshake) -> Result<Control, NetError> {
+    let result =
+        match read_control(&mut *channel.receiver)? {
+            Control::Hello(client_hello) => hello.validate(&client_hello),
+            _ => Err("Handshake expected.".to_owned()),
+        };
+
+    match result {
+        Ok(capabilities) => Ok(Control::Accept { capabilities }),
+        Err(reason) => {
+            // the connection is closed right after, so a failure here changes nothing
+            let _ = channel.sender.send_frame(&control_packet(Control::Reject { reason: reason.clone() }));
+            Err(NetError::Rejected(reason))
+        }
+    }
+}
+
+pub struct Server<TMsg>
+    where
+        TMsg: 'static + fmt::Debug + ToBytes + FromBytes + Send {
+
+    hello: Option<Handshake>,
+    heartbeat: Heartbeat,
+    /// traffic of the accepted clients.
+    links: Arc<Mutex<HashMap<SocketAddr, Arc<ConnStats>>>>,
+    chan: Option<Dispatch<TMsg>>,
+    /// transport and address being listened on, and the flag telling the accepting thread to stop.
+    listening: Option<(Box<dyn Transport>, SocketAddr, Arc<AtomicBool>)>,
 }
 
 impl<TMsg> Server<TMsg>
     where
-        TMsg: 'static + fmt::Debug + ToBytes + FromBytes + Send + Default {
+        TMsg: 'static + fmt::Debug + ToBytes + FromBytes + Send {
 
-    pub fn new() -> Self {
+    /// Creates a server that only accepts clients whose hello matches `hello`.
+    pub fn new(hello: Handshake) -> Self {
         Self {
-            chan: None
+            hello: Some(hello),
+            heartbeat: Heartbeat::default(),
+            links: Arc::new(Mutex::new(HashMap::new())),
+            chan: None,
+            listening: None,
         }
     }
 
+    /// Changes how clients are probed; only effective before `listen`.
+    ///
+    /// A client silent for longer than the timeout is reported as `Message::Disconnected`.
+    pub fn set_heartbeat(&mut self, heartbeat: Heartbeat) {
+        self.heartbeat = heartbeat;
+    }
+
+    /// Last round trip to the client at `addr` measured by the heartbeats, if any.
+    pub fn rtt(&self, addr: &SocketAddr) -> Option<Duration> {
+        self.links.lock().unwrap().get(addr).and_then(|stats| stats.rtt())
+    }
+
+    /// Traffic of every connected client so far.
+    pub fn stats(&self) -> HashMap<SocketAddr, NetStats> {
+        self.links
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|(addr, stats)| (*addr, stats.snapshot()))
+            .collect()
+    }
+
     pub fn send(&self, msg: Message<TMsg>) {
         match msg {
-            Message::Accepted(_) | Message::Disconnected(_) => panic!("This type of message is not allowed to be sent."),
+            Message::Accepted(_) | Message::Disconnected(..) => panic!("This type of message is not allowed to be sent."),
             _ => ()
         }
 
-        self.chan.as_ref().unwrap().0.send(msg).unwrap();
+        // if the dispatcher is gone there's nobody to deliver it to
+        let _ = self.chan.as_ref().unwrap().0.send(Event::Send(msg));
     }
 
-    pub fn try_recv(&self) -> Result<Message<TMsg>, TryRecvError> {
-        self.chan.as_ref().unwrap().1.try_recv()
+    /// Returns `Ok(None)` if no message is waiting.
+    pub fn try_recv(&self) -> Result<Option<Message<TMsg>>, NetError> {
+        match self.chan.as_ref().unwrap().1.try_recv() {
+            Ok(msg) => Ok(Some(msg)),
+            Err(TryRecvError::Empty) => Ok(None),
+            Err(TryRecvError::Disconnected) => Err(NetError::Closed),
+        }
     }
 
-    pub fn recv(&self) -> Message<TMsg> {
-        self.chan.as_ref().unwrap().1.recv().unwrap()
+    pub fn recv(&self) -> Result<Message<TMsg>, NetError> {
+        self.chan.as_ref().unwrap().1.recv().map_err(|_| NetError::Closed)
     }
 
-    pub fn listen(&mut self, port: u16) {
-        let (tx, rx) = channel(); // send channel
+    /// Starts accepting clients on `port` over TCP.
+    pub fn listen(&mut self, port: u16) -> Result<(), NetError> {
+        self.listen_with(Tcp, &SocketAddr::V4(SocketAddrV4::new(Ipv4Addr::new(127,0,0,1), port)))
+    }
+
+    /// Starts accepting clients at `addr` over `transport`.
+    ///
+    /// Every connection gets a thread blocked reading it and another one blocked
+    /// writing to it, while a single dispatcher thread routes the messages between
+    /// them and the game, so nothing waits for a polling interval.
+    pub fn listen_with<T: 'static + Transport>(&mut self, transport: T, addr: &SocketAddr) -> Result<(), NetError> {
+        // bind before returning, so clients can connect as soon as listen returns
+        let acceptor = transport.listen(addr)?;
+        let addr = acceptor.local_addr();
+
+        let (tx, rx) = channel(); // event channel
         let (r_tx, r_rx) = channel(); // receive channel
+        let hello = self.hello.take().unwrap();
+        let closed = Arc::new(AtomicBool::new(false));
+
+        let events = tx.clone();
+        let accepting = closed.clone();
+        let heartbeat = self.heartbeat;
+        thread::spawn(move || Self::accept_loop(acceptor, hello, heartbeat, events, accepting));
+
+        let links = self.links.clone();
+        thread::spawn(move || Self::dispatch(rx, r_tx, &links));
+
         self.chan = Some((tx, r_rx));
+        self.listening = Some((Box::new(transport), addr, closed));
 
-        thread::spawn(move || {
-            let addr = SocketAddrV4::new(Ipv4Addr::new(127,0,0,1), port);
-            let listener = TcpListener::bind(addr).unwrap();
-            listener.set_nonblocking(true).unwrap();
+        Ok(())
+    }
 
-            let mut clients = Vec::with_capacity(10);
-            let mut buf = vec![0;std::mem::size_of::<Packet<TMsg>>()];
+    /// Address the clients connect to, once listening.
+    pub fn local_addr(&self) -> Option<SocketAddr> {
+        self.listening.as_ref().map(|(_, addr, _)| *addr)
+    }
 
-            loop {
-                let mut done_something = false;
+    /// Spawns the reading thread of every new connection.
+    fn accept_loop(mut acceptor: Box<dyn Acceptor>, hello: Handshake, heartbeat: Heartbeat, events: Sender<Event<TMsg>>, closed: Arc<AtomicBool>) {
+        loop {
+            let channel = acceptor.accept();
 
-                // check new connections (accept)
-                if let Ok((stream, addr)) = listener.accept() {
-                    stream.set_nonblocking(true).unwrap();
-                    stream.set_nodelay(true).unwrap();
-                    clients.push((stream, addr));
+            if closed.load(Ordering::SeqCst) {
+                return;
+            }
 
-                    r_tx.send(Message::Accepted(addr)).unwrap();
+            if let Ok(channel) = channel {
+                let hello = hello.clone();
+                let events = events.clone();
 
-                    done_something = true;
+                thread::spawn(move || Self::serve(channel, hello, heartbeat, events));
+            }
+        }
+    }
+
+    /// Performs the handshake of a connection, then forwards everything it receives.
+    fn serve(mut connection: Channel, hello: Handshake, heartbeat: Heartbeat, events: Sender<Event<TMsg>>) {
+        let addr = connection.peer;
+
+        let accept =
+            match answer_hello(&mut connection, &hello) {
+                Ok(accept) => accept,
+                Err(e) => {
+                    println!("Client {} rejected: {}", addr, e);
+                    return;
                 }
+            };
 
-                let mut errors = Vec::new();
-
-                // check send data channel
-                // sender part
-                match rx.try_recv() {
-                    Ok(msg) => {
-                        match msg {
-                            Message::Broadcast(msg) => {
-                                let packet = Packet::<TMsg>::new(msg);
-                                let packet_bytes = packet.to_bytes();
-
-                                clients
-                                    .iter_mut()
-                                    .for_each(|(stream, addr)| {
-                                        if let Err(_) = stream.write_all(&packet_bytes) {
-                                            errors.push(*addr);
-                                        }
-                                    });
-                            },
-                            Message::BroadcastExcept(msg, client_addr) => {
-                                let packet = Packet::<TMsg>::new(msg);
-                                let packet_bytes = packet.to_bytes();
-
-                                clients
-                                    .iter_mut()
-                                    .filter(|(_, addr)| *addr != client_addr)
-                                    .for_each(|(stream, addr)| {
-                                        if let Err(_) = stream.write_all(&packet_bytes) {
-                                            errors.push(*addr);
-                                        }
-                                    });
-                            },
-                            Message::Direct(msg, client_addr) => {
-                                let (stream, addr) = clients.iter_mut().find(|(_, addr)| *addr == client_addr).unwrap();
-                                let packet = Packet::<TMsg>::new(msg);
-                                let packet_bytes = packet.to_bytes();
-
-                                if let Err(_) = stream.write_all(&packet_bytes) {
-                                    errors.push(*addr);
-                                }
+        let Channel { sender, mut receiver, .. } = connection;
 
-                            },
-                            _ => unreachable!(),
-                        }
+        // the answer is queued before the dispatcher learns about the client and
+        // written after, so the game can't send anything to a client that doesn't
+        // know it was accepted, nor miss a client that does
+        let (w_tx, w_rx) = channel();
+        let stats = Arc::new(ConnStats::new());
+        let writer = Writer(w_tx.clone(), stats.clone());
+        writer.send(Arc::from(control_packet(accept)));
 
-                        done_something = true;
-                    },
-                    Err(TryRecvError::Disconnected) => println!("Can't send message. Channel disconnected."),
-                    Err(TryRecvError::Empty) => ()
-                }
+        if events.send(Event::Accepted(addr, writer, stats.clone())).is_err() {
+            return;
+        }
 
-                // something went wrong
-                if !errors.is_empty() {
-                    // notify server
-                    errors.iter().for_each(|addr| {
-                        r_tx.send(Message::Disconnected(*addr)).unwrap();
-                    });
+        let w_events = events.clone();
+        let w_stats = stats.clone();
+        thread::spawn(move || {
+            if let Err(e) = write_loop(sender, w_rx, &w_stats, heartbeat) {
+                let _ = w_events.send(Event::Failed(addr, e));
+            }
+        });
 
-                    // clear fault stream
-                    clients.retain(|(_, addr)| !errors.contains(addr));
+        if let Err(e) = Self::read_loop(&mut *receiver, addr, &events, &w_tx, &stats) {
+            let _ = events.send(Event::Failed(addr, e));
+        }
+    }
 
-                    // clear errors
-                    errors.clear();
-                }
+    /// Forwards the messages of one client; returns when the server is dropped or the connection fails.
+    fn read_loop(receiver: &mut dyn FrameReceiver, addr: SocketAddr, events: &Sender<Event<TMsg>>, pongs: &Sender<Outgoing>, stats: &ConnStats) -> Result<(), NetError> {
+        loop {
+            let frame = next_data(receiver, pongs, stats)?;
+            let packet = Packet::<TMsg>::from_bytes(&frame)?;
 
-                // check recv TCP data and send
-                for (stream, client_addr) in clients.iter_mut() {
-                    match stream.peek(&mut buf) {
-                        Ok(size) if size >= buf.len() => {
-                            stream.read_exact(&mut buf[..]).unwrap();
-    
-                            let packet = Packet::<TMsg>::from_bytes(&buf[..]);
-                            let msg = Message::Direct(packet.data, *client_addr);
-
-                            r_tx.send(msg).unwrap();
-                            
-                            done_something = true;
-                        },
-                        Ok(_) => (),
-                        Err(ref e) if e.kind() == std::io::ErrorKind::WouldBlock => (),
-                        Err(_) => errors.push(*client_addr),
-                    }
-                }
+            if events.send(Event::Received(packet.data, addr)).is_err() {
+                return Ok(());
+            }
+        }
+    }
 
-                // something went wrong
-                if !errors.is_empty() {
-                    // notify server
-                    errors.iter().for_each(|addr| {
-                        r_tx.send(Message::Disconnected(*addr)).unwrap();
-                    });
+    /// Routes the messages between the game and the connections.
+    ///
+    /// Dropping the writer of a connection shuts it down, which stops its reading thread too.
+    fn dispatch(events: Receiver<Event<TMsg>>, r_tx: Sender<Message<TMsg>>, links: &Mutex<HashMap<SocketAddr, Arc<ConnStats>>>) {
+        let mut clients: HashMap<SocketAddr, Writer> = HashMap::with_capacity(10);
+
+        for event in events {
+            let notified =
+                match event {
+                    Event::Send(Message::Broadcast(msg)) => {
+                        let packet_bytes =
+                            match Packet::<TMsg>::new(msg) {
+                                Ok(packet) => Arc::<[u8]>::from(packet.to_bytes()),
+                                Err(e) => {
+                                    println!("Broadcast dropped: {}", e);
+                                    continue;
+                                }
+                            };
 
-                    // clear fault stream
-                    clients.retain(|(_, addr)| !errors.contains(addr));
+                        clients
+                            .values()
+                            .for_each(|writer| writer.send(packet_bytes.clone()));
 
-                    // clear errors
-                    errors.clear();
-                }
+                        Ok(())
+                    },
+                    Event::Send(Message::BroadcastExcept(msg, client_addr)) => {
+                        let packet_bytes =
+                            match Packet::<TMsg>::new(msg) {
+                                Ok(packet) => Arc::<[u8]>::from(packet.to_bytes()),
+                                Err(e) => {
+                                    println!("Broadcast dropped: {}", e);
+                                    continue;
+                                }
+                            };
 
-                if !done_something {
-                    thread::sleep(Duration::from_millis(16));
-                }
+                        clients
+                            .iter()
+                            .filter(|(addr, _)| **addr != client_addr)
+                            .for_each(|(_, writer)| writer.send(packet_bytes.clone()));
+
+                        Ok(())
+                    },
+                    Event::Send(Message::Direct(msg, client_addr)) => {
+                        // the client may have left after the message was queued
+                        if let Some(writer) = clients.get(&client_addr) {
+                            match Packet::<TMsg>::new(msg) {
+                                Ok(packet) => writer.send(Arc::from(packet.to_bytes())),
+                                Err(e) => println!("Message to {} dropped: {}", client_addr, e),
+                            }
+                        }
+
+                        Ok(())
+                    },
+                    Event::Send(_) => unreachable!(),
+                    Event::Accepted(addr, writer, stats) => {
+                        clients.insert(addr, writer);
+                        links.lock().unwrap().insert(addr, stats);
+                        r_tx.send(Message::Accepted(addr))
+                    },
+                    Event::Received(msg, addr) => r_tx.send(Message::Direct(msg, addr)),
+                    // both threads of a connection may report the same failure
+                    Event::Failed(addr, e) => match clients.remove(&addr) {
+                        Some(_) => {
+                            links.lock().unwrap().remove(&addr);
+                            r_tx.send(Message::Disconnected(addr, e))
+                        },
+                        None => Ok(()),
+                    },
+                    Event::Shutdown => return,
+                };
+
+            // nobody is listening to the game side anymore
+            if notified.is_err() {
+                return;
             }
-        });
+        }
     }
-    
 }
 
+impl<TMsg> Drop for Server<TMsg>
+    where
+        TMsg: 'static + fmt::Debug + ToBytes + FromBytes + Send {
+
+    fn drop(&mut self) {
+        if let Some((tx, _)) = self.chan.as_ref() {
+            let _ = tx.send(Event::Shutdown);
+        }
+
+        // wake the accepting thread up, so it releases the port
+        if let Some((transport, addr, closed)) = self.listening.take() {
+            closed.store(true, Ordering::SeqCst);
+            let _ = transport.connect(&addr);
+        }
+    }
+}
 #[cfg(test)]
 mod tests {
     use super::*;
 
+    fn loopback_addr(port: u16) -> SocketAddr {
+        SocketAddr::V4(SocketAddrV4::new(Ipv4Addr::new(127,0,0,1), port))
+    }
+
     #[test]
     #[ignore]
     fn usage_client() {
         println!("Tamanho do pacote: {}", std::mem::size_of::<PacketCharInfo>());
-        thread::spawn(|| {
-            let addr = SocketAddrV4::new(Ipv4Addr::new(127,0,0,1), 34000);
-            let listener = TcpListener::bind(addr).unwrap();
-            //listener.set_nonblocking(true).unwrap();
-            
-            for stream in listener.incoming() {
-                match stream {
-                    Ok(mut stream) => {
-                        let mut buf = [0u8; 114];
-                        let read = stream.read(&mut buf).unwrap();
-                        let packet = Packet::<PacketCharInfo>::from_bytes(&buf);
-                        println!("client->server ({}): {:?}", read, packet);
-                        stream.write_all(&buf).unwrap();
-                        break;
-                    },
-                    Err(e) => println!("Erro: {:?}", e)
-                }
+        let mut acceptor = Tcp.listen(&loopback_addr(0)).unwrap();
+        let addr = acceptor.local_addr();
+
+        thread::spawn(move || {
+            match acceptor.accept() {
+                Ok(mut channel) => {
+                    let hello = read_control(&mut *channel.receiver);
+                    println!("client->server hello: {:?}", hello);
+                    channel.sender.send_frame(&control_packet(Control::Accept { capabilities: 0 })).unwrap();
+
+                    let frame = channel.receiver.recv_frame(None).unwrap();
+                    let packet = Packet::<PacketCharInfo>::from_bytes(&frame);
+                    println!("client->server ({}): {:?}", frame.len(), packet);
+                    channel.sender.send_frame(&frame).unwrap();
+                },
+                Err(e) => println!("Erro: {:?}", e)
             }
         });
 
-        let addr = SocketAddrV4::new(Ipv4Addr::new(127,0,0,1), 34000);
-        let mut client = Client::connect(&SocketAddr::V4(addr));
+        let mut client = Client::connect(&addr, Handshake::default()).unwrap();
         client.start();
 
         client.send(PacketCharInfo {
@@ -371,7 +666,7 @@ mod tests {
             d2: 7,
             dhit: 8,
             numchar: 9,
-            idchar: 10,
+            idchar: EntityId::new(10, 0),
             totchar: 11,
             totenemies: 12,
             exit: true,
@@ -399,11 +694,14 @@ mod tests {
 
     #[test]
     fn usage_client_server() {
-        let mut server = Server::new();
-        server.listen(34000);
+        let hello = Handshake { version: 1, build: "test".to_owned(), capabilities: 0 };
+
+        let transport = Loopback::new();
+
+        let mut server = Server::new(hello.clone());
+        server.listen_with(transport.clone(), &loopback_addr(34000)).unwrap();
 
-        let addr = SocketAddrV4::new(Ipv4Addr::new(127,0,0,1), 34000);
-        let mut client = Client::connect(&SocketAddr::V4(addr));
+        let mut client = Client::connect_with(&transport, &loopback_addr(34000), hello).unwrap();
         client.start();
 
         let packet = PacketCharInfo {
@@ -416,7 +714,7 @@ mod tests {
             d2: 7,
             dhit: 8,
             numchar: 9,
-            idchar: 10,
+            idchar: EntityId::new(10, 0),
             totchar: 11,
             totenemies: 12,
             exit: true,
@@ -443,16 +741,16 @@ mod tests {
         client.send(packet.clone());
         server.send(Message::Broadcast(packet.clone()));
 
-        let packet2 = client.recv();
+        let packet2 = client.recv().unwrap();
         assert_eq!(packet, packet2);
 
-        let msg = server.recv();
+        let msg = server.recv().unwrap();
         match msg {
             Message::Accepted(_) => (),
             _ => panic!("Unexpected type of message")
         }
         
-        let msg = server.recv();
+        let msg = server.recv().unwrap();
         match msg {
             Message::Direct(packet3, _) => assert_eq!(packet2, packet3),
             _ => panic!("Unexpected type of message")
@@ -460,50 +758,260 @@ mod tests {
     }
 
     #[test]
-    fn ser_des() {
-        let packet = 
-            Packet {
-                sign: b"GDP".to_owned(),
-                size: mem::size_of::<Packet<PacketCharInfo>>() as u16,
-                data: PacketCharInfo {
-                    x: 1,
-                    y: 2,
-                    w: 3,
-                    h: 4,
-                    a: 5,
-                    d: 6,
-                    d2: 7,
-                    dhit: 8,
-                    numchar: 9,
-                    idchar: 10,
-                    totchar: 11,
-                    totenemies: 12,
-                    exit: true,
-                    healt: 13,
-                    stamina: 14,
-                    damage: 15,
-                    idmap: 16,
-                    totlifeless: 17,
-                    step: 18,
-                    vision: 19,
-                    listlifeless: [
-                        None, None, None, None,
-                        Some(PacketLifelessInfo {
-                            x: 20,
-                            y: 21,
-                            w: 22,
-                            h: 23,
-                            d: 24,
-                            damage: 25,
-                        })
-                    ]
+    fn handshake_version_mismatch() {
+        let transport = Loopback::new();
+
+        let mut server = Server::<PacketCharInfo>::new(Handshake { version: 2, build: "new".to_owned(), capabilities: 0 });
+        server.listen_with(transport.clone(), &loopback_addr(34000)).unwrap();
+
+        let result = Client::<PacketCharInfo>::connect_with(&transport, &loopback_addr(34000), Handshake { version: 1, build: "old".to_owned(), capabilities: 0 });
+
+        match result {
+            Err(NetError::Rejected(reason)) => assert!(reason.contains("version mismatch")),
+            Err(e) => panic!("Unexpected error: {}", e),
+            Ok(_) => panic!("A stale client must be rejected."),
+        }
+
+        // rejected clients are never announced to the game
+        thread::sleep(Duration::from_millis(50));
+        assert!(server.try_recv().unwrap().is_none());
+    }
+
+    #[test]
+    fn broadcast_to_many_clients() {
+        let hello = Handshake { version: 1, build: "test".to_owned(), capabilities: 0 };
+
+        let transport = Loopback::new();
+
+        let mut server = Server::<PacketCharInfo>::new(hello.clone());
+        server.listen_with(transport.clone(), &loopback_addr(34000)).unwrap();
+
+        let clients: Vec<_> = (0..32)
+            .map(|_| {
+                let mut client = Client::<PacketCharInfo>::connect_with(&transport, &loopback_addr(34000), hello.clone()).unwrap();
+                client.start();
+                client
+            })
+            .collect();
+
+        for _ in 0..clients.len() {
+            match server.recv().unwrap() {
+                Message::Accepted(_) => (),
+                msg => panic!("Unexpected message: {:?}", msg),
+            }
+        }
+
+        let packet = PacketCharInfo { idchar: EntityId::new(7, 0), ..PacketCharInfo::default() };
+        server.send(Message::Broadcast(packet.clone()));
+
+        for client in clients.iter() {
+            assert_eq!(client.recv_timeout(Duration::from_secs(1)).unwrap(), Some(packet.clone()));
+        }
+    }
+
+    #[test]
+    fn connect_refused() {
+        // nobody listens on this address
+        let result = Client::<PacketCharInfo>::connect_with(&Loopback::new(), &loopback_addr(34000), Handshake::default());
+
+        match result {
+            Err(NetError::ConnectRefused) => (),
+            Err(e) => panic!("Unexpected error: {}", e),
+            Ok(_) => panic!("Nobody should be listening."),
+        }
+    }
+
+    #[test]
+    fn server_closed() {
+        let hello = Handshake { version: 1, build: "test".to_owned(), capabilities: 0 };
+
+        let transport = Loopback::new();
+
+        let mut server = Server::<PacketCharInfo>::new(hello.clone());
+        server.listen_with(transport.clone(), &loopback_addr(34000)).unwrap();
+
+        let mut client = Client::<PacketCharInfo>::connect_with(&transport, &loopback_addr(34000), hello).unwrap();
+        client.start();
+
+        // dropping the server closes every connection
+        drop(server);
+
+        match client.recv() {
+            Err(NetError::Closed) => (),
+            other => panic!("Unexpected result: {:?}", other),
+        }
+    }
+
+    #[test]
+    fn heartbeat_rtt() {
+        let hello = Handshake { version: 1, build: "test".to_owned(), capabilities: 0 };
+        let heartbeat = Heartbeat { interval: Duration::from_millis(5), timeout: Duration::from_secs(1) };
+        let transport = Loopback::new();
+
+        let mut server = Server::<PacketCharInfo>::new(hello.clone());
+        server.set_heartbeat(heartbeat);
+        server.listen_with(transport.clone(), &loopback_addr(34000)).unwrap();
+
+        let mut client = Client::<PacketCharInfo>::connect_with(&transport, &loopback_addr(34000), hello).unwrap();
+        client.set_heartbeat(heartbeat);
+        client.start();
+
+        let addr =
+            match server.recv().unwrap() {
+                Message::Accepted(addr) => addr,
+                msg => panic!("Unexpected message: {:?}", msg),
+            };
+
+        client.send(PacketCharInfo::default());
+
+        // a few heartbeats, waiting longer if the machine is busy
+        let deadline = Instant::now() + Duration::from_secs(1);
+        while (client.rtt().is_none() || server.rtt(&addr).is_none() || client.stats().packets_sent < 3) && Instant::now() < deadline {
+            thread::sleep(Duration::from_millis(10));
+        }
+
+        assert!(client.rtt().is_some());
+        assert!(server.rtt(&addr).is_some());
+
+        let client_stats = client.stats();
+        let server_stats = server.stats()[&addr].clone();
+        assert!(client_stats.packets_sent > 1);
+        // a pong may be waiting to be written right now
+        assert!(client_stats.queue_depth <= 1);
+        assert_eq!(client_stats.dropped_sends, 0);
+        assert!(server_stats.rtt.is_some());
+        assert!(server_stats.packets_received > 1);
+
+        match server.recv().unwrap() {
+            Message::Direct(_, from) => assert_eq!(from, addr),
+            msg => panic!("Unexpected message: {:?}", msg),
+        }
+
+        // pings alone keep both sides connected
+        assert_eq!(client.try_recv().unwrap(), None);
+        assert!(server.try_recv().unwrap().is_none());
+    }
+
+    #[test]
+    fn queue_depth_never_wraps() {
+        let hello = Handshake { version: 1, build: "test".to_owned(), capabilities: 0 };
+        // no pings, so every packet queued is one of ours
+        let heartbeat = Heartbeat { interval: Duration::from_secs(60), timeout: Duration::from_secs(60) };
+        let transport = Loopback::new();
+
+        let mut server = Server::<PacketCharInfo>::new(hello.clone());
+        server.set_heartbeat(heartbeat);
+        server.listen_with(transport.clone(), &loopback_addr(34000)).unwrap();
+
+        let mut client = Client::<PacketCharInfo>::connect_with(&transport, &loopback_addr(34000), hello).unwrap();
+        client.set_heartbeat(heartbeat);
+        client.start();
+
+        // watches the depth while the packets are queued and written
+        let (stats, done) = (client.stats.clone(), Arc::new(AtomicBool::new(false)));
+        let watcher = {
+            let done = done.clone();
+            thread::spawn(move || {
+                let mut deepest = 0;
+                while !done.load(Ordering::Relaxed) {
+                    deepest = deepest.max(stats.snapshot().queue_depth);
                 }
+                deepest
+            })
+        };
+
+        let sent = 20_000;
+        for _ in 0..sent {
+            client.send(PacketCharInfo::default());
+        }
+
+        done.store(true, Ordering::Relaxed);
+        let deepest = watcher.join().unwrap();
+        assert!(deepest <= sent, "Queue depth {} after {} packets.", deepest, sent);
+    }
+
+    #[test]
+    fn silent_server_times_out() {
+        let transport = Loopback::new();
+        let mut acceptor = transport.listen(&loopback_addr(34000)).unwrap();
+
+        // accepts the hello, then never says anything again
+        let silent = thread::spawn(move || {
+            let mut channel = acceptor.accept().unwrap();
+            read_control(&mut *channel.receiver).unwrap();
+            channel.sender.send_frame(&control_packet(Control::Accept { capabilities: 0 })).unwrap();
+            channel
+        });
+
+        let mut client = Client::<PacketCharInfo>::connect_with(&transport, &loopback_addr(34000), Handshake::default()).unwrap();
+        client.set_heartbeat(Heartbeat { interval: Duration::from_millis(5), timeout: Duration::from_millis(30) });
+        client.start();
+
+        let _channel = silent.join().unwrap();
+        match client.recv() {
+            Err(NetError::Timeout) => (),
+            other => panic!("Unexpected result: {:?}", other),
+        }
+    }
+
+    #[test]
+    fn silent_client_times_out() {
+        let transport = Loopback::new();
+
+        let mut server = Server::<PacketCharInfo>::new(Handshake::default());
+        server.set_heartbeat(Heartbeat { interval: Duration::from_millis(5), timeout: Duration::from_millis(30) });
+        server.listen_with(transport.clone(), &loopback_addr(34000)).unwrap();
+
+        // says hello, then never says anything again
+        let mut channel = transport.connect(&loopback_addr(34000)).unwrap();
+        channel.sender.send_frame(&control_packet(Control::Hello(Handshake::default()))).unwrap();
+
+        let addr =
+            match server.recv().unwrap() {
+                Message::Accepted(addr) => addr,
+                msg => panic!("Unexpected message: {:?}", msg),
             };
-        
-        let buf = packet.to_bytes();
-        let packet2 = Packet::<PacketCharInfo>::from_bytes(&buf);
 
-        assert_eq!(packet.sign, packet2.sign);
-        assert_eq!(packet.data,  packet2.data);
+        match server.recv().unwrap() {
+            Message::Disconnected(disconnected, NetError::Timeout) => assert_eq!(disconnected, addr),
+            msg => panic!("Unexpected message: {:?}", msg),
+        }
+    }
+
+    #[test]
+    fn usage_tcp() {
+        let hello = Handshake { version: 1, build: "test".to_owned(), capabilities: 0 };
+
+        // any free port, so parallel tests never collide
+        let mut server = Server::<PacketCharInfo>::new(hello.clone());
+        server.listen(0).unwrap();
+        let addr = server.local_addr().unwrap();
+
+        let mut client = Client::<PacketCharInfo>::connect(&addr, hello).unwrap();
+        client.start();
+
+        let packet = PacketCharInfo { idchar: EntityId::new(3, 0), ..PacketCharInfo::default() };
+        client.send(packet.clone());
+
+        match server.recv().unwrap() {
+            Message::Accepted(_) => (),
+            msg => panic!("Unexpected message: {:?}", msg),
+        }
+
+        let client_addr =
+            match server.recv().unwrap() {
+                Message::Direct(packet2, client_addr) => {
+                    assert_eq!(packet, packet2);
+                    client_addr
+                },
+                msg => panic!("Unexpected message: {:?}", msg),
+            };
+
+        drop(client);
+
+        match server.recv().unwrap() {
+            Message::Disconnected(addr, _) => assert_eq!(addr, client_addr),
+            msg => panic!("Unexpected message: {:?}", msg),
+        }
     }
 }
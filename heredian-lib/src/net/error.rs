@@ -0,0 +1,63 @@
+use std::fmt;
+use std::io;
+
+use crate::DecodeError;
+
+/// Everything that can go wrong with a connection.
+#[derive(Debug)]
+pub enum NetError {
+    /// Nobody is listening at the server address.
+    ConnectRefused,
+    /// The peer didn't answer in time.
+    Timeout,
+    /// The peer closed the connection.
+    Closed,
+    /// The connection was reset or aborted by the peer.
+    PeerReset,
+    /// The server refused the handshake, with its reason.
+    Rejected(String),
+    /// The peer sent bytes that aren't a valid packet.
+    Decode(DecodeError),
+    /// A message too large for a packet, with the size the packet would have had.
+    TooLarge(usize),
+    /// Any other I/O failure.
+    Io(io::Error),
+}
+
+impl fmt::Display for NetError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            NetError::ConnectRefused => write!(f, "Connection refused by the server."),
+            NetError::Timeout => write!(f, "Connection timed out."),
+            NetError::Closed => write!(f, "Connection closed."),
+            NetError::PeerReset => write!(f, "Connection reset by the peer."),
+            NetError::Rejected(reason) => write!(f, "Rejected by the server: {}", reason),
+            NetError::Decode(e) => write!(f, "{}", e),
+            NetError::TooLarge(size) => write!(f, "Packet of {} bytes is too large.", size),
+            NetError::Io(e) => write!(f, "I/O error: {}", e),
+        }
+    }
+}
+
+impl std::error::Error for NetError {}
+
+impl From<io::Error> for NetError {
+    fn from(e: io::Error) -> Self {
+        match e.kind() {
+            io::ErrorKind::ConnectionRefused => NetError::ConnectRefused,
+            io::ErrorKind::TimedOut | io::ErrorKind::WouldBlock => NetError::Timeout,
+            io::ErrorKind::UnexpectedEof => NetError::Closed,
+            io::ErrorKind::ConnectionReset |
+            io::ErrorKind::ConnectionAborted |
+            io::ErrorKind::BrokenPipe => NetError::PeerReset,
+            io::ErrorKind::InvalidData => NetError::Decode(DecodeError(e.to_string())),
+            _ => NetError::Io(e),
+        }
+    }
+}
+
+impl From<DecodeError> for NetError {
+    fn from(e: DecodeError) -> Self {
+        NetError::Decode(e)
+    }
+}
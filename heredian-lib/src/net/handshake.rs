@@ -0,0 +1,153 @@
+use std::convert::{TryInto};
+
+use crate::{ToBytes, FromBytes, DecodeError, put_str, get_str, check_len};
+
+const TAG_HELLO: u8 = 1;
+const TAG_ACCEPT: u8 = 2;
+const TAG_REJECT: u8 = 3;
+const TAG_PING: u8 = 4;
+const TAG_PONG: u8 = 5;
+
+/// What a peer announces about itself when a connection opens.
+#[derive(Default, Debug, Clone, PartialEq)]
+pub struct Handshake {
+    pub version: u16,
+    pub build: String,
+    pub capabilities: u32,
+}
+
+impl Handshake {
+    /// Checks the hello of a client against this (server side) handshake.
+    ///
+    /// Returns the capabilities supported by both sides, or the reason the client is refused.
+    pub fn validate(&self, hello: &Handshake) -> Result<u32, String> {
+        if hello.version != self.version {
+            Err(format!(
+                "Protocol version mismatch: client is {} (build {}), server is {} (build {}).",
+                hello.version, hello.build, self.version, self.build))
+        } else {
+            Ok(self.capabilities & hello.capabilities)
+        }
+    }
+}
+
+/// Messages of the net layer itself, sent in `KIND_CONTROL` packets.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Control {
+    /// client -> server: first packet of every connection.
+    Hello(Handshake),
+    /// server -> client: the hello was accepted, with the capabilities enabled for this connection.
+    Accept { capabilities: u32 },
+    /// server -> client: the hello was refused; the server closes the connection right after.
+    Reject { reason: String },
+    /// both ways: heartbeat, carrying the time it was sent on the sender clock.
+    Ping { stamp: u64 },
+    /// both ways: answer to `Ping`, echoing its stamp.
+    Pong { stamp: u64 },
+}
+
+impl ToBytes for Control {
+    fn to_bytes(&self) -> Vec<u8> {
+        let mut buf = Vec::new();
+
+        match self {
+            Control::Hello(hello) => {
+                buf.push(TAG_HELLO);
+                buf.extend_from_slice(&hello.version.to_le_bytes());
+                buf.extend_from_slice(&hello.capabilities.to_le_bytes());
+                put_str(&mut buf, &hello.build);
+            },
+            Control::Accept { capabilities } => {
+                buf.push(TAG_ACCEPT);
+                buf.extend_from_slice(&capabilities.to_le_bytes());
+            },
+            Control::Reject { reason } => {
+                buf.push(TAG_REJECT);
+                put_str(&mut buf, reason);
+            },
+            Control::Ping { stamp } => {
+                buf.push(TAG_PING);
+                buf.extend_from_slice(&stamp.to_le_bytes());
+            },
+            Control::Pong { stamp } => {
+                buf.push(TAG_PONG);
+                buf.extend_from_slice(&stamp.to_le_bytes());
+            },
+        }
+
+        buf
+    }
+}
+
+impl FromBytes for Control {
+    fn from_bytes(buf: &[u8]) -> Result<Self, DecodeError> {
+        check_len(buf, 1, "Control")?;
+        let body = &buf[1..];
+
+        let control = match buf[0] {
+            TAG_HELLO => {
+                check_len(body, 6, "Hello")?;
+                Control::Hello(Handshake {
+                    version: u16::from_le_bytes(body[0..2].try_into().unwrap()),
+                    capabilities: u32::from_le_bytes(body[2..6].try_into().unwrap()),
+                    build: get_str(body, 6)?,
+                })
+            },
+            TAG_ACCEPT => {
+                check_len(body, 4, "Accept")?;
+                Control::Accept {
+                    capabilities: u32::from_le_bytes(body[0..4].try_into().unwrap()),
+                }
+            },
+            TAG_REJECT => Control::Reject {
+                reason: get_str(body, 0)?,
+            },
+            TAG_PING => {
+                check_len(body, 8, "Ping")?;
+                Control::Ping {
+                    stamp: u64::from_le_bytes(body[0..8].try_into().unwrap()),
+                }
+            },
+            TAG_PONG => {
+                check_len(body, 8, "Pong")?;
+                Control::Pong {
+                    stamp: u64::from_le_bytes(body[0..8].try_into().unwrap()),
+                }
+            },
+            tag => return Err(DecodeError(format!("Unknown control tag {}.", tag))),
+        };
+
+        Ok(control)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn ser_des_control() {
+        let messages = vec![
+            Control::Hello(Handshake { version: 3, build: "0.1.0".to_owned(), capabilities: 5 }),
+            Control::Accept { capabilities: 4 },
+            Control::Reject { reason: "Go away.".to_owned() },
+            Control::Ping { stamp: 1 << 40 },
+            Control::Pong { stamp: 7 },
+        ];
+
+        for msg in messages {
+            assert_eq!(Control::from_bytes(&msg.to_bytes()), Ok(msg));
+        }
+    }
+
+    #[test]
+    fn validate_version() {
+        let server = Handshake { version: 2, build: "b".to_owned(), capabilities: 0b110 };
+
+        let client = Handshake { version: 2, build: "a".to_owned(), capabilities: 0b011 };
+        assert_eq!(server.validate(&client), Ok(0b010));
+
+        let client = Handshake { version: 1, build: "a".to_owned(), capabilities: 0b011 };
+        assert!(server.validate(&client).unwrap_err().contains("version mismatch"));
+    }
+}
@@ -0,0 +1,19 @@
+use std::time::Duration;
+
+/// How often a connection is probed and how long its peer may stay silent.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Heartbeat {
+    /// Time between two pings.
+    pub interval: Duration,
+    /// Silence after which the peer is considered gone.
+    pub timeout: Duration,
+}
+
+impl Default for Heartbeat {
+    fn default() -> Self {
+        Self {
+            interval: Duration::from_secs(1),
+            timeout: Duration::from_secs(10),
+        }
+    }
+}
@@ -0,0 +1,319 @@
+use std::convert::{TryInto};
+use std::io::{Read};
+
+use crate::{ToBytes, FromBytes, DecodeError, check_len};
+use super::error::NetError;
+
+/// Signature that opens every packet on the wire.
+pub const SIGN: &[u8; 3] = b"GDP";
+
+/// Bytes taken by the packet header: the signature, the length prefix and the kind.
+pub const HEADER_SIZE: usize = 6;
+
+/// Packet carrying an application message.
+pub const KIND_DATA: u8 = 0;
+
+/// Packet carrying a `Control` message of the net layer itself.
+pub const KIND_CONTROL: u8 = 1;
+
+#[derive(Default, Debug)]
+#[repr(C)]
+pub struct Packet<TData>
+    where
+        TData: Sized + Send + ToBytes + FromBytes {
+    
+    pub sign: [u8;3], // "GDP"
+    pub size: u16, // header + payload, in bytes
+    pub kind: u8,
+    pub data: TData
+}
+
+impl<TData> Packet<TData>
+    where
+        TData: Sized + Send + ToBytes + FromBytes {
+
+    pub fn new(msg: TData) -> Result<Self, NetError> {
+        Self::with_kind(KIND_DATA, msg)
+    }
+
+    /// Packet of `kind` carrying `msg`, unless `msg` is too large for the length prefix.
+    pub fn with_kind(kind: u8, msg: TData) -> Result<Self, NetError> {
+        let size = HEADER_SIZE + msg.to_bytes().len();
+
+        if size > u16::MAX as usize {
+            return Err(NetError::TooLarge(size));
+        }
+
+        Ok(Self {
+            sign: SIGN.to_owned(),
+            size: size as u16,
+            kind,
+            data: msg
+        })
+    }
+}
+
+impl<TData> FromBytes for Packet<TData>
+    where
+        TData: Sized + Send + ToBytes + FromBytes {
+
+    fn from_bytes(buf: &[u8]) -> Result<Self, DecodeError> {
+        check_len(buf, HEADER_SIZE, "Packet header")?;
+        let size = u16::from_le_bytes(buf[3..5].try_into().unwrap());
+
+        check_len(buf, size as usize, "Packet")?;
+        if (size as usize) < HEADER_SIZE {
+            return Err(DecodeError(format!("Invalid packet size {}.", size)));
+        }
+
+        Ok(Self {
+            sign: buf[0..3].try_into().unwrap(),
+            size,
+            kind: buf[5],
+            data: TData::from_bytes(&buf[HEADER_SIZE..size as usize])?,
+        })
+    }
+}
+
+impl<TData> ToBytes for Packet<TData>
+    where
+        TData: Sized + Send + ToBytes + FromBytes {
+
+    fn to_bytes(&self) -> Vec<u8> {
+        let payload = self.data.to_bytes();
+        assert!(HEADER_SIZE + payload.len() <= u16::MAX as usize, "Packet payload of {} bytes is too large.", payload.len());
+        let size = (HEADER_SIZE + payload.len()) as u16;
+
+        let mut buf = Vec::with_capacity(size as usize);
+        buf.extend_from_slice(&self.sign);
+        buf.extend_from_slice(&size.to_le_bytes());
+        buf.push(self.kind);
+        buf.extend_from_slice(&payload);
+
+        buf
+    }
+}
+
+/// Accumulates bytes read from a stream and splits them into whole packets.
+///
+/// Reads on a non-blocking socket may stop anywhere inside a packet, so the
+/// leftover bytes are kept until the next poll completes the frame.
+#[derive(Default, Debug)]
+pub struct FrameReader {
+    buf: Vec<u8>,
+}
+
+impl FrameReader {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Appends raw bytes to the pending buffer.
+    pub fn extend(&mut self, data: &[u8]) {
+        self.buf.extend_from_slice(data);
+    }
+
+    /// Blocks until a complete packet is available, reading from `stream` as needed.
+    ///
+    /// A closed stream is reported as `UnexpectedEof`.
+    pub fn read_frame<R: Read>(&mut self, stream: &mut R) -> std::io::Result<Vec<u8>> {
+        let mut chunk = [0u8; 1024];
+
+        loop {
+            if let Some(frame) = self.next_frame()? {
+                return Ok(frame);
+            }
+
+            match stream.read(&mut chunk) {
+                Ok(0) => return Err(std::io::ErrorKind::UnexpectedEof.into()),
+                Ok(read) => self.extend(&chunk[..read]),
+                Err(ref e) if e.kind() == std::io::ErrorKind::Interrupted => (),
+                Err(e) => return Err(e),
+            }
+        }
+    }
+
+    /// Takes the next complete packet out of the buffer, header included.
+    pub fn next_frame(&mut self) -> std::io::Result<Option<Vec<u8>>> {
+        if self.buf.len() < HEADER_SIZE {
+            return Ok(None);
+        }
+
+        if &self.buf[0..3] != SIGN {
+            return Err(std::io::Error::new(std::io::ErrorKind::InvalidData, "Invalid packet signature."));
+        }
+
+        let size = u16::from_le_bytes(self.buf[3..5].try_into().unwrap()) as usize;
+        if size < HEADER_SIZE {
+            return Err(std::io::Error::new(std::io::ErrorKind::InvalidData, format!("Invalid packet size {}.", size)));
+        }
+
+        if self.buf.len() < size {
+            return Ok(None);
+        }
+
+        let rest = self.buf.split_off(size);
+        let frame = std::mem::replace(&mut self.buf, rest);
+
+        Ok(Some(frame))
+    }
+}
+
+/// Kind of a complete packet returned by `FrameReader::next_frame`.
+pub fn frame_kind(frame: &[u8]) -> u8 {
+    frame[5]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{PacketCharInfo, PacketLifelessInfo};
+    use crate::entity::EntityId;
+
+    #[test]
+    fn ser_des() {
+        let packet = 
+            Packet {
+                sign: b"GDP".to_owned(),
+                size: 0,
+                kind: KIND_DATA,
+                data: PacketCharInfo {
+                    x: 1,
+                    y: 2,
+                    w: 3,
+                    h: 4,
+                    a: 5,
+                    d: 6,
+                    d2: 7,
+                    dhit: 8,
+                    numchar: 9,
+                    idchar: EntityId::new(10, 0),
+                    totchar: 11,
+                    totenemies: 12,
+                    exit: true,
+                    healt: 13,
+                    stamina: 14,
+                    damage: 15,
+                    idmap: 16,
+                    totlifeless: 17,
+                    step: 18,
+                    vision: 19,
+                    listlifeless: [
+                        None, None, None, None,
+                        Some(PacketLifelessInfo {
+                            x: 20,
+                            y: 21,
+                            w: 22,
+                            h: 23,
+                            d: 24,
+                            damage: 25,
+                        })
+                    ]
+                }
+            };
+        
+        let buf = packet.to_bytes();
+        let packet2 = Packet::<PacketCharInfo>::from_bytes(&buf).unwrap();
+
+        assert_eq!(packet.sign, packet2.sign);
+        assert_eq!(packet2.size as usize, buf.len());
+        assert_eq!(packet.kind, packet2.kind);
+        assert_eq!(packet.data,  packet2.data);
+    }
+
+    #[derive(Default, Debug, PartialEq)]
+    struct Text(String);
+
+    impl FromBytes for Text {
+        fn from_bytes(buf: &[u8]) -> Result<Self, DecodeError> {
+            Ok(Text(String::from_utf8(buf.to_vec()).unwrap()))
+        }
+    }
+
+    impl ToBytes for Text {
+        fn to_bytes(&self) -> Vec<u8> {
+            self.0.as_bytes().to_vec()
+        }
+    }
+
+    #[test]
+    fn variable_size_packets() {
+        let short = Packet::new(Text("hi".to_owned())).unwrap();
+        let long = Packet::new(Text("a much longer message".to_owned())).unwrap();
+
+        assert_eq!(short.size as usize, HEADER_SIZE + 2);
+        assert_eq!(long.to_bytes().len(), long.size as usize);
+
+        let packet = Packet::<Text>::from_bytes(&long.to_bytes()).unwrap();
+        assert_eq!(packet.data, long.data);
+    }
+
+    #[test]
+    fn oversized_packets_are_refused() {
+        let largest = "a".repeat(u16::MAX as usize - HEADER_SIZE);
+
+        assert_eq!(Packet::new(Text(largest.clone())).unwrap().to_bytes().len(), u16::MAX as usize);
+
+        match Packet::new(Text(largest + "a")) {
+            Err(NetError::TooLarge(size)) => assert_eq!(size, u16::MAX as usize + 1),
+            other => panic!("Unexpected result: {:?}", other),
+        }
+    }
+
+    #[test]
+    fn frame_reader_partial_reads() {
+        let first = Packet::new(Text("first".to_owned())).unwrap().to_bytes();
+        let second = Packet::new(Text("second one".to_owned())).unwrap().to_bytes();
+
+        let mut stream = first.clone();
+        stream.extend_from_slice(&second);
+
+        let mut reader = FrameReader::new();
+
+        // header only
+        reader.extend(&stream[..3]);
+        assert_eq!(reader.next_frame().unwrap(), None);
+
+        // first packet plus part of the second
+        reader.extend(&stream[3..first.len() + 4]);
+        assert_eq!(reader.next_frame().unwrap(), Some(first.clone()));
+        assert_eq!(reader.next_frame().unwrap(), None);
+
+        // the rest of the second packet
+        reader.extend(&stream[first.len() + 4..]);
+        let frame = reader.next_frame().unwrap().unwrap();
+        assert_eq!(Packet::<Text>::from_bytes(&frame).unwrap().data, Text("second one".to_owned()));
+        assert_eq!(reader.next_frame().unwrap(), None);
+    }
+
+    #[test]
+    fn frame_reader_blocking_reads() {
+        let first = Packet::new(Text("first".to_owned())).unwrap().to_bytes();
+        let second = Packet::new(Text("second one".to_owned())).unwrap().to_bytes();
+
+        let mut bytes = first.clone();
+        bytes.extend_from_slice(&second);
+        let mut stream = &bytes[..];
+
+        let mut reader = FrameReader::new();
+        assert_eq!(reader.read_frame(&mut stream).unwrap(), first);
+        assert_eq!(reader.read_frame(&mut stream).unwrap(), second);
+        assert_eq!(reader.read_frame(&mut stream).unwrap_err().kind(), std::io::ErrorKind::UnexpectedEof);
+    }
+
+    #[test]
+    fn frame_reader_invalid_size() {
+        let mut reader = FrameReader::new();
+        reader.extend(b"GDP\x02\x00\x00");
+
+        assert!(reader.next_frame().is_err());
+    }
+
+    #[test]
+    fn frame_reader_invalid_sign() {
+        let mut reader = FrameReader::new();
+        reader.extend(b"XYZ\x06\x00\x00");
+
+        assert!(reader.next_frame().is_err());
+    }
+}
@@ -0,0 +1,222 @@
+use std::fmt;
+use std::sync::Mutex;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{Duration, Instant};
+
+/// Traffic of one connection, as seen from this side.
+#[derive(Default, Debug, Clone, PartialEq)]
+pub struct NetStats {
+    pub bytes_sent: u64,
+    pub bytes_received: u64,
+    /// Packets of every kind, heartbeats included.
+    pub packets_sent: u64,
+    pub packets_received: u64,
+    /// Game messages per second, averaged since the snapshot that last updated them, at least a second earlier.
+    pub sent_per_sec: f32,
+    pub received_per_sec: f32,
+    /// Last round trip measured by the heartbeats.
+    pub rtt: Option<Duration>,
+    /// Packets queued but not written yet.
+    pub queue_depth: u64,
+    /// Messages that never left because the connection was gone.
+    pub dropped_sends: u64,
+}
+
+impl fmt::Display for NetStats {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.rtt {
+            Some(rtt) => write!(f, "rtt {} ms", rtt.as_millis())?,
+            None => write!(f, "rtt ?")?,
+        }
+
+        write!(f, ", in {:.0} msg/s {} pkt {} B, out {:.0} msg/s {} pkt {} B, queue {}, dropped {}",
+            self.received_per_sec, self.packets_received, self.bytes_received,
+            self.sent_per_sec, self.packets_sent, self.bytes_sent,
+            self.queue_depth, self.dropped_sends)
+    }
+}
+
+/// Messages counted at the start of the current rate window.
+struct Rates {
+    since: Instant,
+    sent: u64,
+    received: u64,
+    sent_per_sec: f32,
+    received_per_sec: f32,
+}
+
+/// Counters of one connection, shared by its threads.
+///
+/// Times are kept as microseconds since the connection opened, so they fit atomics.
+pub(crate) struct ConnStats {
+    start: Instant,
+    /// when the last packet was received.
+    last_recv: AtomicU64,
+    /// last round trip measured; `u64::MAX` until the first pong.
+    rtt: AtomicU64,
+    bytes_sent: AtomicU64,
+    bytes_received: AtomicU64,
+    packets_sent: AtomicU64,
+    packets_received: AtomicU64,
+    messages_sent: AtomicU64,
+    messages_received: AtomicU64,
+    queued: AtomicU64,
+    dropped: AtomicU64,
+    rates: Mutex<Rates>,
+}
+
+impl ConnStats {
+    pub(crate) fn new() -> Self {
+        let start = Instant::now();
+
+        Self {
+            start,
+            last_recv: AtomicU64::new(0),
+            rtt: AtomicU64::new(u64::MAX),
+            bytes_sent: AtomicU64::new(0),
+            bytes_received: AtomicU64::new(0),
+            packets_sent: AtomicU64::new(0),
+            packets_received: AtomicU64::new(0),
+            messages_sent: AtomicU64::new(0),
+            messages_received: AtomicU64::new(0),
+            queued: AtomicU64::new(0),
+            dropped: AtomicU64::new(0),
+            rates: Mutex::new(Rates { since: start, sent: 0, received: 0, sent_per_sec: 0.0, received_per_sec: 0.0 }),
+        }
+    }
+
+    /// Stamp of the current time, as carried by pings.
+    pub(crate) fn stamp(&self) -> u64 {
+        self.start.elapsed().as_micros() as u64
+    }
+
+    /// How long the peer has been silent.
+    pub(crate) fn silence(&self) -> Duration {
+        Duration::from_micros(self.stamp().saturating_sub(self.last_recv.load(Ordering::Relaxed)))
+    }
+
+    /// Records the answer to the ping sent at `stamp`.
+    pub(crate) fn pong(&self, stamp: u64) {
+        self.rtt.store(self.stamp().saturating_sub(stamp), Ordering::Relaxed);
+    }
+
+    /// Last round trip measured, if any.
+    pub(crate) fn rtt(&self) -> Option<Duration> {
+        match self.rtt.load(Ordering::Relaxed) {
+            u64::MAX => None,
+            rtt => Some(Duration::from_micros(rtt)),
+        }
+    }
+
+    /// Records a packet waiting to be written.
+    pub(crate) fn queue(&self) {
+        self.queued.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Records a queued packet taken by the writing thread.
+    pub(crate) fn dequeue(&self) {
+        self.queued.fetch_sub(1, Ordering::Relaxed);
+    }
+
+    /// Records a message that couldn't be sent.
+    pub(crate) fn drop_send(&self) {
+        self.dropped.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Records a packet written; `message` tells whether it carried a game message.
+    pub(crate) fn sent(&self, size: usize, message: bool) {
+        self.bytes_sent.fetch_add(size as u64, Ordering::Relaxed);
+        self.packets_sent.fetch_add(1, Ordering::Relaxed);
+
+        if message {
+            self.messages_sent.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    /// Records a packet received, which also proves the peer alive.
+    pub(crate) fn received(&self, size: usize, message: bool) {
+        self.last_recv.store(self.stamp(), Ordering::Relaxed);
+        self.bytes_received.fetch_add(size as u64, Ordering::Relaxed);
+        self.packets_received.fetch_add(1, Ordering::Relaxed);
+
+        if message {
+            self.messages_received.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    pub(crate) fn snapshot(&self) -> NetStats {
+        let sent = self.messages_sent.load(Ordering::Relaxed);
+        let received = self.messages_received.load(Ordering::Relaxed);
+
+        let mut rates = self.rates.lock().unwrap();
+        let elapsed = rates.since.elapsed();
+
+        // the rates only move once a second, so they stay readable when drawn every frame
+        if elapsed >= Duration::from_secs(1) {
+            let secs = elapsed.as_secs_f32();
+
+            *rates = Rates {
+                since: Instant::now(),
+                sent,
+                received,
+                sent_per_sec: (sent - rates.sent) as f32 / secs,
+                received_per_sec: (received - rates.received) as f32 / secs,
+            };
+        }
+
+        NetStats {
+            bytes_sent: self.bytes_sent.load(Ordering::Relaxed),
+            bytes_received: self.bytes_received.load(Ordering::Relaxed),
+            packets_sent: self.packets_sent.load(Ordering::Relaxed),
+            packets_received: self.packets_received.load(Ordering::Relaxed),
+            sent_per_sec: rates.sent_per_sec,
+            received_per_sec: rates.received_per_sec,
+            rtt: self.rtt(),
+            queue_depth: self.queued.load(Ordering::Relaxed),
+            dropped_sends: self.dropped.load(Ordering::Relaxed),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn liveness() {
+        let stats = ConnStats::new();
+        assert_eq!(stats.rtt(), None);
+
+        let stamp = stats.stamp();
+        std::thread::sleep(Duration::from_millis(5));
+        assert!(stats.silence() >= Duration::from_millis(5));
+
+        stats.received(10, false);
+        stats.pong(stamp);
+        assert!(stats.silence() < Duration::from_millis(5));
+        assert!(stats.rtt().unwrap() >= Duration::from_millis(5));
+    }
+
+    #[test]
+    fn counters() {
+        let stats = ConnStats::new();
+
+        stats.queue();
+        stats.queue();
+        stats.dequeue();
+        stats.sent(20, true);
+        stats.sent(14, false);
+        stats.received(30, true);
+        stats.drop_send();
+
+        assert_eq!(stats.snapshot(), NetStats {
+            bytes_sent: 34,
+            bytes_received: 30,
+            packets_sent: 2,
+            packets_received: 1,
+            queue_depth: 1,
+            dropped_sends: 1,
+            ..NetStats::default()
+        });
+    }
+}
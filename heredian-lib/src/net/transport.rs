@@ -0,0 +1,55 @@
+pub mod tcp;
+pub mod loopback;
+pub mod simulator;
+
+use std::net::SocketAddr;
+use std::time::Duration;
+
+use super::error::NetError;
+
+pub use tcp::*;
+pub use loopback::*;
+pub use simulator::*;
+
+/// Sending half of a connection.
+pub trait FrameSender: Send {
+    /// Sends one complete packet, header included.
+    fn send_frame(&mut self, frame: &[u8]) -> Result<(), NetError>;
+
+    /// Closes the connection; the peer receives `NetError::Closed` once it read everything sent before.
+    fn close(&mut self);
+}
+
+/// Receiving half of a connection.
+pub trait FrameReceiver: Send {
+    /// Blocks until the next complete packet arrives, header included.
+    ///
+    /// Gives up with `NetError::Timeout` after `timeout`, if any.
+    fn recv_frame(&mut self, timeout: Option<Duration>) -> Result<Vec<u8>, NetError>;
+}
+
+/// An open connection, split in halves so each one can be driven by its own thread.
+pub struct Channel {
+    pub sender: Box<dyn FrameSender>,
+    pub receiver: Box<dyn FrameReceiver>,
+    /// Address identifying the other side of the connection.
+    pub peer: SocketAddr,
+}
+
+/// Source of the connections of a server.
+pub trait Acceptor: Send {
+    /// Blocks until a new client connects.
+    fn accept(&mut self) -> Result<Channel, NetError>;
+
+    /// Address the clients connect to.
+    fn local_addr(&self) -> SocketAddr;
+}
+
+/// How packets get from one side to the other.
+pub trait Transport: Send + Sync {
+    /// Opens a connection to the server listening at `addr`.
+    fn connect(&self, addr: &SocketAddr) -> Result<Channel, NetError>;
+
+    /// Starts accepting connections at `addr`.
+    fn listen(&self, addr: &SocketAddr) -> Result<Box<dyn Acceptor>, NetError>;
+}
@@ -0,0 +1,177 @@
+use std::collections::HashMap;
+use std::net::{SocketAddr, Ipv4Addr};
+use std::sync::{Arc, Mutex};
+use std::sync::mpsc::{channel, Sender, Receiver, RecvTimeoutError};
+use std::time::Duration;
+
+use super::*;
+
+/// Packets passed in memory between the threads of a single process.
+///
+/// Servers and clients only see each other when they share the same `Loopback`
+/// (or clones of it), so independent instances never collide, whatever addresses they use.
+#[derive(Default, Clone)]
+pub struct Loopback {
+    shared: Arc<Mutex<Registry>>,
+}
+
+#[derive(Default)]
+struct Registry {
+    /// servers by the address they listen at.
+    listeners: HashMap<SocketAddr, Sender<Channel>>,
+    /// connections made so far, used to give each client its own address.
+    connections: u32,
+}
+
+impl Loopback {
+    pub fn new() -> Self {
+        Self::default()
+    }
+}
+
+struct LoopbackSender {
+    tx: Option<Sender<Vec<u8>>>,
+}
+
+impl FrameSender for LoopbackSender {
+    fn send_frame(&mut self, frame: &[u8]) -> Result<(), NetError> {
+        match self.tx.as_ref() {
+            Some(tx) => tx.send(frame.to_vec()).map_err(|_| NetError::Closed),
+            None => Err(NetError::Closed),
+        }
+    }
+
+    fn close(&mut self) {
+        self.tx = None;
+    }
+}
+
+struct LoopbackReceiver {
+    rx: Receiver<Vec<u8>>,
+}
+
+impl FrameReceiver for LoopbackReceiver {
+    fn recv_frame(&mut self, timeout: Option<Duration>) -> Result<Vec<u8>, NetError> {
+        match timeout {
+            Some(timeout) => self.rx.recv_timeout(timeout).map_err(|e| match e {
+                RecvTimeoutError::Timeout => NetError::Timeout,
+                RecvTimeoutError::Disconnected => NetError::Closed,
+            }),
+            None => self.rx.recv().map_err(|_| NetError::Closed),
+        }
+    }
+}
+
+/// Both ends of an in-memory connection.
+fn pipe(client: SocketAddr, server: SocketAddr) -> (Channel, Channel) {
+    let (client_tx, server_rx) = channel();
+    let (server_tx, client_rx) = channel();
+
+    let client_side = Channel {
+        sender: Box::new(LoopbackSender { tx: Some(client_tx) }),
+        receiver: Box::new(LoopbackReceiver { rx: client_rx }),
+        peer: server,
+    };
+
+    let server_side = Channel {
+        sender: Box::new(LoopbackSender { tx: Some(server_tx) }),
+        receiver: Box::new(LoopbackReceiver { rx: server_rx }),
+        peer: client,
+    };
+
+    (client_side, server_side)
+}
+
+struct LoopbackAcceptor {
+    incoming: Receiver<Channel>,
+    addr: SocketAddr,
+    shared: Arc<Mutex<Registry>>,
+}
+
+impl Acceptor for LoopbackAcceptor {
+    fn accept(&mut self) -> Result<Channel, NetError> {
+        // the registry keeps a sender while the acceptor lives, so this can't fail
+        self.incoming.recv().map_err(|_| NetError::Closed)
+    }
+
+    fn local_addr(&self) -> SocketAddr {
+        self.addr
+    }
+}
+
+impl Drop for LoopbackAcceptor {
+    fn drop(&mut self) {
+        // frees the address for the next server
+        if let Ok(mut registry) = self.shared.lock() {
+            registry.listeners.remove(&self.addr);
+        }
+    }
+}
+
+impl Transport for Loopback {
+    fn connect(&self, addr: &SocketAddr) -> Result<Channel, NetError> {
+        let mut registry = self.shared.lock().unwrap();
+
+        registry.connections += 1;
+        let client = SocketAddr::new(Ipv4Addr::from(0x7f00_0000 + registry.connections).into(), 1);
+        let (client_side, server_side) = pipe(client, *addr);
+
+        match registry.listeners.get(addr) {
+            Some(listener) if listener.send(server_side).is_ok() => Ok(client_side),
+            _ => Err(NetError::ConnectRefused),
+        }
+    }
+
+    fn listen(&self, addr: &SocketAddr) -> Result<Box<dyn Acceptor>, NetError> {
+        let mut registry = self.shared.lock().unwrap();
+
+        if registry.listeners.contains_key(addr) {
+            return Err(std::io::Error::from(std::io::ErrorKind::AddrInUse).into());
+        }
+
+        let (tx, incoming) = channel();
+        registry.listeners.insert(*addr, tx);
+
+        Ok(Box::new(LoopbackAcceptor { incoming, addr: *addr, shared: self.shared.clone() }))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn loopback_pipe() {
+        let transport = Loopback::new();
+        let addr = SocketAddr::new(Ipv4Addr::LOCALHOST.into(), 1000);
+
+        assert!(transport.connect(&addr).is_err());
+
+        let mut acceptor = transport.listen(&addr).unwrap();
+        assert!(transport.listen(&addr).is_err());
+
+        let mut client = transport.connect(&addr).unwrap();
+        let mut server = acceptor.accept().unwrap();
+        assert_eq!(client.peer, addr);
+        assert_ne!(server.peer, addr);
+
+        client.sender.send_frame(b"ping").unwrap();
+        assert_eq!(server.receiver.recv_frame(None).unwrap(), b"ping");
+
+        match server.receiver.recv_frame(Some(Duration::from_millis(1))) {
+            Err(NetError::Timeout) => (),
+            other => panic!("Unexpected result: {:?}", other),
+        }
+
+        server.sender.close();
+        match client.receiver.recv_frame(None) {
+            Err(NetError::Closed) => (),
+            other => panic!("Unexpected result: {:?}", other),
+        }
+
+        // the address is free again once the server stops
+        drop(acceptor);
+        assert!(transport.connect(&addr).is_err());
+        assert!(transport.listen(&addr).is_ok());
+    }
+}
@@ -0,0 +1,411 @@
+use std::cmp::Ordering;
+use std::collections::BinaryHeap;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicU64, Ordering as AtomicOrdering};
+use std::sync::mpsc::{channel, Sender, Receiver, RecvTimeoutError};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use rand::{Rng, SeedableRng};
+use rand::rngs::StdRng;
+
+use super::*;
+use crate::file_manager::ConfigFile;
+use crate::net::packet::{HEADER_SIZE, KIND_CONTROL, frame_kind};
+
+/// Network conditions imposed by `Simulated` on each direction of a connection.
+#[derive(Default, Debug, Clone, PartialEq)]
+pub struct Conditions {
+    /// Delay added to every packet.
+    pub latency: Duration,
+    /// Largest random variation of the delay, up or down.
+    pub jitter: Duration,
+    /// Bytes per second the link carries; 0 for unlimited.
+    pub bandwidth: u32,
+    /// Chance, from 0 to 1, of a packet being overtaken by the ones sent after it.
+    pub reorder: f32,
+    /// Chance, from 0 to 1, of a packet being lost.
+    pub loss: f32,
+    /// Seed of every random choice, so a session can be reproduced.
+    pub seed: u64,
+}
+
+impl Conditions {
+    /// Reads the `net_sim*` keys of a config file.
+    ///
+    /// Returns `None` unless `net_sim=1`. Times are in milliseconds.
+    pub fn from_config(config: &ConfigFile) -> Option<Self> {
+        if config.get::<u8>("net_sim").unwrap_or(0) == 0 {
+            return None;
+        }
+
+        let millis = |key| Duration::from_millis(config.get(key).unwrap_or(0));
+
+        Some(Self {
+            latency: millis("net_sim_latency"),
+            jitter: millis("net_sim_jitter"),
+            bandwidth: config.get("net_sim_bandwidth").unwrap_or(0),
+            reorder: config.get("net_sim_reorder").unwrap_or(0.0),
+            loss: config.get("net_sim_loss").unwrap_or(0.0),
+            seed: config.get("net_sim_seed").unwrap_or(0),
+        })
+    }
+}
+
+/// Decides when each packet sent in one direction arrives.
+struct Link {
+    conditions: Conditions,
+    rng: StdRng,
+    /// when the link is done carrying the packets already sent.
+    free_at: Instant,
+    /// arrival of the last packet kept in order.
+    ordered_at: Instant,
+    /// arrival of the last packet of all.
+    latest_at: Instant,
+}
+
+impl Link {
+    fn new(conditions: Conditions, seed: u64) -> Self {
+        let now = Instant::now();
+
+        Self {
+            conditions,
+            rng: StdRng::seed_from_u64(seed),
+            free_at: now,
+            ordered_at: now,
+            latest_at: now,
+        }
+    }
+
+    /// When `frame`, sent now, arrives; `None` if it's lost.
+    ///
+    /// Control packets are never lost nor reordered, so the handshake always completes.
+    fn schedule(&mut self, frame: &[u8]) -> Option<Instant> {
+        let now = Instant::now();
+        let control = frame.len() >= HEADER_SIZE && frame_kind(frame) == KIND_CONTROL;
+
+        if !control && self.rng.gen::<f32>() < self.conditions.loss {
+            return None;
+        }
+
+        // the link carries one packet after the other
+        self.free_at = self.free_at.max(now);
+        if self.conditions.bandwidth > 0 {
+            self.free_at += Duration::from_secs_f64(frame.len() as f64 / f64::from(self.conditions.bandwidth));
+        }
+
+        let jitter = self.conditions.jitter.as_secs_f64();
+        let jitter = if jitter > 0.0 { self.rng.gen_range(-jitter, jitter) } else { 0.0 };
+        let delay = (self.conditions.latency.as_secs_f64() + jitter).max(0.0);
+        let at = self.free_at + Duration::from_secs_f64(delay);
+
+        let at =
+            if !control && self.rng.gen::<f32>() < self.conditions.reorder {
+                // held back, so the next packets overtake it
+                let most = (self.conditions.latency + self.conditions.jitter).as_secs_f64().max(0.002);
+                at + Duration::from_secs_f64(self.rng.gen_range(0.001, most))
+            } else {
+                // otherwise packets arrive in the order they were sent, as on a stream
+                self.ordered_at = self.ordered_at.max(at);
+                self.ordered_at
+            };
+
+        self.latest_at = self.latest_at.max(at);
+        Some(at)
+    }
+}
+
+/// Something waiting for its arrival; the earliest one comes out of the heap first.
+struct Pending<T> {
+    at: Instant,
+    seq: u64,
+    item: T,
+}
+
+impl<T> Ord for Pending<T> {
+    fn cmp(&self, other: &Self) -> Ordering {
+        other.at.cmp(&self.at).then(other.seq.cmp(&self.seq))
+    }
+}
+
+impl<T> PartialOrd for Pending<T> {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl<T> PartialEq for Pending<T> {
+    fn eq(&self, other: &Self) -> bool {
+        self.cmp(other) == Ordering::Equal
+    }
+}
+
+impl<T> Eq for Pending<T> {}
+
+/// Hands every item of `rx` to `deliver` at its arrival time.
+///
+/// Returns once `rx` is closed and everything arrived, or `deliver` returns false.
+fn deliver_loop<T>(rx: Receiver<(Instant, T)>, mut deliver: impl FnMut(T) -> bool) {
+    let mut queue: BinaryHeap<Pending<T>> = BinaryHeap::new();
+    let mut seq = 0;
+    let mut open = true;
+
+    loop {
+        let now = Instant::now();
+        while let Some(pending) = queue.peek() {
+            if pending.at > now {
+                break;
+            }
+
+            if !deliver(queue.pop().unwrap().item) {
+                return;
+            }
+        }
+
+        let wait = queue.peek().map(|pending| pending.at - now);
+        let incoming =
+            match (open, wait) {
+                (true, None) => rx.recv().map_err(|_| RecvTimeoutError::Disconnected),
+                (true, Some(wait)) => rx.recv_timeout(wait),
+                (false, Some(wait)) => {
+                    thread::sleep(wait);
+                    continue;
+                },
+                (false, None) => return,
+            };
+
+        match incoming {
+            Ok((at, item)) => {
+                queue.push(Pending { at, seq, item });
+                seq += 1;
+            },
+            Err(RecvTimeoutError::Timeout) => (),
+            Err(RecvTimeoutError::Disconnected) => open = false,
+        }
+    }
+}
+
+struct SimSender {
+    link: Link,
+    tx: Option<Sender<(Instant, Vec<u8>)>>,
+}
+
+impl SimSender {
+    fn start(mut inner: Box<dyn FrameSender>, link: Link) -> Self {
+        let (tx, rx) = channel();
+
+        thread::spawn(move || {
+            deliver_loop(rx, |frame: Vec<u8>| inner.send_frame(&frame).is_ok());
+            inner.close();
+        });
+
+        Self { link, tx: Some(tx) }
+    }
+}
+
+impl FrameSender for SimSender {
+    fn send_frame(&mut self, frame: &[u8]) -> Result<(), NetError> {
+        let tx = self.tx.as_ref().ok_or(NetError::Closed)?;
+
+        match self.link.schedule(frame) {
+            Some(at) => tx.send((at, frame.to_vec())).map_err(|_| NetError::Closed),
+            None => Ok(()),
+        }
+    }
+
+    fn close(&mut self) {
+        // the connection closes once everything sent before arrived
+        self.tx = None;
+    }
+}
+
+struct SimReceiver {
+    rx: Receiver<Result<Vec<u8>, NetError>>,
+}
+
+impl SimReceiver {
+    fn start(mut inner: Box<dyn FrameReceiver>, mut link: Link) -> Self {
+        let (sched_tx, sched_rx) = channel();
+        let (tx, rx) = channel();
+
+        thread::spawn(move || {
+            loop {
+                let scheduled =
+                    match inner.recv_frame(None) {
+                        Ok(frame) => match link.schedule(&frame) {
+                            Some(at) => sched_tx.send((at, Ok(frame))),
+                            None => Ok(()),
+                        },
+                        Err(e) => {
+                            // the failure arrives after everything received before it
+                            let _ = sched_tx.send((link.latest_at.max(Instant::now()), Err(e)));
+                            return;
+                        }
+                    };
+
+                if scheduled.is_err() {
+                    return;
+                }
+            }
+        });
+
+        thread::spawn(move || deliver_loop(sched_rx, |item| tx.send(item).is_ok()));
+
+        Self { rx }
+    }
+}
+
+impl FrameReceiver for SimReceiver {
+    fn recv_frame(&mut self, timeout: Option<Duration>) -> Result<Vec<u8>, NetError> {
+        match timeout {
+            Some(timeout) => self.rx.recv_timeout(timeout).map_err(|e| match e {
+                RecvTimeoutError::Timeout => NetError::Timeout,
+                RecvTimeoutError::Disconnected => NetError::Closed,
+            })?,
+            None => self.rx.recv().map_err(|_| NetError::Closed)?,
+        }
+    }
+}
+
+/// Applies `conditions` to both directions of `channel`.
+fn simulate(channel: Channel, conditions: &Conditions, links: &AtomicU64) -> Channel {
+    // every direction gets its own random sequence, derived from the seed
+    let seed = |link: u64| conditions.seed.wrapping_add(link.wrapping_mul(0x9e37_79b9_7f4a_7c15));
+    let link = links.fetch_add(2, AtomicOrdering::SeqCst);
+
+    Channel {
+        sender: Box::new(SimSender::start(channel.sender, Link::new(conditions.clone(), seed(link)))),
+        receiver: Box::new(SimReceiver::start(channel.receiver, Link::new(conditions.clone(), seed(link + 1)))),
+        peer: channel.peer,
+    }
+}
+
+struct SimAcceptor {
+    inner: Box<dyn Acceptor>,
+    conditions: Conditions,
+    links: Arc<AtomicU64>,
+}
+
+impl Acceptor for SimAcceptor {
+    fn accept(&mut self) -> Result<Channel, NetError> {
+        Ok(simulate(self.inner.accept()?, &self.conditions, &self.links))
+    }
+
+    fn local_addr(&self) -> SocketAddr {
+        self.inner.local_addr()
+    }
+}
+
+/// Wraps another transport, so its connections behave like a real network instead of localhost.
+///
+/// The conditions apply to each direction of every connection made through it, so
+/// enabling it on one side of the game is enough.
+pub struct Simulated<T: Transport> {
+    inner: T,
+    conditions: Conditions,
+    links: Arc<AtomicU64>,
+}
+
+impl<T: Transport> Simulated<T> {
+    pub fn new(inner: T, conditions: Conditions) -> Self {
+        Self {
+            inner,
+            conditions,
+            links: Arc::new(AtomicU64::new(0)),
+        }
+    }
+}
+
+impl<T: Transport> Transport for Simulated<T> {
+    fn connect(&self, addr: &SocketAddr) -> Result<Channel, NetError> {
+        Ok(simulate(self.inner.connect(addr)?, &self.conditions, &self.links))
+    }
+
+    fn listen(&self, addr: &SocketAddr) -> Result<Box<dyn Acceptor>, NetError> {
+        Ok(Box::new(SimAcceptor {
+            inner: self.inner.listen(addr)?,
+            conditions: self.conditions.clone(),
+            links: self.links.clone(),
+        }))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::Ipv4Addr;
+
+    use super::*;
+    use crate::net::packet::{Packet, KIND_DATA};
+    use crate::net::handshake::Control;
+    use crate::ToBytes;
+
+    /// A packet of 11 bytes.
+    fn frame(kind: u8, n: u32) -> Vec<u8> {
+        Packet::with_kind(kind, Control::Accept { capabilities: n }).unwrap().to_bytes()
+    }
+
+    #[test]
+    fn same_seed_same_losses() {
+        let conditions = Conditions { loss: 0.5, reorder: 0.5, seed: 42, ..Conditions::default() };
+
+        let losses = |seed| {
+            let mut link = Link::new(conditions.clone(), seed);
+            (0..100).map(|n| link.schedule(&frame(KIND_DATA, n)).is_none()).collect::<Vec<_>>()
+        };
+
+        assert_eq!(losses(1), losses(1));
+        assert_ne!(losses(1), losses(2));
+    }
+
+    #[test]
+    fn control_packets_are_never_lost() {
+        let mut link = Link::new(Conditions { loss: 1.0, ..Conditions::default() }, 0);
+
+        assert_eq!(link.schedule(&frame(KIND_DATA, 0)), None);
+        assert!(link.schedule(&frame(KIND_CONTROL, 0)).is_some());
+    }
+
+    #[test]
+    fn bandwidth_and_order() {
+        let conditions = Conditions {
+            latency: Duration::from_millis(10),
+            jitter: Duration::from_millis(10),
+            bandwidth: 1000,
+            ..Conditions::default()
+        };
+
+        let start = Instant::now();
+        let mut link = Link::new(conditions, 0);
+        let arrivals: Vec<_> = (0..10).map(|n| link.schedule(&frame(KIND_DATA, n)).unwrap()).collect();
+
+        // 10 packets of 11 bytes take 110ms at 1000 bytes per second
+        assert!(arrivals[9] - start >= Duration::from_millis(110));
+        assert!(arrivals.windows(2).all(|pair| pair[0] <= pair[1]));
+    }
+
+    #[test]
+    fn simulated_latency() {
+        let conditions = Conditions { latency: Duration::from_millis(50), ..Conditions::default() };
+        let transport = Simulated::new(Loopback::new(), conditions);
+        let addr = SocketAddr::new(Ipv4Addr::LOCALHOST.into(), 1000);
+
+        let mut acceptor = transport.listen(&addr).unwrap();
+        let mut client = transport.connect(&addr).unwrap();
+        let mut server = acceptor.accept().unwrap();
+
+        // the client delays its sending direction and the server its receiving one
+        let start = Instant::now();
+        client.sender.send_frame(&frame(KIND_DATA, 1)).unwrap();
+        client.sender.send_frame(&frame(KIND_DATA, 2)).unwrap();
+        client.sender.close();
+
+        assert_eq!(server.receiver.recv_frame(None).unwrap(), frame(KIND_DATA, 1));
+        assert!(start.elapsed() >= Duration::from_millis(100));
+        assert_eq!(server.receiver.recv_frame(None).unwrap(), frame(KIND_DATA, 2));
+
+        match server.receiver.recv_frame(None) {
+            Err(NetError::Closed) => (),
+            other => panic!("Unexpected result: {:?}", other),
+        }
+    }
+}
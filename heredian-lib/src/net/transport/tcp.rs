@@ -0,0 +1,85 @@
+use std::io::Write;
+use std::net::{TcpListener, TcpStream, SocketAddr, Shutdown};
+use std::time::Duration;
+
+use super::*;
+use crate::net::packet::FrameReader;
+
+/// How long a client waits for the TCP connection to be established.
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Packets over TCP, the transport of the real game.
+#[derive(Default, Debug, Clone, Copy)]
+pub struct Tcp;
+
+struct TcpSender {
+    stream: TcpStream,
+}
+
+impl FrameSender for TcpSender {
+    fn send_frame(&mut self, frame: &[u8]) -> Result<(), NetError> {
+        Ok(self.stream.write_all(frame)?)
+    }
+
+    fn close(&mut self) {
+        // wakes the receiving half up as well
+        let _ = self.stream.shutdown(Shutdown::Both);
+    }
+}
+
+struct TcpReceiver {
+    stream: TcpStream,
+    reader: FrameReader,
+    timeout: Option<Duration>,
+}
+
+impl FrameReceiver for TcpReceiver {
+    fn recv_frame(&mut self, timeout: Option<Duration>) -> Result<Vec<u8>, NetError> {
+        if timeout != self.timeout {
+            self.stream.set_read_timeout(timeout)?;
+            self.timeout = timeout;
+        }
+
+        Ok(self.reader.read_frame(&mut self.stream)?)
+    }
+}
+
+fn split(stream: TcpStream, peer: SocketAddr) -> Result<Channel, NetError> {
+    stream.set_nodelay(true)?;
+
+    Ok(Channel {
+        sender: Box::new(TcpSender { stream: stream.try_clone()? }),
+        receiver: Box::new(TcpReceiver { stream, reader: FrameReader::new(), timeout: None }),
+        peer,
+    })
+}
+
+struct TcpAcceptor {
+    listener: TcpListener,
+    addr: SocketAddr,
+}
+
+impl Acceptor for TcpAcceptor {
+    fn accept(&mut self) -> Result<Channel, NetError> {
+        let (stream, peer) = self.listener.accept()?;
+        split(stream, peer)
+    }
+
+    fn local_addr(&self) -> SocketAddr {
+        self.addr
+    }
+}
+
+impl Transport for Tcp {
+    fn connect(&self, addr: &SocketAddr) -> Result<Channel, NetError> {
+        let stream = TcpStream::connect_timeout(addr, CONNECT_TIMEOUT)?;
+        split(stream, *addr)
+    }
+
+    fn listen(&self, addr: &SocketAddr) -> Result<Box<dyn Acceptor>, NetError> {
+        let listener = TcpListener::bind(addr)?;
+        let addr = listener.local_addr()?;
+
+        Ok(Box::new(TcpAcceptor { listener, addr }))
+    }
+}
@@ -0,0 +1,689 @@
+use std::convert::{TryInto};
+
+use super::*;
+use super::net::{Handshake, HEADER_SIZE};
+
+pub mod delta;
+pub use delta::*;
+
+/// Version of the wire protocol. Bump it whenever the encoding of any message changes.
+pub const PROTOCOL_VERSION: u16 = 8;
+
+/// Build of this crate, sent along the protocol version so mismatches are easier to report.
+pub const BUILD_ID: &str = env!("CARGO_PKG_VERSION");
+
+const TAG_JOIN: u8 = 1;
+const TAG_WELCOME: u8 = 2;
+const TAG_INPUT: u8 = 3;
+const TAG_ENTITY_STATE: u8 = 4;
+const TAG_ENTITY_DESPAWN: u8 = 5;
+const TAG_PROJECTILE_SPAWN: u8 = 6;
+const TAG_CHAT: u8 = 7;
+const TAG_DISCONNECT: u8 = 8;
+const TAG_BATCH: u8 = 9;
+const TAG_ENTITY_DELTA: u8 = 10;
+const TAG_ACK: u8 = 11;
+const TAG_INPUT_ACK: u8 = 12;
+const TAG_SNAPSHOT: u8 = 13;
+
+/// Largest encoded message a packet can carry.
+pub const MAX_MESSAGE_SIZE: usize = u16::MAX as usize - HEADER_SIZE;
+
+/// `InputCommand::buttons` bit held to run instead of walking.
+pub const BUTTON_RUN: u8 = 1;
+/// `InputCommand::buttons` bit held to attack.
+pub const BUTTON_ATTACK: u8 = 2;
+
+/// What the player asks of its hero during one client frame.
+#[derive(Debug, Default, PartialEq, Clone, Copy)]
+pub struct InputCommand {
+    /// Directions held, `DIRECTION_*` bits.
+    pub d2: u8,
+    /// `BUTTON_*` bits.
+    pub buttons: u8,
+}
+
+impl InputCommand {
+    /// Action the hero takes, an attack winning over any movement.
+    pub fn action(&self) -> i32 {
+        if self.buttons & BUTTON_ATTACK != 0 {
+            ACTION_ATTACK
+        } else if self.d2 == 0 {
+            ACTION_IDLE
+        } else if self.buttons & BUTTON_RUN != 0 {
+            ACTION_RUN
+        } else {
+            ACTION_WALK
+        }
+    }
+
+    /// Direction the hero faces, if any direction is held.
+    ///
+    /// With two held, right or left win over down, which wins over up.
+    pub fn facing(&self) -> Option<i32> {
+        [DIRECTION_RIGHT, DIRECTION_LEFT, DIRECTION_DOWN, DIRECTION_UP]
+            .iter()
+            .copied()
+            .find(|d| self.d2 as i32 & d != 0)
+    }
+}
+
+/// Why an entity stopped being sent to a client.
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum DespawnReason {
+    /// It is still around, only out of the client's interest.
+    OutOfView = 0,
+    /// Its player left the game.
+    Disconnected = 1,
+    /// Its health ran out.
+    Killed = 2,
+    /// It went through a gate to another map.
+    ChangedMap = 3,
+}
+
+impl DespawnReason {
+    fn from_u8(value: u8) -> Result<DespawnReason, DecodeError> {
+        match value {
+            0 => Ok(DespawnReason::OutOfView),
+            1 => Ok(DespawnReason::Disconnected),
+            2 => Ok(DespawnReason::Killed),
+            3 => Ok(DespawnReason::ChangedMap),
+            value => Err(DecodeError(format!("Unknown despawn reason {}.", value))),
+        }
+    }
+}
+
+/// A projectile in flight, as carried by a `Snapshot`.
+#[derive(Debug, Default, PartialEq, Clone)]
+pub struct ProjectileState {
+    /// Hero that fired it.
+    pub idchar: EntityId,
+    pub lifelessid: i16,
+    pub x: i16,
+    pub y: i16,
+    pub d: i16,
+}
+
+/// Every message exchanged between client and server.
+///
+/// On the wire a message is a one byte tag followed by its fields, little endian.
+#[derive(Debug, PartialEq, Clone)]
+pub enum GameMessage {
+    /// client -> server: enters the game with the chosen character, at the entrance of the
+    /// given map. `interp_delay` is how far behind (ms) the client draws other entities.
+    Join { numchar: i16, idmap: i16, interp_delay: u16 },
+    /// server -> client: answer to `Join`, carrying the id of the new hero.
+    Welcome { idchar: EntityId, totchar: i16 },
+    /// client -> server: what the player does during one frame. `seq` numbers the inputs
+    /// of a client; the server runs each one as a frame of the hero.
+    Input { seq: u16, command: InputCommand },
+    /// server -> client: current state of a hero or an enemy.
+    EntityState(PacketCharInfo),
+    /// server -> client: the entity is gone and should no longer be drawn.
+    EntityDespawn { idchar: EntityId, reason: DespawnReason },
+    /// server -> client: a hero fired a lifeless (projectile).
+    ProjectileSpawn { idchar: EntityId, lifelessid: i16, x: i16, y: i16, d: i16 },
+    /// both ways: chat line from a hero.
+    Chat { idchar: EntityId, text: String },
+    /// both ways: the peer is leaving, with a human readable reason.
+    Disconnect { reason: String },
+    /// server -> client: every message of one server tick, in order. Batches are never nested.
+    Batch(Vec<GameMessage>),
+    /// server -> client: changes to an entity since the `baseline` frame, or since the
+    /// default state when there is none. `seq` is the frame carrying it.
+    EntityDelta { seq: u16, baseline: Option<u16>, delta: StateDelta },
+    /// client -> server: frame `seq` was received, as were the 32 frames before it
+    /// whose bits are set, the lowest bit being `seq - 1`.
+    Ack { seq: u16, bits: u32 },
+    /// server -> client: last input processed, sent before the state of the hero it moved.
+    InputAck { seq: u16 },
+    /// server -> client: everything on the map of a hero that just joined, in as many
+    /// chunks as it takes, sent right after `Welcome` and before any other update.
+    Snapshot { entities: Vec<PacketCharInfo>, projectiles: Vec<ProjectileState> },
+}
+
+/// Handshake announced by both client and server when a connection opens.
+pub fn handshake() -> Handshake {
+    Handshake {
+        version: PROTOCOL_VERSION,
+        build: BUILD_ID.to_owned(),
+        capabilities: 0,
+    }
+}
+
+impl GameMessage {
+    fn tag(&self) -> u8 {
+        match self {
+            GameMessage::Join { .. } => TAG_JOIN,
+            GameMessage::Welcome { .. } => TAG_WELCOME,
+            GameMessage::Input { .. } => TAG_INPUT,
+            GameMessage::EntityState(_) => TAG_ENTITY_STATE,
+            GameMessage::EntityDespawn { .. } => TAG_ENTITY_DESPAWN,
+            GameMessage::ProjectileSpawn { .. } => TAG_PROJECTILE_SPAWN,
+            GameMessage::Chat { .. } => TAG_CHAT,
+            GameMessage::Disconnect { .. } => TAG_DISCONNECT,
+            GameMessage::Batch(_) => TAG_BATCH,
+            GameMessage::EntityDelta { .. } => TAG_ENTITY_DELTA,
+            GameMessage::Ack { .. } => TAG_ACK,
+            GameMessage::InputAck { .. } => TAG_INPUT_ACK,
+            GameMessage::Snapshot { .. } => TAG_SNAPSHOT,
+        }
+    }
+
+    /// Packs `msgs` in as few messages as possible, each one fitting a packet.
+    ///
+    /// A lone message is left as is, since a batch of one only adds bytes.
+    pub fn batch(msgs: Vec<GameMessage>) -> Vec<GameMessage> {
+        let mut batcher = Batcher::new();
+
+        for msg in msgs {
+            batcher.push(msg);
+        }
+
+        batcher.finish()
+    }
+
+    /// Splits everything a joining hero has to know about in as many `Snapshot` messages
+    /// as it takes for each to fit a packet. There is always at least one.
+    pub fn snapshots(entities: Vec<PacketCharInfo>, projectiles: Vec<ProjectileState>) -> Vec<GameMessage> {
+        // tag and both counts
+        const HEADER: usize = 5;
+
+        let mut chunks = Vec::new();
+        let (mut chunk_entities, mut chunk_projectiles) = (Vec::new(), Vec::new());
+        let mut size = HEADER;
+
+        for entity in entities {
+            // each entity is prefixed by its length
+            let len = 2 + entity.to_bytes().len();
+
+            if size + len > MAX_MESSAGE_SIZE && !chunk_entities.is_empty() {
+                chunks.push(GameMessage::Snapshot { entities: std::mem::take(&mut chunk_entities), projectiles: Vec::new() });
+                size = HEADER;
+            }
+
+            size += len;
+            chunk_entities.push(entity);
+        }
+
+        for p in projectiles {
+            if size + 12 > MAX_MESSAGE_SIZE {
+                chunks.push(GameMessage::Snapshot {
+                    entities: std::mem::take(&mut chunk_entities),
+                    projectiles: std::mem::take(&mut chunk_projectiles),
+                });
+                size = HEADER;
+            }
+
+            size += 12;
+            chunk_projectiles.push(p);
+        }
+
+        chunks.push(GameMessage::Snapshot { entities: chunk_entities, projectiles: chunk_projectiles });
+        chunks
+    }
+}
+
+/// Packs messages one at a time into batches that fit a packet.
+pub struct Batcher {
+    batches: Vec<GameMessage>,
+    current: Vec<GameMessage>,
+    size: usize,
+}
+
+impl Batcher {
+    // tag and count
+    const HEADER: usize = 3;
+
+    pub fn new() -> Batcher {
+        Batcher {
+            batches: Vec::new(),
+            current: Vec::new(),
+            size: Batcher::HEADER,
+        }
+    }
+
+    // each message is prefixed by its length
+    fn size_of(msg: &GameMessage) -> usize {
+        2 + msg.to_bytes().len()
+    }
+
+    /// Tells whether `msg` still fits the current batch.
+    pub fn fits(&self, msg: &GameMessage) -> bool {
+        self.current.is_empty() || self.size + Batcher::size_of(msg) <= MAX_MESSAGE_SIZE
+    }
+
+    /// Adds `msg` to the current batch, starting a new one if it does not fit.
+    pub fn push(&mut self, msg: GameMessage) {
+        if !self.fits(&msg) {
+            self.close();
+        }
+
+        self.size += Batcher::size_of(&msg);
+        self.current.push(msg);
+    }
+
+    fn close(&mut self) {
+        let mut msgs = std::mem::take(&mut self.current);
+        self.size = Batcher::HEADER;
+
+        match msgs.len() {
+            0 => (),
+            1 => self.batches.push(msgs.pop().unwrap()),
+            _ => self.batches.push(GameMessage::Batch(msgs)),
+        }
+    }
+
+    pub fn finish(mut self) -> Vec<GameMessage> {
+        self.close();
+        self.batches
+    }
+}
+
+impl Default for Batcher {
+    fn default() -> Batcher {
+        Batcher::new()
+    }
+}
+
+fn put_i16(buf: &mut Vec<u8>, value: i16) {
+    buf.extend_from_slice(&value.to_le_bytes());
+}
+
+fn get_i16(buf: &[u8], pos: usize) -> i16 {
+    i16::from_le_bytes(buf[pos..pos+2].try_into().unwrap())
+}
+
+fn put_id(buf: &mut Vec<u8>, id: EntityId) {
+    buf.extend_from_slice(&id.to_bits().to_le_bytes());
+}
+
+fn get_id(buf: &[u8], pos: usize) -> EntityId {
+    EntityId::from_bits(u32::from_le_bytes(buf[pos..pos+4].try_into().unwrap()))
+}
+
+impl ToBytes for GameMessage {
+    fn to_bytes(&self) -> Vec<u8> {
+        let mut buf = vec![self.tag()];
+
+        match self {
+            GameMessage::Join { numchar, idmap, interp_delay } => {
+                put_i16(&mut buf, *numchar);
+                put_i16(&mut buf, *idmap);
+                buf.extend_from_slice(&interp_delay.to_le_bytes());
+            },
+            GameMessage::Welcome { idchar, totchar } => {
+                put_id(&mut buf, *idchar);
+                put_i16(&mut buf, *totchar);
+            },
+            GameMessage::Input { seq, command } => {
+                buf.extend_from_slice(&seq.to_le_bytes());
+                buf.push(command.d2);
+                buf.push(command.buttons);
+            },
+            GameMessage::EntityState(info) => {
+                buf.extend_from_slice(&info.to_bytes());
+            },
+            GameMessage::EntityDespawn { idchar, reason } => {
+                put_id(&mut buf, *idchar);
+                buf.push(*reason as u8);
+            },
+            GameMessage::ProjectileSpawn { idchar, lifelessid, x, y, d } => {
+                put_id(&mut buf, *idchar);
+                for value in [*lifelessid, *x, *y, *d].iter() {
+                    put_i16(&mut buf, *value);
+                }
+            },
+            GameMessage::Chat { idchar, text } => {
+                put_id(&mut buf, *idchar);
+                put_str(&mut buf, text);
+            },
+            GameMessage::Disconnect { reason } => {
+                put_str(&mut buf, reason);
+            },
+            GameMessage::Batch(msgs) => {
+                buf.extend_from_slice(&(msgs.len() as u16).to_le_bytes());
+
+                for msg in msgs {
+                    let bytes = msg.to_bytes();
+                    buf.extend_from_slice(&(bytes.len() as u16).to_le_bytes());
+                    buf.extend_from_slice(&bytes);
+                }
+            },
+            GameMessage::EntityDelta { seq, baseline, delta } => {
+                buf.extend_from_slice(&seq.to_le_bytes());
+                // how far back the baseline is, 0 meaning none
+                let age = baseline.map_or(0, |baseline| seq.wrapping_sub(baseline));
+                buf.extend_from_slice(&age.to_le_bytes());
+                buf.extend_from_slice(&delta.to_bytes());
+            },
+            GameMessage::Ack { seq, bits } => {
+                buf.extend_from_slice(&seq.to_le_bytes());
+                buf.extend_from_slice(&bits.to_le_bytes());
+            },
+            GameMessage::InputAck { seq } => {
+                buf.extend_from_slice(&seq.to_le_bytes());
+            },
+            GameMessage::Snapshot { entities, projectiles } => {
+                buf.extend_from_slice(&(entities.len() as u16).to_le_bytes());
+
+                for entity in entities {
+                    let bytes = entity.to_bytes();
+                    buf.extend_from_slice(&(bytes.len() as u16).to_le_bytes());
+                    buf.extend_from_slice(&bytes);
+                }
+
+                buf.extend_from_slice(&(projectiles.len() as u16).to_le_bytes());
+
+                for p in projectiles {
+                    put_id(&mut buf, p.idchar);
+                    for value in [p.lifelessid, p.x, p.y, p.d].iter() {
+                        put_i16(&mut buf, *value);
+                    }
+                }
+            },
+        }
+
+        buf
+    }
+}
+
+impl FromBytes for GameMessage {
+    fn from_bytes(buf: &[u8]) -> Result<Self, DecodeError> {
+        check_len(buf, 1, "GameMessage")?;
+        let body = &buf[1..];
+
+        let msg = match buf[0] {
+            TAG_JOIN => {
+                check_len(body, 6, "Join")?;
+                GameMessage::Join {
+                    numchar: get_i16(body, 0),
+                    idmap: get_i16(body, 2),
+                    interp_delay: u16::from_le_bytes(body[4..6].try_into().unwrap()),
+                }
+            },
+            TAG_WELCOME => {
+                check_len(body, 6, "Welcome")?;
+                GameMessage::Welcome {
+                    idchar: get_id(body, 0),
+                    totchar: get_i16(body, 4),
+                }
+            },
+            TAG_INPUT => {
+                check_len(body, 4, "Input")?;
+                GameMessage::Input {
+                    seq: u16::from_le_bytes(body[0..2].try_into().unwrap()),
+                    command: InputCommand { d2: body[2], buttons: body[3] },
+                }
+            },
+            TAG_ENTITY_STATE => GameMessage::EntityState(PacketCharInfo::from_bytes(body)?),
+            TAG_ENTITY_DESPAWN => {
+                check_len(body, 5, "EntityDespawn")?;
+                GameMessage::EntityDespawn {
+                    idchar: get_id(body, 0),
+                    reason: DespawnReason::from_u8(body[4])?,
+                }
+            },
+            TAG_PROJECTILE_SPAWN => {
+                check_len(body, 12, "ProjectileSpawn")?;
+                GameMessage::ProjectileSpawn {
+                    idchar: get_id(body, 0),
+                    lifelessid: get_i16(body, 4),
+                    x: get_i16(body, 6),
+                    y: get_i16(body, 8),
+                    d: get_i16(body, 10),
+                }
+            },
+            TAG_CHAT => {
+                check_len(body, 4, "Chat")?;
+                GameMessage::Chat {
+                    idchar: get_id(body, 0),
+                    text: get_str(body, 4)?,
+                }
+            },
+            TAG_DISCONNECT => GameMessage::Disconnect {
+                reason: get_str(body, 0)?,
+            },
+            TAG_BATCH => {
+                check_len(body, 2, "Batch")?;
+                let count = u16::from_le_bytes(body[0..2].try_into().unwrap()) as usize;
+                let mut msgs = Vec::with_capacity(count);
+                let mut pos = 2;
+
+                for _ in 0..count {
+                    check_len(body, pos + 2, "Batch")?;
+                    let len = u16::from_le_bytes(body[pos..pos+2].try_into().unwrap()) as usize;
+                    check_len(body, pos + 2 + len, "Batch")?;
+
+                    let msg = GameMessage::from_bytes(&body[pos+2..pos+2+len])?;
+                    if let GameMessage::Batch(_) = msg {
+                        return Err(DecodeError("Nested batch.".to_owned()));
+                    }
+
+                    msgs.push(msg);
+                    pos += 2 + len;
+                }
+
+                GameMessage::Batch(msgs)
+            },
+            TAG_ENTITY_DELTA => {
+                check_len(body, 4, "EntityDelta")?;
+                let seq = u16::from_le_bytes(body[0..2].try_into().unwrap());
+                let age = u16::from_le_bytes(body[2..4].try_into().unwrap());
+
+                GameMessage::EntityDelta {
+                    seq,
+                    baseline: if age == 0 { None } else { Some(seq.wrapping_sub(age)) },
+                    delta: StateDelta::from_bytes(&body[4..])?,
+                }
+            },
+            TAG_ACK => {
+                check_len(body, 6, "Ack")?;
+                GameMessage::Ack {
+                    seq: u16::from_le_bytes(body[0..2].try_into().unwrap()),
+                    bits: u32::from_le_bytes(body[2..6].try_into().unwrap()),
+                }
+            },
+            TAG_INPUT_ACK => {
+                check_len(body, 2, "InputAck")?;
+                GameMessage::InputAck {
+                    seq: u16::from_le_bytes(body[0..2].try_into().unwrap()),
+                }
+            },
+            TAG_SNAPSHOT => {
+                check_len(body, 2, "Snapshot")?;
+                let count = u16::from_le_bytes(body[0..2].try_into().unwrap()) as usize;
+                let mut entities = Vec::with_capacity(count);
+                let mut pos = 2;
+
+                for _ in 0..count {
+                    check_len(body, pos + 2, "Snapshot")?;
+                    let len = u16::from_le_bytes(body[pos..pos+2].try_into().unwrap()) as usize;
+                    check_len(body, pos + 2 + len, "Snapshot")?;
+
+                    entities.push(PacketCharInfo::from_bytes(&body[pos+2..pos+2+len])?);
+                    pos += 2 + len;
+                }
+
+                check_len(body, pos + 2, "Snapshot")?;
+                let count = u16::from_le_bytes(body[pos..pos+2].try_into().unwrap()) as usize;
+                pos += 2;
+
+                check_len(body, pos + count * 12, "Snapshot")?;
+                let projectiles = body[pos..pos + count * 12]
+                    .chunks(12)
+                    .map(|p| ProjectileState {
+                        idchar: get_id(p, 0),
+                        lifelessid: get_i16(p, 4),
+                        x: get_i16(p, 6),
+                        y: get_i16(p, 8),
+                        d: get_i16(p, 10),
+                    })
+                    .collect();
+
+                GameMessage::Snapshot { entities, projectiles }
+            },
+            tag => return Err(DecodeError(format!("Unknown message tag {}.", tag))),
+        };
+
+        Ok(msg)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn roundtrip(msg: GameMessage) {
+        let buf = msg.to_bytes();
+        assert_eq!(buf[0], msg.tag());
+        assert_eq!(GameMessage::from_bytes(&buf), Ok(msg));
+    }
+
+    #[test]
+    fn ser_des_all_messages() {
+        let info = PacketCharInfo {
+            x: 1,
+            y: 2,
+            idchar: EntityId::new(3, 0),
+            healt: 4,
+            exit: true,
+            listlifeless: [
+                Some(PacketLifelessInfo { x: 5, y: 6, w: 7, h: 8, d: 9, damage: 10 }),
+                None, None, None, None
+            ],
+            ..PacketCharInfo::default()
+        };
+
+        roundtrip(GameMessage::Join { numchar: 1, idmap: 2, interp_delay: 100 });
+        roundtrip(GameMessage::Welcome { idchar: EntityId::new(19, 3), totchar: 2 });
+        roundtrip(GameMessage::Input { seq: 65535, command: InputCommand { d2: DIRECTION_RIGHTUP as u8, buttons: BUTTON_RUN } });
+        roundtrip(GameMessage::EntityState(info.clone()));
+        roundtrip(GameMessage::EntityDespawn { idchar: EntityId::new(7, 0), reason: DespawnReason::OutOfView });
+        roundtrip(GameMessage::EntityDespawn { idchar: EntityId::new(8, 0), reason: DespawnReason::ChangedMap });
+        roundtrip(GameMessage::ProjectileSpawn { idchar: EntityId::new(1, 0), lifelessid: 2, x: 3, y: 4, d: 8 });
+        roundtrip(GameMessage::Chat { idchar: EntityId::new(1, 0), text: "olá".to_owned() });
+        roundtrip(GameMessage::Disconnect { reason: "bye".to_owned() });
+        roundtrip(GameMessage::Batch(vec![
+            GameMessage::EntityDespawn { idchar: EntityId::new(7, 0), reason: DespawnReason::Killed },
+            GameMessage::EntityState(PacketCharInfo::default()),
+            GameMessage::Chat { idchar: EntityId::new(1, 0), text: "olá".to_owned() },
+        ]));
+        roundtrip(GameMessage::EntityDelta {
+            seq: 2,
+            baseline: Some(65534),
+            delta: StateDelta::between(&PacketCharInfo::default(), &info),
+        });
+        roundtrip(GameMessage::EntityDelta {
+            seq: 0,
+            baseline: None,
+            delta: StateDelta::between(&info, &info),
+        });
+        roundtrip(GameMessage::Ack { seq: 9, bits: 0xdead_beef });
+        roundtrip(GameMessage::InputAck { seq: 300 });
+        roundtrip(GameMessage::Snapshot { entities: vec![], projectiles: vec![] });
+        roundtrip(GameMessage::Snapshot {
+            entities: vec![info.clone(), PacketCharInfo::default()],
+            projectiles: vec![ProjectileState { idchar: EntityId::new(3, 0), lifelessid: 1, x: 10, y: 20, d: 2 }],
+        });
+    }
+
+    #[test]
+    fn batch_splits_large_ticks() {
+        let state = GameMessage::EntityState(PacketCharInfo::default());
+        let per_packet = MAX_MESSAGE_SIZE / (2 + state.to_bytes().len());
+
+        assert_eq!(GameMessage::batch(vec![]), vec![]);
+        assert_eq!(GameMessage::batch(vec![state.clone()]), vec![state.clone()]);
+
+        let batches = GameMessage::batch(vec![state.clone(); per_packet + 1]);
+        assert_eq!(batches.len(), 2);
+        assert!(batches.iter().all(|batch| batch.to_bytes().len() <= MAX_MESSAGE_SIZE));
+
+        match &batches[0] {
+            GameMessage::Batch(msgs) => assert_eq!(msgs.len(), per_packet),
+            msg => panic!("Unexpected message: {:?}", msg),
+        }
+        assert_eq!(batches[1], state);
+    }
+
+    #[test]
+    fn snapshots_split_to_fit() {
+        let info = PacketCharInfo::default();
+        let projectile = ProjectileState { idchar: EntityId::new(3, 0), lifelessid: 1, x: 10, y: 20, d: 2 };
+
+        assert_eq!(GameMessage::snapshots(vec![], vec![]), vec![GameMessage::Snapshot { entities: vec![], projectiles: vec![] }]);
+
+        let per_packet = (MAX_MESSAGE_SIZE - 5) / (2 + info.to_bytes().len());
+        let chunks = GameMessage::snapshots(vec![info; per_packet + 1], vec![projectile; 10_000]);
+
+        assert!(chunks.len() > 2);
+        assert!(chunks.iter().all(|chunk| chunk.to_bytes().len() <= MAX_MESSAGE_SIZE));
+
+        let (mut entities, mut projectiles) = (0, 0);
+        for chunk in &chunks {
+            match chunk {
+                GameMessage::Snapshot { entities: e, projectiles: p } => {
+                    entities += e.len();
+                    projectiles += p.len();
+                },
+                msg => panic!("Unexpected message: {:?}", msg),
+            }
+        }
+        assert_eq!((entities, projectiles), (per_packet + 1, 10_000));
+
+        match &chunks[0] {
+            GameMessage::Snapshot { entities, projectiles } => assert_eq!((entities.len(), projectiles.len()), (per_packet, 0)),
+            msg => panic!("Unexpected message: {:?}", msg),
+        }
+    }
+
+    #[test]
+    fn messages_have_different_sizes() {
+        let despawn = GameMessage::EntityDespawn { idchar: EntityId::new(7, 0), reason: DespawnReason::Disconnected }.to_bytes();
+        let state = GameMessage::EntityState(PacketCharInfo::default()).to_bytes();
+
+        assert!(despawn.len() < state.len());
+
+        let info = PacketCharInfo { idchar: EntityId::new(3, 0), x: 10, y: 10, ..PacketCharInfo::default() };
+        let moved = PacketCharInfo { x: 11, ..info.clone() };
+        let delta = GameMessage::EntityDelta { seq: 1, baseline: Some(0), delta: StateDelta::between(&info, &moved) }.to_bytes();
+
+        assert!(delta.len() * 5 < state.len());
+    }
+
+    #[test]
+    fn input_commands() {
+        let command = |d2: i32, buttons| InputCommand { d2: d2 as u8, buttons };
+
+        assert_eq!(command(0, 0).action(), ACTION_IDLE);
+        assert_eq!(command(0, BUTTON_RUN).action(), ACTION_IDLE);
+        assert_eq!(command(DIRECTION_UP, 0).action(), ACTION_WALK);
+        assert_eq!(command(DIRECTION_UP, BUTTON_RUN).action(), ACTION_RUN);
+        assert_eq!(command(DIRECTION_UP, BUTTON_RUN | BUTTON_ATTACK).action(), ACTION_ATTACK);
+
+        assert_eq!(command(0, 0).facing(), None);
+        assert_eq!(command(DIRECTION_LEFTUP, 0).facing(), Some(DIRECTION_LEFT));
+        assert_eq!(command(DIRECTION_RIGHTDOWN, 0).facing(), Some(DIRECTION_RIGHT));
+        assert_eq!(command(DIRECTION_UP | DIRECTION_DOWN, 0).facing(), Some(DIRECTION_DOWN));
+    }
+
+    #[test]
+    fn invalid_messages() {
+        assert!(GameMessage::from_bytes(&[]).is_err());
+        assert!(GameMessage::from_bytes(&[200]).is_err());
+        assert!(GameMessage::from_bytes(&[TAG_WELCOME, 1]).is_err());
+        assert!(GameMessage::from_bytes(&[TAG_INPUT, 1, 0, 2]).is_err());
+        assert!(GameMessage::from_bytes(&[TAG_SNAPSHOT, 0, 0, 1, 0, 1, 0]).is_err());
+        assert!(GameMessage::from_bytes(&[TAG_CHAT, 1, 0, 10, 0, b'a']).is_err());
+        assert!(GameMessage::from_bytes(&[TAG_BATCH, 2, 0, 4, 0, TAG_ENTITY_DESPAWN, 7, 0, 1]).is_err());
+        assert!(GameMessage::from_bytes(&[TAG_ENTITY_DESPAWN, 7, 0, 9]).is_err());
+
+        let nested = GameMessage::Batch(vec![GameMessage::Batch(vec![])]).to_bytes();
+        assert!(GameMessage::from_bytes(&nested).is_err());
+
+        let mut state = GameMessage::EntityState(PacketCharInfo::default()).to_bytes();
+        state.truncate(20);
+        assert!(GameMessage::from_bytes(&state).is_err());
+    }
+}
@@ -0,0 +1,499 @@
+use std::collections::{HashMap, VecDeque};
+use std::convert::{TryInto};
+
+use super::*;
+
+/// States kept per entity, on both ends, to be used as delta baselines.
+pub const DELTA_HISTORY: usize = 64;
+
+/// Frames the client acknowledges besides the latest one.
+const ACK_BITS: u16 = 32;
+
+const SCALARS: usize = 19;
+const LIFELESS_SIZE: usize = 12;
+
+/// Tells whether sequence `a` comes after `b`, allowing for wrap around.
+pub fn seq_newer(a: u16, b: u16) -> bool {
+    (a.wrapping_sub(b) as i16) > 0
+}
+
+/// Every field of a state but `idchar` and `listlifeless`, in wire order.
+fn scalars(info: &PacketCharInfo) -> [i16; SCALARS] {
+    [
+        info.x, info.y, info.w, info.h, info.a, info.d, info.d2, info.dhit, info.numchar,
+        info.totchar, info.totenemies, info.exit as i16, info.healt, info.stamina, info.damage,
+        info.idmap, info.totlifeless, info.step, info.vision,
+    ]
+}
+
+fn set_scalar(info: &mut PacketCharInfo, i: usize, value: i16) {
+    match i {
+        0 => info.x = value,
+        1 => info.y = value,
+        2 => info.w = value,
+        3 => info.h = value,
+        4 => info.a = value,
+        5 => info.d = value,
+        6 => info.d2 = value,
+        7 => info.dhit = value,
+        8 => info.numchar = value,
+        9 => info.totchar = value,
+        10 => info.totenemies = value,
+        11 => info.exit = value != 0,
+        12 => info.healt = value,
+        13 => info.stamina = value,
+        14 => info.damage = value,
+        15 => info.idmap = value,
+        16 => info.totlifeless = value,
+        17 => info.step = value,
+        18 => info.vision = value,
+        _ => unreachable!(),
+    }
+}
+
+/// Fields of an entity's state that changed since a baseline.
+///
+/// Bits `0..SCALARS` of the mask flag the scalar fields, the next `MAXCHARLIFELESS`
+/// bits flag the lifeless slots. Only flagged fields go on the wire.
+#[derive(Debug, PartialEq, Clone)]
+pub struct StateDelta {
+    pub idchar: EntityId,
+    mask: u32,
+    /// New values of the flagged fields, the others are left at their default.
+    values: PacketCharInfo,
+}
+
+impl StateDelta {
+    pub fn between(base: &PacketCharInfo, state: &PacketCharInfo) -> StateDelta {
+        let mut mask = 0;
+        let mut values = PacketCharInfo::default();
+
+        let (old, new) = (scalars(base), scalars(state));
+        for i in 0..SCALARS {
+            if old[i] != new[i] {
+                mask |= 1 << i;
+                set_scalar(&mut values, i, new[i]);
+            }
+        }
+
+        for i in 0..MAXCHARLIFELESS {
+            if base.listlifeless[i] != state.listlifeless[i] {
+                mask |= 1 << (SCALARS + i);
+                values.listlifeless[i] = state.listlifeless[i].clone();
+            }
+        }
+
+        StateDelta { idchar: state.idchar, mask, values }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.mask == 0
+    }
+
+    /// Rebuilds the full state from the baseline this delta was made against.
+    pub fn apply(&self, base: &PacketCharInfo) -> PacketCharInfo {
+        let mut state = base.clone();
+        state.idchar = self.idchar;
+
+        let values = scalars(&self.values);
+        for (i, value) in values.iter().enumerate() {
+            if self.mask & (1 << i) != 0 {
+                set_scalar(&mut state, i, *value);
+            }
+        }
+
+        for i in 0..MAXCHARLIFELESS {
+            if self.mask & (1 << (SCALARS + i)) != 0 {
+                state.listlifeless[i] = self.values.listlifeless[i].clone();
+            }
+        }
+
+        state
+    }
+}
+
+impl ToBytes for StateDelta {
+    fn to_bytes(&self) -> Vec<u8> {
+        let mut buf = Vec::new();
+        put_id(&mut buf, self.idchar);
+        buf.extend_from_slice(&self.mask.to_le_bytes());
+
+        let values = scalars(&self.values);
+        for (i, value) in values.iter().enumerate() {
+            if self.mask & (1 << i) != 0 {
+                put_i16(&mut buf, *value);
+            }
+        }
+
+        for i in 0..MAXCHARLIFELESS {
+            if self.mask & (1 << (SCALARS + i)) != 0 {
+                match &self.values.listlifeless[i] {
+                    Some(lifeless) => {
+                        buf.push(1);
+                        for value in [lifeless.x, lifeless.y, lifeless.w, lifeless.h, lifeless.d, lifeless.damage].iter() {
+                            put_i16(&mut buf, *value);
+                        }
+                    },
+                    None => buf.push(0),
+                }
+            }
+        }
+
+        buf
+    }
+}
+
+impl FromBytes for StateDelta {
+    fn from_bytes(buf: &[u8]) -> Result<Self, DecodeError> {
+        check_len(buf, 8, "StateDelta")?;
+        let idchar = get_id(buf, 0);
+        let mask = u32::from_le_bytes(buf[4..8].try_into().unwrap());
+
+        if mask >> (SCALARS + MAXCHARLIFELESS) != 0 {
+            return Err(DecodeError(format!("Invalid delta mask {:#x}.", mask)));
+        }
+
+        let mut values = PacketCharInfo::default();
+        let mut pos = 8;
+
+        for i in 0..SCALARS {
+            if mask & (1 << i) != 0 {
+                check_len(buf, pos + 2, "StateDelta")?;
+                set_scalar(&mut values, i, get_i16(buf, pos));
+                pos += 2;
+            }
+        }
+
+        for i in 0..MAXCHARLIFELESS {
+            if mask & (1 << (SCALARS + i)) != 0 {
+                check_len(buf, pos + 1, "StateDelta")?;
+                pos += 1;
+
+                if buf[pos - 1] == 1 {
+                    check_len(buf, pos + LIFELESS_SIZE, "StateDelta")?;
+                    values.listlifeless[i] = Some(PacketLifelessInfo {
+                        x: get_i16(buf, pos),
+                        y: get_i16(buf, pos + 2),
+                        w: get_i16(buf, pos + 4),
+                        h: get_i16(buf, pos + 6),
+                        d: get_i16(buf, pos + 8),
+                        damage: get_i16(buf, pos + 10),
+                    });
+                    pos += LIFELESS_SIZE;
+                }
+            }
+        }
+
+        Ok(StateDelta { idchar, mask, values })
+    }
+}
+
+struct Sent {
+    seq: u16,
+    state: PacketCharInfo,
+    acked: bool,
+}
+
+/// Server side of the delta compression, one per client.
+///
+/// Every frame sent to the client has its own sequence number. States are encoded
+/// against the newest state of the same entity that the client acknowledged, or
+/// against the default state when there is none.
+#[derive(Default)]
+pub struct DeltaEncoder {
+    seq: u16,
+    history: HashMap<EntityId, VecDeque<Sent>>,
+}
+
+impl DeltaEncoder {
+    pub fn new() -> DeltaEncoder {
+        DeltaEncoder::default()
+    }
+
+    /// Sequence number of the frame being built.
+    pub fn seq(&self) -> u16 {
+        self.seq
+    }
+
+    /// Starts a new frame.
+    ///
+    /// States sent half a wrap ago are dropped, before their sequence number comes
+    /// around again and an ack of the new frame passes for theirs.
+    pub fn next_frame(&mut self) {
+        self.seq = self.seq.wrapping_add(1);
+
+        let seq = self.seq;
+        for history in self.history.values_mut() {
+            history.retain(|sent| seq_newer(seq, sent.seq));
+        }
+    }
+
+    /// Handles an `Ack` from the client.
+    pub fn ack(&mut self, seq: u16, bits: u32) {
+        for sent in self.history.values_mut().flat_map(|history| history.iter_mut()) {
+            let age = seq.wrapping_sub(sent.seq);
+            if age == 0 || (age <= ACK_BITS && bits & (1 << (age - 1)) != 0) {
+                sent.acked = true;
+            }
+        }
+    }
+
+    fn baseline(&self, idchar: EntityId) -> Option<&Sent> {
+        self.history
+            .get(&idchar)?
+            .iter()
+            .rev()
+            .find(|sent| sent.acked && seq_newer(self.seq, sent.seq))
+    }
+
+    /// Encodes `state` for the current frame. Call `sent` once it is actually sent.
+    pub fn encode(&self, state: &PacketCharInfo) -> GameMessage {
+        match self.baseline(state.idchar) {
+            Some(sent) => GameMessage::EntityDelta {
+                seq: self.seq,
+                baseline: Some(sent.seq),
+                delta: StateDelta::between(&sent.state, state),
+            },
+            None => GameMessage::EntityDelta {
+                seq: self.seq,
+                baseline: None,
+                delta: StateDelta::between(&PacketCharInfo::default(), state),
+            },
+        }
+    }
+
+    /// Records `state` as sent in the current frame.
+    pub fn sent(&mut self, state: PacketCharInfo) {
+        let history = self.history.entry(state.idchar).or_default();
+
+        // states older than the newest acked one are never used again
+        if let Some(i) = history.iter().rposition(|sent| sent.acked) {
+            history.drain(..i);
+        }
+
+        history.push_back(Sent { seq: self.seq, state, acked: false });
+
+        if history.len() > DELTA_HISTORY {
+            history.pop_front();
+        }
+    }
+
+    /// Drops what was sent of `idchar`, once the client was told it is gone.
+    pub fn forget(&mut self, idchar: EntityId) {
+        self.history.remove(&idchar);
+    }
+}
+
+/// Client side of the delta compression.
+///
+/// Rebuilds full states from deltas and tracks the frames received, to be acknowledged.
+#[derive(Default)]
+pub struct DeltaDecoder {
+    received: HashMap<EntityId, VecDeque<(u16, PacketCharInfo)>>,
+    latest: Option<u16>,
+    bits: u32,
+    unacked: bool,
+}
+
+impl DeltaDecoder {
+    pub fn new() -> DeltaDecoder {
+        DeltaDecoder::default()
+    }
+
+    /// Full state carried by an `EntityDelta`, or `None` if its baseline is unknown.
+    pub fn decode(&mut self, seq: u16, baseline: Option<u16>, delta: &StateDelta) -> Option<PacketCharInfo> {
+        let history = self.received.entry(delta.idchar).or_default();
+
+        let state =
+            match baseline {
+                // after a wrap the same seq may be there twice, the newest is the one meant
+                Some(baseline) => delta.apply(&history.iter().rev().find(|(seq, _)| *seq == baseline)?.1),
+                None => delta.apply(&PacketCharInfo::default()),
+            };
+
+        history.push_back((seq, state.clone()));
+        if history.len() > DELTA_HISTORY {
+            history.pop_front();
+        }
+
+        self.received(seq);
+
+        Some(state)
+    }
+
+    /// Drops the states received of `idchar`, once the server said it is gone.
+    pub fn forget(&mut self, idchar: EntityId) {
+        self.received.remove(&idchar);
+    }
+
+    fn received(&mut self, seq: u16) {
+        self.unacked = true;
+
+        match self.latest {
+            Some(latest) if seq_newer(seq, latest) => {
+                let shift = seq.wrapping_sub(latest) as u32;
+                self.bits = if shift > ACK_BITS as u32 { 0 } else { ((self.bits << 1) | 1) << (shift - 1) };
+                self.latest = Some(seq);
+            },
+            Some(latest) => {
+                let age = latest.wrapping_sub(seq);
+                if age > 0 && age <= ACK_BITS {
+                    self.bits |= 1 << (age - 1);
+                }
+            },
+            None => self.latest = Some(seq),
+        }
+    }
+
+    /// The `Ack` to send, if frames were received since the last one.
+    pub fn ack(&mut self) -> Option<GameMessage> {
+        if !self.unacked {
+            return None;
+        }
+
+        self.unacked = false;
+        self.latest.map(|seq| GameMessage::Ack { seq, bits: self.bits })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn state(idchar: u16, x: i16) -> PacketCharInfo {
+        PacketCharInfo { idchar: EntityId::new(idchar, 0), x, y: 20, healt: 100, vision: 300, ..PacketCharInfo::default() }
+    }
+
+    fn delta(msg: &GameMessage) -> (u16, Option<u16>, &StateDelta) {
+        match msg {
+            GameMessage::EntityDelta { seq, baseline, delta } => (*seq, *baseline, delta),
+            msg => panic!("Unexpected message: {:?}", msg),
+        }
+    }
+
+    #[test]
+    fn delta_roundtrip() {
+        let base = state(3, 10);
+        let mut new = state(3, 12);
+        new.exit = true;
+        new.listlifeless[2] = Some(PacketLifelessInfo { x: 5, y: 6, w: 7, h: 8, d: 9, damage: 10 });
+
+        let delta = StateDelta::between(&base, &new);
+        let bytes = delta.to_bytes();
+
+        // idchar, mask, x, exit and one lifeless slot
+        assert_eq!(bytes.len(), 4 + 4 + 2 + 2 + 1 + LIFELESS_SIZE);
+        assert_eq!(StateDelta::from_bytes(&bytes).as_ref(), Ok(&delta));
+        assert_eq!(delta.apply(&base), new);
+
+        let cleared = StateDelta::between(&new, &base);
+        assert_eq!(StateDelta::from_bytes(&cleared.to_bytes()).unwrap().apply(&new), base);
+
+        assert!(StateDelta::between(&new, &new).is_empty());
+        assert!(StateDelta::from_bytes(&[3, 0, 0, 0, 0, 0x80]).is_err());
+        assert!(StateDelta::from_bytes(&[3, 0, 1, 0, 0, 0]).is_err());
+    }
+
+    #[test]
+    fn deltas_against_acked_baseline() {
+        let mut encoder = DeltaEncoder::new();
+        let mut decoder = DeltaDecoder::new();
+
+        // nothing acked, so the first state goes against the default one
+        let msg = encoder.encode(&state(3, 10));
+        encoder.sent(state(3, 10));
+        let (seq, baseline, d) = delta(&msg);
+        assert_eq!(baseline, None);
+        assert_eq!(decoder.decode(seq, baseline, d), Some(state(3, 10)));
+
+        // this frame is lost
+        encoder.next_frame();
+        encoder.encode(&state(3, 11));
+        encoder.sent(state(3, 11));
+
+        let ack = decoder.ack().unwrap();
+        assert_eq!(ack, GameMessage::Ack { seq: 0, bits: 0 });
+        assert_eq!(decoder.ack(), None);
+        encoder.ack(0, 0);
+
+        encoder.next_frame();
+        let msg = encoder.encode(&state(3, 12));
+        encoder.sent(state(3, 12));
+        let (seq, baseline, d) = delta(&msg);
+        assert_eq!(baseline, Some(0));
+        assert_eq!(d.to_bytes().len(), 4 + 4 + 2);
+        assert_eq!(decoder.decode(seq, baseline, d), Some(state(3, 12)));
+
+        // unknown baselines are refused
+        assert_eq!(decoder.decode(9, Some(1), d), None);
+
+        // despawned entities are forgotten on both sides
+        encoder.forget(EntityId::new(3, 0));
+        decoder.forget(EntityId::new(3, 0));
+        assert!(encoder.history.is_empty() && decoder.received.is_empty());
+        assert_eq!(decoder.decode(seq, baseline, d), None);
+    }
+
+    #[test]
+    fn ack_bits() {
+        let mut decoder = DeltaDecoder::new();
+        let d = StateDelta::between(&PacketCharInfo::default(), &state(1, 1));
+
+        for seq in [65534, 65535, 1, 0].iter() {
+            decoder.decode(*seq, None, &d);
+        }
+
+        // 65535 and 65534 are the 2nd and 3rd before 1, 0 is the 1st
+        assert_eq!(decoder.ack(), Some(GameMessage::Ack { seq: 1, bits: 0b111 }));
+
+        decoder.decode(40, None, &d);
+        assert_eq!(decoder.ack(), Some(GameMessage::Ack { seq: 40, bits: 0 }));
+
+        let mut encoder = DeltaEncoder::new();
+        for _ in 0..4 {
+            encoder.sent(state(1, 1));
+            encoder.next_frame();
+        }
+
+        // 0 and 2 received
+        encoder.ack(2, 0b10);
+        let acked: Vec<_> = encoder.history[&EntityId::new(1, 0)].iter().map(|sent| sent.acked).collect();
+        assert_eq!(acked, vec![true, false, true, false]);
+        assert_eq!(encoder.baseline(EntityId::new(1, 0)).map(|sent| sent.seq), Some(2));
+    }
+
+    #[test]
+    fn baselines_across_a_full_wrap() {
+        let mut encoder = DeltaEncoder::new();
+        encoder.sent(state(3, 10));
+
+        // not sent again for a whole wrap, then the new frame 0 is acked
+        for _ in 0..65536 {
+            encoder.next_frame();
+        }
+        assert_eq!(encoder.seq(), 0);
+        encoder.ack(0, 0);
+
+        encoder.next_frame();
+        let (_, baseline, _) = delta(&encoder.encode(&state(3, 11)));
+        assert_eq!(baseline, None);
+
+        // frame 5 of the last wrap and of this one both carried the entity
+        let mut decoder = DeltaDecoder::new();
+        let stale = state(3, 10);
+        let fresh = PacketCharInfo { healt: 50, ..state(3, 12) };
+        let moved = PacketCharInfo { y: 30, ..fresh.clone() };
+
+        decoder.decode(5, None, &StateDelta::between(&PacketCharInfo::default(), &stale));
+        decoder.decode(5, None, &StateDelta::between(&PacketCharInfo::default(), &fresh));
+        assert_eq!(decoder.decode(6, Some(5), &StateDelta::between(&fresh, &moved)), Some(moved));
+    }
+
+    #[test]
+    fn seq_wraps() {
+        assert!(seq_newer(1, 0));
+        assert!(seq_newer(0, 65535));
+        assert!(!seq_newer(65535, 0));
+        assert!(!seq_newer(7, 7));
+    }
+}
@@ -0,0 +1,214 @@
+use heredian_lib::PacketCharInfo;
+use heredian_lib::collision::{CollisionGrid, Sweep};
+
+use crate::spatial::SpatialGrid;
+
+/// Mass of a hero or enemy whose config doesn't give one.
+pub const DEFAULT_MASS: f32 = 1.0;
+
+/// A hero or enemy, solid to the others of its map.
+pub struct Body<'a> {
+    pub packet: &'a mut PacketCharInfo,
+    /// How hard it is to push: of two overlapping bodies, the lighter moves more.
+    pub mass: f32,
+}
+
+/// How enemies keep some room between each other, so a crowd spreads around its target.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Crowd {
+    /// Distance between centres under which two enemies push each other away.
+    pub spacing: f32,
+    /// Push per frame, in pixels, of two enemies on top of each other.
+    pub strength: f32,
+}
+
+/// Moves `packet` by `mov`, as far as the walls let it.
+pub fn sweep(packet: &mut PacketCharInfo, mov: (i32, i32), grid: &CollisionGrid) -> Sweep {
+    let moved = grid.sweep((packet.x as i32, packet.y as i32, packet.w as i32, packet.h as i32), mov);
+
+    packet.x = moved.x as i16;
+    packet.y = moved.y as i16;
+
+    moved
+}
+
+fn centre(packet: &PacketCharInfo) -> (f32, f32) {
+    (packet.x as f32 + packet.w as f32 / 2.0, packet.y as f32 + packet.h as f32 / 2.0)
+}
+
+/// Shortest move getting `b` out of `a`, if their hit boxes overlap: afterwards they only touch.
+fn penetration(a: &PacketCharInfo, b: &PacketCharInfo) -> Option<(i32, i32)> {
+    let x = (a.x + a.w).min(b.x + b.w) as i32 - a.x.max(b.x) as i32;
+    let y = (a.y + a.h).min(b.y + b.h) as i32 - a.y.max(b.y) as i32;
+
+    if x <= 0 || y <= 0 {
+        return None;
+    }
+
+    let (ac, bc) = (centre(a), centre(b));
+
+    if x <= y {
+        Some((if bc.0 >= ac.0 { x } else { -x }, 0))
+    } else {
+        Some((0, if bc.1 >= ac.1 { y } else { -y }))
+    }
+}
+
+fn hitbox(packet: &PacketCharInfo) -> (f32, f32, f32, f32) {
+    (packet.x as f32, packet.y as f32, packet.w as f32, packet.h as f32)
+}
+
+/// Pushes apart the overlapping bodies of each map, through the walls of `grids`, sharing
+/// every push by mass. Returns the indices of the bodies that moved.
+///
+/// `cells` is rebuilt with the bodies, to only look at the ones next to each other.
+/// Bodies pushed out of each other are left touching, which still counts as a reach for
+/// melee attacks.
+pub fn separate(bodies: &mut [Body], grids: &[CollisionGrid], cells: &mut SpatialGrid) -> Vec<usize> {
+    let mut pushed = vec![false; bodies.len()];
+
+    cells.rebuild(bodies.iter().map(|body| &*body.packet));
+
+    for j in 1..bodies.len() {
+        let (before, rest) = bodies.split_at_mut(j);
+        let b = &mut rest[0];
+
+        for i in cells.near(b.packet.idmap, hitbox(b.packet)).into_iter().take_while(|&i| i < j) {
+            let a = &mut before[i];
+
+            if a.packet.exit || b.packet.exit {
+                continue;
+            }
+
+            if let Some((px, py)) = penetration(a.packet, b.packet) {
+                let share = a.mass / (a.mass + b.mass);
+                let b_push = ((px as f32 * share).round() as i32, (py as f32 * share).round() as i32);
+                let a_push = (b_push.0 - px, b_push.1 - py);
+                let grid = &grids[a.packet.idmap as usize - 1];
+
+                if a_push != (0, 0) {
+                    sweep(a.packet, a_push, grid);
+                    pushed[i] = true;
+                }
+
+                if b_push != (0, 0) {
+                    sweep(b.packet, b_push, grid);
+                    pushed[j] = true;
+                }
+            }
+        }
+    }
+
+    pushed.iter().enumerate().filter(|(_, &p)| p).map(|(i, _)| i).collect()
+}
+
+impl Crowd {
+    /// Push on each of `enemies` away from the others of its map closer than `spacing`, the
+    /// harder the closer, to add to its own move. `cells` has to hold `enemies`.
+    pub fn spread(&self, enemies: &[PacketCharInfo], cells: &SpatialGrid) -> Vec<(f32, f32)> {
+        let mut push = vec![(0.0, 0.0); enemies.len()];
+
+        for i in 0..enemies.len() {
+            let ac = centre(&enemies[i]);
+
+            for j in cells.around(enemies[i].idmap, ac, self.spacing).into_iter().skip_while(|&j| j <= i) {
+                let (a, b) = (&enemies[i], &enemies[j]);
+
+                if a.exit || b.exit {
+                    continue;
+                }
+
+                let bc = centre(b);
+                let (dx, dy) = (bc.0 - ac.0, bc.1 - ac.1);
+                let dist = dx.hypot(dy);
+
+                if dist >= self.spacing {
+                    continue;
+                }
+
+                // right on top of each other, any way out will do
+                let (ux, uy) = if dist > 0.0 { (dx / dist, dy / dist) } else { (1.0, 0.0) };
+                let force = self.strength * (1.0 - dist / self.spacing);
+
+                push[i].0 -= ux * force;
+                push[i].1 -= uy * force;
+                push[j].0 += ux * force;
+                push[j].1 += uy * force;
+            }
+        }
+
+        push
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn body(x: i16, y: i16) -> PacketCharInfo {
+        PacketCharInfo { x, y, w: 10, h: 10, idmap: 1, ..PacketCharInfo::default() }
+    }
+
+    fn open_map() -> Vec<CollisionGrid> {
+        vec![CollisionGrid::from_cells(1, 1, (100.0, 100.0), vec![false])]
+    }
+
+    fn cells() -> SpatialGrid {
+        SpatialGrid::new(20.0, (100.0, 100.0), 2)
+    }
+
+    #[test]
+    fn overlaps_are_pushed_apart_by_mass() {
+        let grids = open_map();
+        let (mut hero, mut zombie, mut other) = (body(20, 20), body(26, 22), body(60, 60));
+
+        let pushed = separate(&mut [
+            Body { packet: &mut hero, mass: 20.0 },
+            Body { packet: &mut zombie, mass: 1.0 },
+            Body { packet: &mut other, mass: 1.0 },
+        ], &grids, &mut cells());
+
+        // the zombie, much lighter, takes all of the push, out the shorter way
+        assert_eq!(pushed, vec![1]);
+        assert_eq!((hero.x, hero.y), (20, 20));
+        assert_eq!((zombie.x, zombie.y), (30, 22));
+        assert_eq!(penetration(&hero, &zombie), None);
+
+        let (mut a, mut b) = (body(20, 20), body(20, 24));
+        separate(&mut [Body { packet: &mut a, mass: 1.0 }, Body { packet: &mut b, mass: 1.0 }], &grids, &mut cells());
+        assert_eq!((a.y, b.y), (17, 27));
+
+        // other maps, and the dead, aren't in the way
+        let (mut a, mut b) = (body(20, 20), PacketCharInfo { idmap: 2, ..body(20, 20) });
+        let (mut c, mut d) = (body(20, 20), PacketCharInfo { exit: true, ..body(20, 20) });
+        assert!(separate(&mut [Body { packet: &mut a, mass: 1.0 }, Body { packet: &mut b, mass: 1.0 }], &grids, &mut cells()).is_empty());
+        assert!(separate(&mut [Body { packet: &mut c, mass: 1.0 }, Body { packet: &mut d, mass: 1.0 }], &grids, &mut cells()).is_empty());
+    }
+
+    #[test]
+    fn pushes_stop_at_walls() {
+        // a wall on the right half
+        let grids = vec![CollisionGrid::from_cells(2, 1, (100.0, 100.0), vec![false, true])];
+        let (mut a, mut b) = (body(36, 20), body(39, 20));
+
+        separate(&mut [Body { packet: &mut a, mass: 1.0 }, Body { packet: &mut b, mass: 1.0 }], &grids, &mut cells());
+
+        // b, against the wall, can't take its half
+        assert_eq!((a.x, b.x), (33, 39));
+    }
+
+    #[test]
+    fn crowds_spread() {
+        let crowd = Crowd { spacing: 20.0, strength: 2.0 };
+        let enemies = [body(0, 0), body(10, 0), body(50, 50), PacketCharInfo { idmap: 2, ..body(0, 0) }];
+
+        let mut cells = cells();
+
+        cells.rebuild(enemies.iter());
+        assert_eq!(crowd.spread(&enemies, &cells), vec![(-1.0, 0.0), (1.0, 0.0), (0.0, 0.0), (0.0, 0.0)]);
+
+        let stacked = [body(5, 5), body(5, 5)];
+        cells.rebuild(stacked.iter());
+        assert_eq!(crowd.spread(&stacked, &cells), vec![(-2.0, 0.0), (2.0, 0.0)]);
+    }
+}
@@ -0,0 +1,201 @@
+use std::collections::{HashMap, VecDeque};
+use std::time::{Duration, Instant};
+
+use heredian_lib::PacketCharInfo;
+use heredian_lib::collision::CollisionGrid;
+use heredian_lib::entity::EntityId;
+
+use crate::bodies::sweep;
+
+#[derive(Debug, Clone, Copy)]
+struct Entry {
+    time: Instant,
+    x: i16,
+    y: i16,
+}
+
+/// Recent positions of every entity, to see the world as a client saw it.
+pub struct PositionHistory {
+    /// How far back positions are kept, which also caps how far back hits are rewound.
+    max_rewind: Duration,
+    entries: HashMap<EntityId, VecDeque<Entry>>,
+}
+
+impl PositionHistory {
+    pub fn new(max_rewind: Duration) -> PositionHistory {
+        PositionHistory {
+            max_rewind,
+            entries: HashMap::new(),
+        }
+    }
+
+    /// Records where `chars` are at `now`.
+    pub fn record<'a>(&mut self, now: Instant, chars: impl IntoIterator<Item = &'a PacketCharInfo>) {
+        let cutoff = now.checked_sub(self.max_rewind).unwrap_or(now);
+
+        for packet in chars {
+            let entries = self.entries.entry(packet.idchar).or_default();
+            entries.push_back(Entry { time: now, x: packet.x, y: packet.y });
+
+            // keep one entry older than the cutoff, to interpolate from
+            while entries.len() > 1 && entries[1].time <= cutoff {
+                entries.pop_front();
+            }
+        }
+    }
+
+    pub fn forget(&mut self, idchar: EntityId) {
+        self.entries.remove(&idchar);
+    }
+
+    /// Position of `idchar` at `time`, interpolated between the entries around it.
+    pub fn position_at(&self, idchar: EntityId, time: Instant) -> Option<(i16, i16)> {
+        let entries = self.entries.get(&idchar)?;
+        let after = entries.iter().position(|entry| entry.time > time);
+
+        let (prev, next) =
+            match after {
+                Some(0) => return entries.front().map(|entry| (entry.x, entry.y)),
+                Some(i) => (entries[i - 1], entries[i]),
+                None => return entries.back().map(|entry| (entry.x, entry.y)),
+            };
+
+        let t = (time - prev.time).as_secs_f32() / (next.time - prev.time).as_secs_f32();
+        let lerp = |a: i16, b: i16| (a as f32 + (b - a) as f32 * t).round() as i16;
+
+        Some((lerp(prev.x, next.x), lerp(prev.y, next.y)))
+    }
+
+    /// Runs `f` with `chars` moved back to where they were `lag` ago, at most `max_rewind`.
+    ///
+    /// Whatever `f` changes is kept, and moves it makes (like a knockback) are applied
+    /// to the current positions, as far as the walls of `grid` let them.
+    pub fn rewind<R>(&self, chars: &mut [&mut PacketCharInfo], grid: &CollisionGrid, now: Instant, lag: Duration, f: impl FnOnce(&mut [&mut PacketCharInfo]) -> R) -> R {
+        let lag = lag.min(self.max_rewind);
+        let time = now.checked_sub(lag).unwrap_or(now);
+
+        let mut moved = Vec::with_capacity(chars.len());
+
+        for packet in chars.iter_mut() {
+            let current = (packet.x, packet.y);
+
+            if let Some((x, y)) = self.position_at(packet.idchar, time) {
+                packet.x = x;
+                packet.y = y;
+            }
+
+            moved.push((current, (packet.x, packet.y)));
+        }
+
+        let res = f(chars);
+
+        for (packet, ((x, y), (past_x, past_y))) in chars.iter_mut().zip(moved) {
+            let mov = (packet.x as i32 - past_x as i32, packet.y as i32 - past_y as i32);
+
+            packet.x = x;
+            packet.y = y;
+
+            if mov != (0, 0) {
+                sweep(packet, mov, grid);
+            }
+        }
+
+        res
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn id(index: u16) -> EntityId {
+        EntityId::new(index, 0)
+    }
+
+    fn at(idchar: u16, x: i16, y: i16) -> PacketCharInfo {
+        PacketCharInfo { idchar: id(idchar), x, y, ..PacketCharInfo::default() }
+    }
+
+    fn ms(millis: u64) -> Duration {
+        Duration::from_millis(millis)
+    }
+
+    fn open_map() -> CollisionGrid {
+        CollisionGrid::from_cells(1, 1, (100.0, 100.0), vec![false])
+    }
+
+    #[test]
+    fn positions_in_the_past() {
+        let start = Instant::now();
+        let mut history = PositionHistory::new(ms(200));
+
+        assert_eq!(history.position_at(id(1), start), None);
+
+        history.record(start, &[at(1, 0, 0)]);
+        history.record(start + ms(100), &[at(1, 10, 20)]);
+        history.record(start + ms(200), &[at(1, 30, 20)]);
+
+        assert_eq!(history.position_at(id(1), start + ms(50)), Some((5, 10)));
+        assert_eq!(history.position_at(id(1), start + ms(150)), Some((20, 20)));
+        assert_eq!(history.position_at(id(1), start + ms(900)), Some((30, 20)));
+
+        // the entry at 0 is only kept to interpolate from, up to 100
+        history.record(start + ms(300), &[at(1, 40, 20)]);
+        assert_eq!(history.position_at(id(1), start), Some((10, 20)));
+
+        history.forget(id(1));
+        assert_eq!(history.position_at(id(1), start), None);
+    }
+
+    #[test]
+    fn rewind_keeps_changes() {
+        let start = Instant::now();
+        let mut history = PositionHistory::new(ms(100));
+
+        history.record(start, &[at(1, 0, 0), at(2, 50, 50)]);
+        history.record(start + ms(100), &[at(1, 10, 0), at(2, 50, 50)]);
+        history.record(start + ms(200), &[at(1, 20, 0), at(2, 50, 50)]);
+
+        let (mut one, mut two) = (at(1, 20, 0), at(2, 50, 50));
+        let mut chars = [&mut one, &mut two];
+
+        // asks for 150ms but only rewinds 100ms
+        let seen = history.rewind(&mut chars, &open_map(), start + ms(200), ms(150), |chars| {
+            let seen = chars[0].x;
+            chars[0].x += 3;
+            chars[0].healt = 7;
+            seen
+        });
+
+        assert_eq!(seen, 10);
+        assert_eq!(chars[0].x, 23);
+        assert_eq!(chars[0].healt, 7);
+        assert_eq!((chars[1].x, chars[1].y), (50, 50));
+    }
+
+    #[test]
+    fn rewind_knockback_stops_at_walls() {
+        // a wall on the right half
+        let grid = CollisionGrid::from_cells(2, 1, (100.0, 100.0), vec![false, true]);
+        let start = Instant::now();
+        let mut history = PositionHistory::new(ms(100));
+        let sized = |x| PacketCharInfo { w: 10, h: 10, ..at(1, x, 20) };
+
+        history.record(start, &[sized(10)]);
+        history.record(start + ms(100), &[sized(39)]);
+
+        // knocked right where it was, but it is against the wall now
+        let mut enemy = sized(39);
+        history.rewind(&mut [&mut enemy], &grid, start + ms(100), ms(100), |chars| {
+            assert_eq!(chars[0].x, 10);
+            chars[0].x += 5;
+        });
+        assert_eq!((enemy.x, enemy.y), (39, 20));
+
+        // and only as far as the wall lets it
+        history.record(start + ms(200), &[sized(36)]);
+        let mut enemy = sized(36);
+        history.rewind(&mut [&mut enemy], &grid, start + ms(200), ms(100), |chars| chars[0].x += 5);
+        assert_eq!(enemy.x, 39);
+    }
+}
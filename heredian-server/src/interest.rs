@@ -0,0 +1,259 @@
+use std::collections::{HashMap, HashSet};
+use std::net::SocketAddr;
+
+use heredian_lib::PacketCharInfo;
+use heredian_lib::entity::EntityId;
+use heredian_lib::net::Message;
+use heredian_lib::protocol::{DespawnReason, GameMessage};
+
+use crate::outbox::Outbox;
+
+/// How much farther than the radius an entity goes before it leaves, so one walking
+/// along the edge doesn't flicker in and out.
+const LEAVE_MARGIN: f32 = 32.0;
+
+/// Which entities each client is told about.
+///
+/// A client sees the entities on the map of its hero, within `radius` of the hero when
+/// there is one. Entities coming into view are sent whole, and a client is told when
+/// one goes out of view, and why.
+pub struct Interest {
+    radius: Option<f32>,
+    visible: HashMap<SocketAddr, HashSet<EntityId>>,
+    /// Map of every entity at the last update, to tell the ones that went through a gate.
+    maps: HashMap<EntityId, i16>,
+}
+
+fn center(packet: &PacketCharInfo) -> (f32, f32) {
+    (packet.x as f32 + packet.w as f32 / 2.0, packet.y as f32 + packet.h as f32 / 2.0)
+}
+
+impl Interest {
+    /// `radius` of 0 means the whole map.
+    pub fn new(radius: u16) -> Interest {
+        Interest {
+            radius: if radius > 0 { Some(radius as f32) } else { None },
+            visible: HashMap::new(),
+            maps: HashMap::new(),
+        }
+    }
+
+    fn relevant(&self, hero: &PacketCharInfo, entity: &PacketCharInfo, seen: bool) -> bool {
+        if entity.idchar == hero.idchar {
+            return true;
+        }
+
+        if entity.idmap != hero.idmap {
+            return false;
+        }
+
+        match self.radius {
+            Some(radius) => {
+                let (hx, hy) = center(hero);
+                let (ex, ey) = center(entity);
+                let reach = if seen { radius + LEAVE_MARGIN } else { radius };
+
+                (hx - ex).hypot(hy - ey) <= reach
+            },
+            None => true,
+        }
+    }
+
+    /// Works out what each of the `viewers` sees of `entities` now, queueing the
+    /// entities that came into view and the ones that left it.
+    pub fn update<'a>(&mut self, viewers: impl IntoIterator<Item = (SocketAddr, &'a PacketCharInfo)>, entities: &[&PacketCharInfo], outbox: &mut Outbox) {
+        let mut visible = HashMap::new();
+        let maps: HashMap<EntityId, i16> = entities.iter().map(|entity| (entity.idchar, entity.idmap)).collect();
+
+        for (addr, hero) in viewers {
+            let before = self.visible.remove(&addr).unwrap_or_default();
+            let mut now = HashSet::new();
+
+            for entity in entities {
+                if self.relevant(hero, entity, before.contains(&entity.idchar)) {
+                    now.insert(entity.idchar);
+
+                    if !before.contains(&entity.idchar) {
+                        outbox.send(Message::Direct(GameMessage::EntityState((*entity).clone()), addr));
+                    }
+                }
+            }
+
+            for idchar in before.difference(&now) {
+                let reason =
+                    match (self.maps.get(idchar), maps.get(idchar)) {
+                        (Some(before), Some(now)) if before != now => DespawnReason::ChangedMap,
+                        _ => DespawnReason::OutOfView,
+                    };
+
+                outbox.send(Message::Direct(GameMessage::EntityDespawn { idchar: *idchar, reason }, addr));
+            }
+
+            visible.insert(addr, now);
+        }
+
+        // clients no longer viewing are forgotten
+        self.visible = visible;
+        self.maps = maps;
+    }
+
+    /// Tells every client that sees `idchar` that it is gone for `reason`.
+    pub fn despawn(&mut self, idchar: EntityId, reason: DespawnReason, outbox: &mut Outbox) {
+        for (addr, visible) in self.visible.iter_mut() {
+            if visible.remove(&idchar) {
+                outbox.send(Message::Direct(GameMessage::EntityDespawn { idchar, reason }, *addr));
+            }
+        }
+
+        self.maps.remove(&idchar);
+    }
+
+    /// Forgets the client at `addr`, which is told nothing more.
+    pub fn forget(&mut self, addr: SocketAddr) {
+        self.visible.remove(&addr);
+    }
+
+    /// States of the `entities` seen by the client at `addr` as it joins with `hero`, who
+    /// are then known to it, so the next `update` sends it changes only.
+    pub fn snapshot(&mut self, addr: SocketAddr, hero: &PacketCharInfo, entities: &[&PacketCharInfo]) -> Vec<PacketCharInfo> {
+        let seen: Vec<PacketCharInfo> = entities.iter().filter(|entity| self.relevant(hero, entity, false)).map(|entity| (*entity).clone()).collect();
+
+        self.visible.insert(addr, seen.iter().map(|entity| entity.idchar).collect());
+        seen
+    }
+
+    /// Whether `msg`, broadcast, concerns the client at `addr`.
+    pub fn sees(&self, addr: SocketAddr, msg: &GameMessage) -> bool {
+        let idchar =
+            match msg {
+                GameMessage::EntityState(packet) => packet.idchar,
+                GameMessage::ProjectileSpawn { idchar, .. } => *idchar,
+                _ => return true,
+            };
+
+        self.visible.get(&addr).is_some_and(|visible| visible.contains(&idchar))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn id(index: u16) -> EntityId {
+        EntityId::new(index, 0)
+    }
+
+    fn at(idchar: u16, idmap: i16, x: i16) -> PacketCharInfo {
+        PacketCharInfo { idchar: id(idchar), idmap, x, w: 10, h: 10, ..PacketCharInfo::default() }
+    }
+
+    fn events(outbox: &mut Outbox, addr: SocketAddr) -> Vec<GameMessage> {
+        outbox.take(&[addr], &Interest::new(0)).into_iter().flat_map(|(_, msgs)| msgs).collect()
+    }
+
+    #[test]
+    fn same_map_only() {
+        let a: SocketAddr = "127.0.0.1:1".parse().unwrap();
+        let mut interest = Interest::new(0);
+        let mut outbox = Outbox::new();
+
+        let mut hero = at(1, 1, 0);
+        let (near, other) = (at(2, 1, 700), at(3, 2, 0));
+
+        interest.update(vec![(a, &hero)], &[&hero, &near, &other], &mut outbox);
+        assert_eq!(events(&mut outbox, a), vec![GameMessage::EntityState(hero.clone()), GameMessage::EntityState(near.clone())]);
+
+        assert!(interest.sees(a, &GameMessage::EntityState(near.clone())));
+        assert!(!interest.sees(a, &GameMessage::EntityState(other.clone())));
+        assert!(!interest.sees(a, &GameMessage::ProjectileSpawn { idchar: id(3), lifelessid: 1, x: 0, y: 0, d: 1 }));
+        assert!(interest.sees(a, &GameMessage::Chat { idchar: id(3), text: "oi".to_owned() }));
+
+        // nothing changed, nothing to tell
+        interest.update(vec![(a, &hero)], &[&hero, &near, &other], &mut outbox);
+        assert_eq!(events(&mut outbox, a), vec![]);
+
+        // through a gate
+        hero.idmap = 2;
+        interest.update(vec![(a, &hero)], &[&hero, &near, &other], &mut outbox);
+        assert_eq!(events(&mut outbox, a), vec![GameMessage::EntityState(other.clone()), GameMessage::EntityDespawn { idchar: id(2), reason: DespawnReason::OutOfView }]);
+
+        // a viewer gone is forgotten
+        interest.update(vec![], &[&hero, &near, &other], &mut outbox);
+        assert!(!interest.sees(a, &GameMessage::EntityState(hero)));
+    }
+
+    #[test]
+    fn snapshot_on_join() {
+        let a: SocketAddr = "127.0.0.1:1".parse().unwrap();
+        let mut interest = Interest::new(0);
+        let mut outbox = Outbox::new();
+
+        let hero = at(1, 1, 0);
+        let (near, other) = (at(2, 1, 700), at(3, 2, 0));
+
+        let seen = interest.snapshot(a, &hero, &[&near, &other, &hero]);
+        assert_eq!(seen, vec![near.clone(), hero.clone()]);
+
+        // already known, only what changes afterwards is sent
+        interest.update(vec![(a, &hero)], &[&hero, &near, &other], &mut outbox);
+        assert_eq!(events(&mut outbox, a), vec![]);
+        assert!(interest.sees(a, &GameMessage::EntityState(near)));
+    }
+
+    #[test]
+    fn within_radius() {
+        let a: SocketAddr = "127.0.0.1:1".parse().unwrap();
+        let mut interest = Interest::new(100);
+        let mut outbox = Outbox::new();
+
+        let hero = at(1, 1, 0);
+        let mut enemy = at(2, 1, 120);
+
+        interest.update(vec![(a, &hero)], &[&hero, &enemy], &mut outbox);
+        assert_eq!(events(&mut outbox, a), vec![GameMessage::EntityState(hero.clone())]);
+
+        enemy.x = 90;
+        interest.update(vec![(a, &hero)], &[&hero, &enemy], &mut outbox);
+        assert_eq!(events(&mut outbox, a), vec![GameMessage::EntityState(enemy.clone())]);
+
+        // it only leaves some way past the radius
+        enemy.x = 120;
+        interest.update(vec![(a, &hero)], &[&hero, &enemy], &mut outbox);
+        assert_eq!(events(&mut outbox, a), vec![]);
+
+        enemy.x = 140;
+        interest.update(vec![(a, &hero)], &[&hero, &enemy], &mut outbox);
+        assert_eq!(events(&mut outbox, a), vec![GameMessage::EntityDespawn { idchar: id(2), reason: DespawnReason::OutOfView }]);
+    }
+
+    #[test]
+    fn despawn_reasons() {
+        let (a, b): (SocketAddr, SocketAddr) = ("127.0.0.1:1".parse().unwrap(), "127.0.0.1:2".parse().unwrap());
+        let mut interest = Interest::new(0);
+        let mut outbox = Outbox::new();
+
+        let mut hero = at(1, 1, 0);
+        let other = at(2, 1, 100);
+        let enemy = at(3, 1, 200);
+
+        interest.update(vec![(a, &hero), (b, &other)], &[&hero, &other, &enemy], &mut outbox);
+        outbox.take(&[a, b], &interest);
+
+        // b sees the hero of a go through a gate
+        hero.idmap = 2;
+        interest.update(vec![(a, &hero), (b, &other)], &[&hero, &other, &enemy], &mut outbox);
+        let outgoing = outbox.take(&[a, b], &interest);
+        assert_eq!(outgoing[1], (b, vec![GameMessage::EntityDespawn { idchar: id(1), reason: DespawnReason::ChangedMap }]));
+
+        interest.despawn(id(3), DespawnReason::Killed, &mut outbox);
+        assert_eq!(events(&mut outbox, b), vec![GameMessage::EntityDespawn { idchar: id(3), reason: DespawnReason::Killed }]);
+
+        // the player who left hears nothing
+        interest.forget(b);
+        interest.despawn(id(2), DespawnReason::Disconnected, &mut outbox);
+        assert_eq!(events(&mut outbox, b), vec![]);
+
+        interest.update(vec![(a, &hero)], &[&hero], &mut outbox);
+        assert_eq!(events(&mut outbox, a), vec![]);
+    }
+}
@@ -1,26 +1,69 @@
-use std::net::{SocketAddr};
+use std::collections::HashMap;
+use std::net::{SocketAddr, SocketAddrV4, Ipv4Addr};
 use std::path::{Path};
-use std::time::{Duration};
+use std::time::{Duration, Instant};
 use std::thread;
 
 use heredian_lib::*;
+use heredian_lib::collision::CollisionGrid;
+use heredian_lib::entity::EntityIds;
 use heredian_lib::file_manager::*;
-use heredian_lib::allegro_safe::{
-    al_load_bitmap, al_init, al_init_image_addon, 
-    al_map_rgb, al_get_pixel, AlBitmap,
-    al_get_bitmap_width, al_get_bitmap_height
-};
 use heredian_lib::net::*;
+use heredian_lib::protocol::*;
+
+mod bodies;
+mod outbox;
+mod history;
+mod interest;
+mod rules;
+mod spatial;
+mod stage;
+use bodies::{separate, sweep, Body, Crowd, DEFAULT_MASS};
+use outbox::Outbox;
+use history::PositionHistory;
+use interest::Interest;
+use rules::{Guard, Projectile, Rules, Violation};
+use spatial::{pick_mut, SpatialGrid};
+use stage::Stage;
+
+/// Side of the cells heroes and enemies are bucketed in, about the size of the biggest.
+const CELL_SIZE: f32 = 64.0;
+
+/// How far an enemy may be from where `enemy_cells` last saw it when a hit is judged: the
+/// moves it made since, and the ones undone to judge as the attacker saw it.
+const HIT_REACH: f32 = 32.0;
 
 struct Ambients {
-    width: i16,
-    height: i16,
     boss_num: i16,
-    models: Vec<*const AlBitmap>,
+    /// Walls of each map, `idmap - 1`.
+    grids: Vec<CollisionGrid>,
+    /// Entrance and gates of each map, `idmap - 1`.
+    stages: Vec<Stage>,
     enemies: Vec<PacketCharInfo>,
+    /// Mass of each kind of enemy, by `numchar`.
+    enemy_masses: HashMap<i16, f32>,
+    /// How chasing enemies keep apart.
+    crowd: Crowd,
+    /// Where the enemies are, rebuilt every tick.
+    enemy_cells: SpatialGrid,
+    /// Where the heroes are, rebuilt every tick.
+    hero_cells: SpatialGrid,
+    /// Heroes and enemies together, to push them apart.
+    body_cells: SpatialGrid,
     clients: Vec<PacketCharInfo>,
     clients_addrs: Vec<SocketAddr>,
-    last_id: i16,
+    /// Ids of the heroes and enemies alike.
+    ids: EntityIds,
+    /// Interpolation delay of each client, part of how late it sees the others.
+    interp_delays: HashMap<SocketAddr, Duration>,
+    history: PositionHistory,
+    /// What each client is told about.
+    interest: Interest,
+    rules: Rules,
+    /// Checks the inputs of each client.
+    guards: HashMap<SocketAddr, Guard>,
+    /// Projectiles fired by the hero of each client.
+    projectiles: HashMap<SocketAddr, Vec<Projectile>>,
 }
 
 impl Ambients {
@@ -28,40 +71,65 @@ impl Ambients {
         let path = Path::new("assets/Configs/Config.txt");
         let config_file = ConfigFile::load(path);
 
-        let width = config_file.get("width").expect("width not found.");
-        let height = config_file.get("height").expect("height not found.");
+        let width: f32 = config_file.get("width").expect("width not found.");
+        let height: f32 = config_file.get("height").expect("height not found.");
         let boss_num = config_file.get("boss_num").expect("boss_num not found.");
+        let max_rewind = config_file.get("lag_compensation_max").unwrap_or(200);
+        let interest_radius = config_file.get("interest_radius").unwrap_or(0);
+        let crowd = Crowd {
+            spacing: config_file.get("crowd_spacing").unwrap_or(0.0),
+            strength: config_file.get("crowd_push").unwrap_or(0.0),
+        };
 
         let path = Path::new("assets/Configs/Ambients.txt");
         let ambient_config_file = ConfigFile::load(path);
 
         let qt_maps = ambient_config_file.get("qt_maps").expect("qt_maps not found.");
-        let mut models = Vec::with_capacity(qt_maps);
-        
-        models.push(std::ptr::null());
+        let mut grids = Vec::with_capacity(qt_maps);
+        let mut stages = Vec::with_capacity(qt_maps);
 
         for i in 1..=qt_maps {
             let key = format!("map{}", i);
             let model_path = ambient_config_file.get_string(&key).expect(&(key + " not found."));
-            models.push(al_load_bitmap(model_path));
+            grids.push(CollisionGrid::load(model_path, (width, height)).unwrap_or_else(|_| panic!("Can't load {}.", model_path)));
+
+            let key = format!("stage{}", i);
+            let stage_path = ambient_config_file.get_string(&key).expect(&(key + " not found."));
+            stages.push(Stage::load(stage_path));
         }
 
-        let enemies = Self::load_enemies();
-        let last_id = enemies.len() as i16;
+        let mut ids = EntityIds::new();
+        let enemies = Self::load_enemies(&mut ids);
+
+        let enemies_config_file = ConfigFile::load(Path::new("assets/Configs/EnemiesConf.txt"));
+        let enemy_masses = enemies
+                            .iter()
+                            .map(|e| (e.numchar, enemies_config_file.get(&format!("{}mass", e.numchar)).unwrap_or(DEFAULT_MASS)))
+                            .collect();
 
         Ambients {
-            width: width,
-            height: height,
-            boss_num: boss_num,
-            models: models,
-            enemies: enemies,
+            boss_num,
+            grids,
+            stages,
+            enemies,
+            enemy_masses,
+            crowd,
+            enemy_cells: SpatialGrid::new(CELL_SIZE, (width, height), qt_maps),
+            hero_cells: SpatialGrid::new(CELL_SIZE, (width, height), qt_maps),
+            body_cells: SpatialGrid::new(CELL_SIZE, (width, height), qt_maps),
             clients: Vec::with_capacity(4),
             clients_addrs: Vec::with_capacity(4),
-            last_id: last_id,
+            ids,
+            interp_delays: HashMap::new(),
+            history: PositionHistory::new(Duration::from_millis(max_rewind)),
+            interest: Interest::new(interest_radius),
+            rules: Rules::load(),
+            guards: HashMap::new(),
+            projectiles: HashMap::new(),
         }
     }
 
-    fn load_enemies() -> Vec<PacketCharInfo> {
+    fn load_enemies(ids: &mut EntityIds) -> Vec<PacketCharInfo> {
         let path = Path::new("assets/Configs/Enemies.txt");
         let config_file = ConfigFile::load(path);
 
@@ -82,7 +150,7 @@ impl Ambients {
                 vision: enemies_config_file.get(&format!("{}vision", numchar)).unwrap_or(0),
                 step: enemies_config_file.get(&format!("{}step", numchar)).unwrap_or(0),
                 damage: enemies_config_file.get(&format!("{}damage", numchar)).unwrap_or(0),
-                idchar: i as i16,
+                idchar: ids.alloc().expect("Too many enemies."),
                 exit: false,
                 healt: config_file.get(&format!("{}_helt", i)).expect("helt not found"),
                 stamina: config_file.get(&format!("{}_stamina", i)).expect("stamina not found"),
@@ -96,18 +164,12 @@ impl Ambients {
         enemies
     }
 
-    fn send_direct_enemies(&mut self, server: &Server<PacketCharInfo>, addr: SocketAddr) {
-        let len_enemies = self.enemies.len();
-
-        for enemy in self.enemies.iter_mut() {
-            enemy.totchar = self.clients.len() as i16;
-            enemy.totenemies = len_enemies as i16;
-            server.send(Message::Direct(enemy.clone(), addr));
-        }
+    fn stage(&self, idmap: i16) -> &Stage {
+        &self.stages[idmap as usize - 1]
     }
 }
 
-fn hit(packet: &mut PacketCharInfo, tx: i16, ty: i16, td: i16, damage: i16, ambient_data: (i16, i16, *const AlBitmap)) -> bool {
+fn hit(packet: &mut PacketCharInfo, tx: i16, ty: i16, td: i16, damage: i16, grid: &CollisionGrid) -> bool {
     const DISPLACEMENT: i16 = 3;
 
     let (x1, y1, x2, y2) = (packet.x, packet.y, packet.x + packet.w, packet.y + packet.h);
@@ -124,20 +186,11 @@ fn hit(packet: &mut PacketCharInfo, tx: i16, ty: i16, td: i16, damage: i16, ambi
                     DIRECTION_DOWN => (0, DISPLACEMENT),
                     DIRECTION_LEFT => (-DISPLACEMENT, 0),
                     DIRECTION_RIGHT => (DISPLACEMENT, 0),
-                    _ => unreachable!()
+                    // the direction comes from the network
+                    _ => (0, 0)
                 };
 
-        if mov.0 != 0 {
-            packet.x += mov.0;
-            if collided(packet, ambient_data) {
-                packet.x -= mov.0;
-            }
-        } else {
-            packet.y += mov.1;
-            if collided(packet, ambient_data) {
-                packet.y -= mov.1;
-            }
-        }
+        sweep(packet, (mov.0 as i32, mov.1 as i32), grid);
 
         true
     } else {
@@ -145,40 +198,136 @@ fn hit(packet: &mut PacketCharInfo, tx: i16, ty: i16, td: i16, damage: i16, ambi
     }
 }
 
-fn disconnect_client(ambients: &mut Ambients, addr: SocketAddr, _server: &Server<PacketCharInfo>) {
+fn disconnect_client(ambients: &mut Ambients, addr: SocketAddr, outbox: &mut Outbox) {
     let idx = ambients.clients_addrs.iter().position(|a| *a == addr);
 
+    ambients.interest.forget(addr);
+
     if let Some(idx) = idx {
         ambients.clients_addrs.remove(idx);
-        ambients.clients.remove(idx);
+        let client = ambients.clients.remove(idx);
+        ambients.ids.free(client.idchar);
+        ambients.history.forget(client.idchar);
+        ambients.interest.despawn(client.idchar, DespawnReason::Disconnected, outbox);
     }
+
+    ambients.interp_delays.remove(&addr);
+    ambients.guards.remove(&addr);
+    ambients.projectiles.remove(&addr);
+}
+
+fn log_violation(addr: SocketAddr, violation: &Violation) {
+    println!("Client {}: {}, dropped.", addr, violation);
 }
 
-fn connect_client(ambients: &mut Ambients, addr: SocketAddr, server: &Server<PacketCharInfo>) {
-    ambients.last_id += 1;
+fn connect_client(ambients: &mut Ambients, addr: SocketAddr, join: (i16, i16, u16), outbox: &mut Outbox) {
+    let (numchar, idmap, interp_delay) = join;
+
+    if ambients.clients_addrs.contains(&addr) {
+        return;
+    }
+
+    let guard =
+        Guard::new(&ambients.rules, numchar, Instant::now())
+            .and_then(|guard| if idmap > 0 && (idmap as usize) <= ambients.grids.len() { Ok(guard) } else { Err(Violation::UnknownMap(idmap)) });
+
+    let guard =
+        match guard {
+            Ok(guard) => guard,
+            Err(violation) => {
+                log_violation(addr, &violation);
+                outbox.send(Message::Direct(GameMessage::Disconnect { reason: format!("Invalid join: {}.", violation) }, addr));
+                return;
+            }
+        };
+
+    let idchar =
+        match ambients.ids.alloc() {
+            Some(idchar) => idchar,
+            None => {
+                println!("Client {}: no ids left, dropped.", addr);
+                outbox.send(Message::Direct(GameMessage::Disconnect { reason: "Server full.".to_owned() }, addr));
+                return;
+            }
+        };
+
+    // the hero starts standing at the entrance of the map, as its definition says
+    let char_def = ambients.rules.char_def(numchar).unwrap();
+    let (healt, stamina) = (char_def.healt, char_def.stamina);
+    let (w, h) = (char_def.actions[ACTION_IDLE as usize].w, char_def.actions[ACTION_IDLE as usize].h);
+    let (x, y) = (ambients.stage(idmap).ex, ambients.stage(idmap).ey);
+
+    ambients.guards.insert(addr, guard);
+    ambients.interp_delays.insert(addr, Duration::from_millis(interp_delay as u64));
+    ambients.projectiles.insert(addr, Vec::new());
 
-    let mut packet = PacketCharInfo {
-        idchar: ambients.last_id,
+    let packet = PacketCharInfo {
+        idchar,
         totchar: (ambients.clients.len() + 1) as i16,
+        numchar,
+        idmap,
+        x,
+        y,
+        w,
+        h,
+        a: ACTION_IDLE as i16,
+        // facing somewhere before any direction is held
+        d: DIRECTION_DOWN as i16,
+        healt,
+        stamina,
         ..PacketCharInfo::default()
     };
 
-    server.send(Message::Direct(packet.clone(), addr));
+    outbox.send(Message::Direct(GameMessage::Welcome { idchar: packet.idchar, totchar: packet.totchar }, addr));
     ambients.clients_addrs.push(addr);
 
-    packet.x = -1;
-    packet.y = -1;
-
     ambients.clients.push(packet);
-    ambients.send_direct_enemies(server, addr);
+
+    // everything on the map comes before any update
+    let entities: Vec<_> = ambients.enemies.iter().chain(ambients.clients.iter()).collect();
+    let entities = ambients.interest.snapshot(addr, ambients.clients.last().unwrap(), &entities);
+
+    let projectiles = ambients.clients_addrs.iter()
+        .zip(ambients.clients.iter())
+        .filter(|(_, hero)| entities.iter().any(|entity| entity.idchar == hero.idchar))
+        .flat_map(|(owner, hero)| ambients.projectiles[owner].iter().map(move |p| ProjectileState {
+            idchar: hero.idchar,
+            lifelessid: p.lifelessid,
+            x: p.x,
+            y: p.y,
+            d: p.d,
+        }))
+        .collect();
+
+    for snapshot in GameMessage::snapshots(entities, projectiles) {
+        outbox.send(Message::Direct(snapshot, addr));
+    }
 }
 
-fn on_message(ambients: &mut Ambients, packet: PacketCharInfo, _addr: SocketAddr, server: &Server<PacketCharInfo>) {
+fn on_message(ambients: &mut Ambients, seq: u16, command: InputCommand, addr: SocketAddr, lag: Duration, outbox: &mut Outbox) {
     let len_clients = ambients.clients.len();
-    let ambient_data = (ambients.width, ambients.height, ambients.models[packet.idmap as usize]);
 
-    let pos_char = ambients.clients.iter().position(|c| c.idchar == packet.idchar).expect("idchar not found - not connected.");
-    
+    let pos_char =
+        match ambients.clients_addrs.iter().position(|a| *a == addr) {
+            Some(pos_char) => pos_char,
+            None => return,
+        };
+
+    let projectiles = ambients.projectiles.get_mut(&addr).unwrap();
+    let checked = ambients.guards.get_mut(&addr).unwrap().check(&ambients.rules, &ambients.clients[pos_char], projectiles.len(), &command, Instant::now());
+    let checked =
+        match checked {
+            Ok(checked) => checked,
+            Err(violation) => {
+                log_violation(addr, &violation);
+                return;
+            }
+        };
+
+    for violation in checked.violations.iter() {
+        log_violation(addr, violation);
+    }
+
     // put this char at the front of the list
     ambients.clients.swap(0, pos_char);
     ambients.clients_addrs.swap(0, pos_char);
@@ -189,85 +338,120 @@ fn on_message(ambients: &mut Ambients, packet: PacketCharInfo, _addr: SocketAddr
     this_char.totchar = len_clients as i16;
     this_char.totenemies = ambients.enemies.len() as i16;
 
+    // sizes, damage and speed come from the action's definition
+    this_char.a = checked.a;
+    this_char.d2 = command.d2 as i16;
+    this_char.d = command.facing().map_or(this_char.d, |d| d as i16);
+    this_char.w = checked.action.w;
+    this_char.h = checked.action.h;
+    this_char.damage = checked.action.damage;
+    this_char.step = checked.action.step;
+
+    let grid = &ambients.grids[this_char.idmap as usize - 1];
+
+    // every input is one frame of the client, which predicts the same step
+    if checked.moves {
+        move_char(this_char, grid);
+    }
 
-    if this_char.healt == 0 {
-        this_char.healt = packet.healt;
-        this_char.numchar = packet.numchar;
+    let gate = ambients.stages[this_char.idmap as usize - 1].crossed_gate((this_char.x, this_char.y), (checked.action.wd, checked.action.hd));
+    if let Some(gate) = gate {
+        this_char.x = gate.ex;
+        this_char.y = gate.ey;
+        this_char.idmap = gate.idmap;
+        projectiles.clear();
     }
 
-    if this_char.x == -1 && this_char.y == -1 || this_char.idmap != packet.idmap {
-        this_char.x = packet.x;
-        this_char.y = packet.y;
+    let grid = &ambients.grids[this_char.idmap as usize - 1];
+
+    outbox.send(Message::Direct(GameMessage::InputAck { seq }, addr));
+
+    // judge the hit against where the enemies were on the attacker's screen
+    if checked.attacks {
+        if let Some((tx, ty)) = hit_point(this_char) {
+            let mut targets = pick_mut(&mut ambients.enemies, &ambients.enemy_cells.around(this_char.idmap, (tx as f32, ty as f32), HIT_REACH));
+            ambients.history.rewind(&mut targets, grid, Instant::now(), lag, |enemies| {
+                damage_char(this_char, enemies, grid, outbox)
+            });
+        }
     }
 
-    this_char.idmap = packet.idmap;
-    this_char.w = packet.w;
-    this_char.h = packet.h;
-    this_char.d = packet.d;
-    this_char.d2 = packet.d2;
-    this_char.a = packet.a;
-    this_char.dhit = packet.dhit;
-    this_char.damage = packet.damage;
-    this_char.step = packet.step;
+    if let Some(fired) = checked.fired {
+        let spawn = GameMessage::ProjectileSpawn { idchar: this_char.idchar, lifelessid: fired.lifelessid, x: fired.x, y: fired.y, d: fired.d };
+        outbox.send(Message::BroadcastExcept(spawn, addr));
+        projectiles.push(fired);
+    }
 
-    //damage_char(this_char, others_chars, ambient_data);
-    damage_char(this_char, ambients.enemies.as_mut_slice(), ambient_data, server);
+    // projectiles fly one step per frame, as on the client, and hit what they reach
+    projectiles.retain_mut(|projectile| move_projectile(projectile, grid));
 
     let mut lifeless_char = PacketCharInfo::default();
 
-    for lifeless in packet.listlifeless.iter() {
-        if let Some(lifeless) = lifeless {
-            lifeless_char.x = lifeless.x;
-            lifeless_char.y = lifeless.y;
-            lifeless_char.w = lifeless.w;
-            lifeless_char.h = lifeless.h;
-            lifeless_char.d = lifeless.d;
-            lifeless_char.damage = lifeless.damage;
-            lifeless_char.idmap = packet.idmap;
-
-            //damage_char(&lifeless_char, others_chars, ambient_data, server);
-            damage_char(&lifeless_char, ambients.enemies.as_mut_slice(), ambient_data, server);
+    for projectile in projectiles.iter() {
+        lifeless_char.x = projectile.x;
+        lifeless_char.y = projectile.y;
+        lifeless_char.w = projectile.w;
+        lifeless_char.h = projectile.h;
+        lifeless_char.d = projectile.d;
+        lifeless_char.damage = projectile.damage;
+        lifeless_char.idmap = this_char.idmap;
+
+        if let Some((tx, ty)) = hit_point(&lifeless_char) {
+            let mut targets = pick_mut(&mut ambients.enemies, &ambients.enemy_cells.around(this_char.idmap, (tx as f32, ty as f32), HIT_REACH));
+            damage_char(&lifeless_char, &mut targets, grid, outbox);
         }
     }
 
+    this_char.listlifeless = Default::default();
+    for (lifeless, projectile) in this_char.listlifeless.iter_mut().zip(projectiles.iter()) {
+        *lifeless = Some(PacketLifelessInfo { x: projectile.x, y: projectile.y, w: projectile.w, h: projectile.h, d: projectile.d, damage: projectile.damage });
+    }
+    this_char.totlifeless = projectiles.len() as i16;
+
     this_char.healt = this_char.healt.max(0);
 
-    server.send(Message::Broadcast(this_char.clone()));
+    outbox.send(Message::Broadcast(GameMessage::EntityState(this_char.clone())));
 }
 
-fn dir_damage_chance(this_char: &PacketCharInfo, other_char: &mut PacketCharInfo, odds: f32, ambient_data: (i16, i16, *const AlBitmap)) -> bool {
-    let res = 
-        if rand::random::<f32>() <= odds {
-            dir_damage(this_char, other_char, ambient_data)
-        } else {
-            false
-        };
-
-    res
+fn dir_damage_chance(this_char: &PacketCharInfo, other_char: &mut PacketCharInfo, odds: f32, grid: &CollisionGrid) -> bool {
+    if rand::random::<f32>() <= odds {
+        dir_damage(this_char, other_char, grid)
+    } else {
+        false
+    }
 }
 
-fn dir_damage(this_char: &PacketCharInfo, other_char: &mut PacketCharInfo, ambient_data: (i16, i16, *const AlBitmap)) -> bool {
+/// Where the attacks of `this_char` land: the middle of the side it faces.
+fn hit_point(this_char: &PacketCharInfo) -> Option<(i16, i16)> {
     let (x1, y1, x2, y2) = (this_char.x, this_char.y, this_char.x + this_char.w, this_char.y + this_char.h);
     let (xm, ym) = ((x1+x2)/2, (y1+y2)/2);
 
     match this_char.d as i32 {
-        DIRECTION_UP => hit(other_char, xm, y1, this_char.d, this_char.damage, ambient_data),
-        DIRECTION_DOWN => hit(other_char, xm, y2, this_char.d, this_char.damage, ambient_data),
-        DIRECTION_LEFT => hit(other_char, x1, ym, this_char.d, this_char.damage, ambient_data),
-        DIRECTION_RIGHT => hit(other_char, x2, ym, this_char.d, this_char.damage, ambient_data),
-        _ => unreachable!()
+        DIRECTION_UP => Some((xm, y1)),
+        DIRECTION_DOWN => Some((xm, y2)),
+        DIRECTION_LEFT => Some((x1, ym)),
+        DIRECTION_RIGHT => Some((x2, ym)),
+        // the direction comes from the network
+        _ => None
     }
 }
 
-fn damage_char(this_char: &PacketCharInfo, others_chars: &mut [PacketCharInfo], ambient_data: (i16, i16, *const AlBitmap), server: &Server<PacketCharInfo>) -> bool {
+fn dir_damage(this_char: &PacketCharInfo, other_char: &mut PacketCharInfo, grid: &CollisionGrid) -> bool {
+    match hit_point(this_char) {
+        Some((tx, ty)) => hit(other_char, tx, ty, this_char.d, this_char.damage, grid),
+        None => false,
+    }
+}
+
+fn damage_char(this_char: &PacketCharInfo, others_chars: &mut [&mut PacketCharInfo], grid: &CollisionGrid, outbox: &mut Outbox) -> bool {
     let mut hit = false;
 
     if this_char.damage > 0 {
-        for other in others_chars {
+        for other in others_chars.iter_mut() {
             if this_char.idmap == other.idmap {
-                if dir_damage_chance(this_char, other, 1.0, ambient_data) {
+                if dir_damage_chance(this_char, other, 1.0, grid) {
                     hit = true;
-                    server.send(Message::Broadcast(other.clone()));
+                    outbox.send(Message::Broadcast(GameMessage::EntityState(other.clone())));
                     break;
                 }
             }
@@ -277,12 +461,28 @@ fn damage_char(this_char: &PacketCharInfo, others_chars: &mut [PacketCharInfo],
     hit
 }
 
-fn recv_once(ambients: &mut Ambients, server: &Server<PacketCharInfo>) {
-    while let Ok(msg) = server.try_recv() {
+fn recv_once(ambients: &mut Ambients, server: &Server<GameMessage>, outbox: &mut Outbox) {
+    while let Ok(Some(msg)) = server.try_recv() {
         match msg {
-            Message::Accepted(addr) => connect_client(ambients, addr, server),
-            Message::Disconnected(addr) => disconnect_client(ambients, addr, server),
-            Message::Direct(packet, addr) => on_message(ambients, packet, addr, server),
+            Message::Accepted(_) => (),
+            Message::Disconnected(addr, e) => {
+                println!("Client {} disconnected: {}", addr, e);
+                disconnect_client(ambients, addr, outbox);
+            },
+            Message::Direct(msg, addr) => match msg {
+                GameMessage::Join { numchar, idmap, interp_delay } => {
+                    connect_client(ambients, addr, (numchar, idmap, interp_delay), outbox)
+                },
+                GameMessage::Input { seq, command } => {
+                    // the attacker sees the others half a round trip and its interpolation delay late
+                    let lag = server.rtt(&addr).unwrap_or_default() / 2 + ambients.interp_delays.get(&addr).copied().unwrap_or_default();
+                    on_message(ambients, seq, command, addr, lag, outbox)
+                },
+                msg @ GameMessage::Chat { .. } => outbox.send(Message::BroadcastExcept(msg, addr)),
+                GameMessage::Disconnect { .. } => disconnect_client(ambients, addr, outbox),
+                GameMessage::Ack { seq, bits } => outbox.ack(addr, seq, bits),
+                msg => println!("Unexpected message from {}: {:?}", addr, msg),
+            },
             _ => unreachable!()
         }
     }
@@ -295,7 +495,8 @@ fn distance(u: &PacketCharInfo, v: &PacketCharInfo) -> f32 {
     (uc.0 - vc.0).hypot(uc.1 - vc.1)
 }
 
-fn move_enemy(enemy: &mut PacketCharInfo, client: &PacketCharInfo, ambient_data: (i16, i16, *const AlBitmap)) {
+/// Moves `enemy` towards `client`, and by `push` away from the enemies around.
+fn move_enemy(enemy: &mut PacketCharInfo, client: &PacketCharInfo, push: (f32, f32), grid: &CollisionGrid) {
     let dx = (client.x + client.w) as f32/2.0 - (enemy.x + enemy.w) as f32/2.0;
     let dy = (client.y + client.h) as f32/2.0 - (enemy.y + enemy.h) as f32/2.0;
 
@@ -318,30 +519,7 @@ fn move_enemy(enemy: &mut PacketCharInfo, client: &PacketCharInfo, ambient_data:
         if mov_y >= 0.0 { DIRECTION_DOWN } else { DIRECTION_UP }
     } as i16;
 
-    let (mov_x, mov_y) = (mov_x.round() as i16, mov_y.round() as i16);
-
-    enemy.x += mov_x;
-    enemy.d = if mov_x >= 0 {
-                DIRECTION_RIGHT
-            } else {
-                DIRECTION_LEFT
-            } as i16;
-
-    if collided(enemy, ambient_data) {
-        enemy.x -= mov_x;
-    }
-
-    enemy.y += mov_y;
-    enemy.d = if mov_y >= 0 {
-                DIRECTION_DOWN
-            } else {
-                DIRECTION_UP
-            } as i16;
-
-    if collided(enemy, ambient_data) {
-        enemy.y -= mov_y;
-    }
-
+    sweep(enemy, ((mov_x + push.0).round() as i32, (mov_y + push.1).round() as i32), grid);
     enemy.d = final_d;
 }
 
@@ -417,166 +595,167 @@ fn intersected(p1: &PacketCharInfo, p2: &PacketCharInfo) -> bool {
     p1.x <= p2_x2 && p2.x <= p1_x2 && p1.y <= p2_y2 && p2.y <= p1_y2
 }
 
-fn game_loop(ambients: &mut Ambients, server: &Server<PacketCharInfo>) {
+/// How often the traffic of each client is logged.
+const STATS_INTERVAL: Duration = Duration::from_secs(10);
+
+fn log_stats(server: &Server<GameMessage>) {
+    for (addr, stats) in server.stats() {
+        println!("Client {}: {}", addr, stats);
+    }
+}
+
+fn game_loop(ambients: &mut Ambients, server: &Server<GameMessage>) {
     let mut lock = 0;
-    let (width, height) = (ambients.width, ambients.height);
+    let mut last_stats = Instant::now();
+    let mut outbox = Outbox::new();
     
     loop {
+        if last_stats.elapsed() >= STATS_INTERVAL {
+            log_stats(server);
+            last_stats = Instant::now();
+        }
+
         let len_chars = ambients.clients.len() as i16;
         let len_enemies = ambients.enemies.len() as i16;
 
         // clean dead enemies
+        for enemy in ambients.enemies.iter().filter(|e| e.exit) {
+            ambients.ids.free(enemy.idchar);
+            ambients.history.forget(enemy.idchar);
+            ambients.interest.despawn(enemy.idchar, DespawnReason::Killed, &mut outbox);
+        }
         ambients.enemies.retain(|e| !e.exit);
 
-        for enemy in ambients.enemies.iter_mut() {
+        ambients.enemy_cells.rebuild(ambients.enemies.iter());
+        ambients.hero_cells.rebuild(ambients.clients.iter());
+
+        let pushes = ambients.crowd.spread(&ambients.enemies, &ambients.enemy_cells);
+        let mut sends = Vec::with_capacity(ambients.enemies.len());
+
+        for (enemy, push) in ambients.enemies.iter_mut().zip(pushes) {
             let mut should_send = true;
-            let ambient_data = (width, height, ambients.models[enemy.idmap as usize]);
+            let grid = &ambients.grids[enemy.idmap as usize - 1];
 
             if enemy.healt <= 0 {
                 // update dead enemies' info
                 enemy.exit = true;
             } else {
                 // detect nearest char to attack
-                let nearest_client = ambients
-                                        .clients
-                                        .iter_mut()
-                                        .filter(|c| c.idmap == enemy.idmap && !c.exit)
-                                        .map(|c| {
-                                            let dist = distance(c, enemy);
-                                            (c, dist)
-                                        })
-                                        .min_by(|c1, c2| c1.1.partial_cmp(&c2.1).unwrap());
-
-                if let Some((client, dist)) = nearest_client {
-                    if dist <= enemy.vision as f32 {
-                        enemy.a = 1;
-
-                        // check if this enemy hit this client
-                        if intersected(enemy, client) {
-                            if dir_damage_chance(enemy, client, 0.5, ambient_data) {
-                                server.send(Message::Broadcast(client.clone()));
-                            }
-                        }
-
-                        // boss moves differently
-                        if enemy.numchar == ambients.boss_num {
-                            should_send = move_boss(enemy, client, &mut lock);
-                        } else {
-                            move_enemy(enemy, client, ambient_data);
-                        }
+                let clients = &ambients.clients;
+                let centre = (enemy.x as f32 + enemy.w as f32 / 2.0, enemy.y as f32 + enemy.h as f32 / 2.0);
+                let nearest_client = ambients.hero_cells.nearest(enemy.idmap, centre, enemy.vision as f32, |i| {
+                    Some(&clients[i]).filter(|c| !c.exit).map(|c| distance(c, enemy))
+                });
+
+                if let Some((i, _)) = nearest_client {
+                    let client = &mut ambients.clients[i];
+                    enemy.a = 1;
+
+                    // check if this enemy hit this client
+                    if intersected(enemy, client) && dir_damage_chance(enemy, client, 0.5, grid) {
+                        outbox.send(Message::Broadcast(GameMessage::EntityState(client.clone())));
+                    }
+
+                    // boss moves differently
+                    if enemy.numchar == ambients.boss_num {
+                        should_send = move_boss(enemy, client, &mut lock);
                     } else {
-                        should_send = false;
-                        enemy.a = 0;
+                        move_enemy(enemy, client, push, grid);
                     }
                 } else {
+                    // nobody in sight
                     should_send = false;
+                    enemy.a = 0;
                 }
             }
 
-            if should_send {
-                enemy.totchar = len_chars;
-                enemy.totenemies = len_enemies;
-                server.send(Message::Broadcast(enemy.clone()));
-            }
+            sends.push(should_send);
         }
 
-        for _ in 0..5 {
-            recv_once(ambients, server);
-            move_chars(ambients, server);
-            thread::sleep(Duration::from_millis(16));
+        // heroes and enemies are solid to each other
+        let masses: Vec<_> = ambients.enemies
+                                .iter()
+                                .map(|e| ambients.enemy_masses.get(&e.numchar).copied().unwrap_or(DEFAULT_MASS))
+                                .chain(ambients.clients.iter().map(|c| ambients.rules.char_def(c.numchar).map_or(DEFAULT_MASS, |def| def.mass)))
+                                .collect();
+        let mut bodies: Vec<_> = ambients.enemies
+                                    .iter_mut()
+                                    .chain(ambients.clients.iter_mut())
+                                    .zip(masses)
+                                    .map(|(packet, mass)| Body { packet, mass })
+                                    .collect();
+
+        for i in separate(&mut bodies, &ambients.grids, &mut ambients.body_cells) {
+            match sends.get_mut(i) {
+                Some(send) => *send = true,
+                None => outbox.send(Message::Broadcast(GameMessage::EntityState(ambients.clients[i - sends.len()].clone()))),
+            }
         }
-    }
-}
 
-fn move_chars(ambients: &mut Ambients, server: &Server<PacketCharInfo>) {
-    for this_char in ambients.clients.iter_mut() {
-        if this_char.a == 1 || this_char.a == 2 {
-            let mov =
-                match this_char.d2 as i32 {
-                    DIRECTION_LEFT => (-this_char.step, 0),
-                    DIRECTION_RIGHT => (this_char.step, 0),
-                    DIRECTION_UP => (0, -this_char.step),
-                    DIRECTION_DOWN => (0, this_char.step),
-                    DIRECTION_LEFTUP => (-this_char.step, -this_char.step),
-                    DIRECTION_RIGHTUP => (this_char.step, -this_char.step),
-                    DIRECTION_LEFTDOWN => (-this_char.step, this_char.step),
-                    DIRECTION_RIGHTDOWN => (this_char.step, this_char.step),
-                    _ => (0, 0)
-                };
+        for (enemy, _) in ambients.enemies.iter_mut().zip(sends).filter(|(_, send)| *send) {
+            enemy.totchar = len_chars;
+            enemy.totenemies = len_enemies;
+            outbox.send(Message::Broadcast(GameMessage::EntityState(enemy.clone())));
+        }
 
-            let ambient_data = (ambients.width, ambients.height, ambients.models[this_char.idmap as usize]);
+        // where the hits of the coming inputs are looked for
+        ambients.enemy_cells.rebuild(ambients.enemies.iter());
 
-            if mov.0 != 0 {
-                this_char.x += mov.0;
-                if collided(this_char, ambient_data) {
-                    this_char.x -= mov.0;
-                }
-            }
+        for _ in 0..5 {
+            recv_once(ambients, server, &mut outbox);
 
-            if mov.1 != 0 {
-                this_char.y += mov.1;
-                if collided(this_char, ambient_data) {
-                    this_char.y -= mov.1;
-                }
-            }
+            let viewers = ambients.clients_addrs.iter().copied().zip(ambients.clients.iter());
+            let entities: Vec<_> = ambients.enemies.iter().chain(ambients.clients.iter()).collect();
+            ambients.interest.update(viewers, &entities, &mut outbox);
 
-            server.send(Message::Broadcast(this_char.clone()));
+            outbox.flush(server, &ambients.clients_addrs, &ambients.interest);
+            ambients.history.record(Instant::now(), ambients.enemies.iter().chain(ambients.clients.iter()));
+            thread::sleep(Duration::from_millis(16));
         }
     }
 }
 
-fn collided(enemy: &PacketCharInfo, ambient_data: (i16, i16, *const AlBitmap)) -> bool {
-    let (width, height, model) = ambient_data;
-
-    if enemy.y < 0 || enemy.x < 0 {
-        return true;
+fn move_char(this_char: &mut PacketCharInfo, grid: &CollisionGrid) {
+    if this_char.a as i32 != ACTION_WALK && this_char.a as i32 != ACTION_RUN {
+        return;
     }
 
-    if (enemy.y + enemy.h) > height as i16 {
-        return true;
-    }
-
-    let colorwall = al_map_rgb(0, 0, 0);
-
-    let we = al_get_bitmap_width(model) as f32;
-    let he = al_get_bitmap_height(model) as f32;
+    sweep(this_char, step_offset(this_char.d2 as i32, this_char.step as i32), grid);
+}
 
-    let sx = we / width as f32;
-    let sy = he / height as f32;
+/// Moves `projectile` one step straight ahead; false when a wall stopped it.
+fn move_projectile(projectile: &mut Projectile, grid: &CollisionGrid) -> bool {
+    let hitbox = (projectile.x as i32, projectile.y as i32, projectile.w as i32, projectile.h as i32);
+    let moved = grid.sweep(hitbox, step_offset(projectile.d as i32, projectile.step as i32));
 
-    let xup   = (enemy.x + enemy.w) as f32 * sx;
-    let yup   = enemy.y as f32 * sy;
+    projectile.x = moved.x as i16;
+    projectile.y = moved.y as i16;
 
-    let xdown = enemy.x as f32 * sx;
-    let ydown = (enemy.y + enemy.h) as f32 * sy;
+    !moved.blocked()
+}
 
-    if xdown >= 0.0 && yup >= 0.0 && xup <= we && ydown <= he {
-        if (enemy.d2 | enemy.d) & (DIRECTION_LEFTDOWN | DIRECTION_UP) as i16 != 0 {
-            let color = al_get_pixel(model, xdown as i32, ydown as i32);
-            if colorwall == color {
-                return true;
-            }
-        }
+fn main() {
+    let mut server = Server::new(handshake());
+    let config_file = ConfigFile::load("assets/Configs/Config.txt");
 
-        if (enemy.d2 | enemy.d) & (DIRECTION_RIGHTDOWN | DIRECTION_UP) as i16 != 0 {
-            let color = al_get_pixel(model, xup as i32, ydown as i32);
-            if colorwall == color {
-                return true;
-            }
-        }
-    } else {
-        return true;
+    if let Some(timeout) = config_file.get("heartbeat_timeout") {
+        server.set_heartbeat(Heartbeat { timeout: Duration::from_millis(timeout), ..Heartbeat::default() });
     }
 
-    return false;
-}
-
-fn main() {
-    al_init();
-    al_init_image_addon();
+    let listening =
+        match Conditions::from_config(&config_file) {
+            Some(conditions) => {
+                println!("Simulating network conditions: {:?}", conditions);
+                let addr = SocketAddr::V4(SocketAddrV4::new(Ipv4Addr::new(127, 0, 0, 1), 34000));
+                server.listen_with(Simulated::new(Tcp, conditions), &addr)
+            },
+            None => server.listen(34000),
+        };
 
-    let mut server = Server::new();
-    server.listen(34000);
+    if let Err(e) = listening {
+        println!("Could not listen on port 34000: {}", e);
+        return;
+    }
 
     let mut ambients = Ambients::load();
     println!("Heredian Server");
@@ -619,4 +798,17 @@ mod test {
         //let (mut p1, mut p2) = (PacketCharInfo::default(), PacketCharInfo::default());
         //dir_damage(&p1, &mut p2);
     }
+
+    #[test]
+    fn first_input_without_direction() {
+        let mut ambients = Ambients::load();
+        let mut outbox = Outbox::new();
+        let addr = "127.0.0.1:5000".parse().unwrap();
+
+        connect_client(&mut ambients, addr, (1, 1, 100), &mut outbox);
+        on_message(&mut ambients, 0, InputCommand { d2: 0, buttons: BUTTON_RUN }, addr, Duration::ZERO, &mut outbox);
+
+        assert_eq!(ambients.clients[0].d, DIRECTION_DOWN as i16);
+        assert_eq!(hit_point(&PacketCharInfo { d: 0, ..ambients.clients[0].clone() }), None);
+    }
 }
\ No newline at end of file
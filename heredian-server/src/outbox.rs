@@ -0,0 +1,222 @@
+use std::collections::{HashMap, HashSet};
+use std::net::SocketAddr;
+
+use heredian_lib::net::{Message, Server};
+use heredian_lib::protocol::{Batcher, DeltaEncoder, GameMessage};
+
+use crate::interest::Interest;
+
+/// Collects everything the server sends during one tick.
+///
+/// On flush, each client gets a single frame with its messages in order, where
+/// only the latest `EntityState` of every entity is kept, sent as a delta against
+/// the last state of that entity the client acknowledged. Broadcasts only reach the
+/// clients they are of interest to.
+#[derive(Default)]
+pub struct Outbox {
+    pending: Vec<Message<GameMessage>>,
+    encoders: HashMap<SocketAddr, DeltaEncoder>,
+}
+
+impl Outbox {
+    pub fn new() -> Outbox {
+        Outbox::default()
+    }
+
+    pub fn send(&mut self, msg: Message<GameMessage>) {
+        self.pending.push(msg);
+    }
+
+    /// Handles an `Ack` from `addr`.
+    pub fn ack(&mut self, addr: SocketAddr, seq: u16, bits: u32) {
+        if let Some(encoder) = self.encoders.get_mut(&addr) {
+            encoder.ack(seq, bits);
+        }
+    }
+
+    /// Splits the pending messages by recipient. `clients` are the broadcast targets.
+    pub fn take(&mut self, clients: &[SocketAddr], interest: &Interest) -> Vec<(SocketAddr, Vec<GameMessage>)> {
+        let mut outgoing: Vec<(SocketAddr, Vec<GameMessage>)> = clients.iter().map(|addr| (*addr, Vec::new())).collect();
+
+        for msg in self.pending.drain(..) {
+            match msg {
+                Message::Broadcast(msg) => {
+                    for (_, msgs) in outgoing.iter_mut().filter(|(addr, _)| interest.sees(*addr, &msg)) {
+                        msgs.push(msg.clone());
+                    }
+                },
+                Message::BroadcastExcept(msg, except) => {
+                    for (_, msgs) in outgoing.iter_mut().filter(|(addr, _)| *addr != except && interest.sees(*addr, &msg)) {
+                        msgs.push(msg.clone());
+                    }
+                },
+                Message::Direct(msg, addr) => {
+                    match outgoing.iter_mut().find(|(a, _)| *a == addr) {
+                        Some((_, msgs)) => msgs.push(msg),
+                        None => outgoing.push((addr, vec![msg])),
+                    }
+                },
+                _ => {},
+            }
+        }
+
+        for (_, msgs) in outgoing.iter_mut() {
+            coalesce(msgs);
+        }
+
+        outgoing.retain(|(_, msgs)| !msgs.is_empty());
+        outgoing
+    }
+
+    /// Sends one frame per client with everything queued since the last flush.
+    pub fn flush(&mut self, server: &Server<GameMessage>, clients: &[SocketAddr], interest: &Interest) {
+        for (addr, msgs) in self.take(clients, interest) {
+            for msg in self.pack(addr, msgs) {
+                server.send(Message::Direct(msg, addr));
+            }
+        }
+
+        self.encoders.retain(|addr, _| clients.contains(addr));
+    }
+
+    /// Encodes the states in `msgs` as deltas and packs everything in frames,
+    /// each one with its own sequence number.
+    fn pack(&mut self, addr: SocketAddr, msgs: Vec<GameMessage>) -> Vec<GameMessage> {
+        let encoder = self.encoders.entry(addr).or_default();
+        let mut batcher = Batcher::new();
+
+        let encode = |encoder: &DeltaEncoder, msg: &GameMessage| match msg {
+            GameMessage::EntityState(state) => encoder.encode(state),
+            msg => msg.clone(),
+        };
+
+        for msg in msgs {
+            let mut out = encode(encoder, &msg);
+
+            if !batcher.fits(&out) {
+                encoder.next_frame();
+                out = encode(encoder, &msg);
+            }
+
+            match msg {
+                GameMessage::EntityState(state) => encoder.sent(state),
+                GameMessage::EntityDespawn { idchar, .. } => encoder.forget(idchar),
+                _ => (),
+            }
+
+            batcher.push(out);
+        }
+
+        encoder.next_frame();
+        batcher.finish()
+    }
+}
+
+/// Drops every `EntityState` that a later one of the same entity overrides,
+/// and every `InputAck` but the last.
+fn coalesce(msgs: &mut Vec<GameMessage>) {
+    let mut seen = HashSet::new();
+    let mut acked = false;
+    let mut keep: Vec<bool> = msgs.iter().rev().map(|msg| match msg {
+        GameMessage::EntityState(packet) => seen.insert(packet.idchar),
+        GameMessage::InputAck { .. } => !std::mem::replace(&mut acked, true),
+        _ => true,
+    }).collect();
+    keep.reverse();
+
+    let mut keep = keep.into_iter();
+    msgs.retain(|_| keep.next().unwrap());
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use heredian_lib::PacketCharInfo;
+    use heredian_lib::protocol::DespawnReason;
+    use heredian_lib::entity::EntityId;
+
+    fn state(idchar: u16, x: i16) -> GameMessage {
+        GameMessage::EntityState(PacketCharInfo { idchar: EntityId::new(idchar, 0), x, ..PacketCharInfo::default() })
+    }
+
+    /// Interest of clients whose heroes are entities 1 and 2, on the same map.
+    fn interest(clients: &[SocketAddr]) -> Interest {
+        let heroes: Vec<_> = (1..=2).map(|idchar| PacketCharInfo { idchar: EntityId::new(idchar, 0), ..PacketCharInfo::default() }).collect();
+        let mut interest = Interest::new(0);
+
+        interest.update(clients.iter().copied().zip(heroes.iter()), &heroes.iter().collect::<Vec<_>>(), &mut Outbox::new());
+        interest
+    }
+
+    #[test]
+    fn keeps_latest_state_per_entity() {
+        let a: SocketAddr = "127.0.0.1:1".parse().unwrap();
+        let b: SocketAddr = "127.0.0.1:2".parse().unwrap();
+        let chat = GameMessage::Chat { idchar: EntityId::new(2, 0), text: "oi".to_owned() };
+
+        let mut outbox = Outbox::new();
+        outbox.send(Message::Broadcast(state(1, 10)));
+        outbox.send(Message::BroadcastExcept(chat.clone(), a));
+        outbox.send(Message::Broadcast(state(2, 5)));
+        outbox.send(Message::Broadcast(state(1, 20)));
+        outbox.send(Message::Broadcast(state(3, 20)));
+        outbox.send(Message::Direct(state(1, 30), b));
+        outbox.send(Message::Direct(GameMessage::InputAck { seq: 4 }, b));
+        outbox.send(Message::Direct(GameMessage::InputAck { seq: 5 }, b));
+
+        let interest = interest(&[a, b]);
+        let outgoing = outbox.take(&[a, b], &interest);
+        assert_eq!(outgoing, vec![
+            (a, vec![state(2, 5), state(1, 20)]),
+            (b, vec![chat, state(2, 5), state(1, 30), GameMessage::InputAck { seq: 5 }]),
+        ]);
+
+        assert!(outbox.take(&[a, b], &interest).is_empty());
+    }
+
+    #[test]
+    fn direct_to_new_client() {
+        let a: SocketAddr = "127.0.0.1:1".parse().unwrap();
+        let welcome = GameMessage::Welcome { idchar: EntityId::new(3, 0), totchar: 1 };
+
+        let mut outbox = Outbox::new();
+        outbox.send(Message::Broadcast(state(1, 10)));
+        outbox.send(Message::Direct(welcome.clone(), a));
+
+        assert_eq!(outbox.take(&[], &interest(&[])), vec![(a, vec![welcome])]);
+    }
+
+    #[test]
+    fn states_are_sent_as_deltas() {
+        let a: SocketAddr = "127.0.0.1:1".parse().unwrap();
+        let mut outbox = Outbox::new();
+
+        let frames = outbox.pack(a, vec![state(1, 10), state(2, 10)]);
+        match &frames[..] {
+            [GameMessage::Batch(msgs)] => assert!(msgs.iter().all(|msg| matches!(msg, GameMessage::EntityDelta { seq: 0, baseline: None, .. }))),
+            frames => panic!("Unexpected frames: {:?}", frames),
+        }
+
+        outbox.ack(a, 0, 0);
+
+        let frames = outbox.pack(a, vec![state(1, 11)]);
+        match &frames[..] {
+            [GameMessage::EntityDelta { seq: 1, baseline: Some(0), delta }] => assert_eq!(delta.idchar, EntityId::new(1, 0)),
+            frames => panic!("Unexpected frames: {:?}", frames),
+        }
+
+        // a despawned entity has nothing left to be encoded against
+        let despawn = GameMessage::EntityDespawn { idchar: EntityId::new(2, 0), reason: DespawnReason::Killed };
+        outbox.pack(a, vec![despawn]);
+        outbox.ack(a, 2, 0b11);
+
+        let frames = outbox.pack(a, vec![state(1, 12), state(2, 12)]);
+        match &frames[..] {
+            [GameMessage::Batch(msgs)] => assert!(matches!(&msgs[..], [
+                GameMessage::EntityDelta { baseline: Some(1), .. },
+                GameMessage::EntityDelta { baseline: None, .. },
+            ])),
+            frames => panic!("Unexpected frames: {:?}", frames),
+        }
+    }
+}
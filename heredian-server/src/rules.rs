@@ -0,0 +1,403 @@
+use std::collections::HashMap;
+use std::fmt;
+use std::path::Path;
+use std::time::{Duration, Instant};
+
+use heredian_lib::{PacketCharInfo, ACTION_ATTACK, ACTION_IDLE, MAXCHARLIFELESS};
+use heredian_lib::file_manager::ConfigFile;
+use heredian_lib::protocol::InputCommand;
+
+use crate::bodies::DEFAULT_MASS;
+
+/// Frame rate of the client, which sends one input per frame.
+const CLIENT_FPS: f32 = 60.0;
+
+/// Moving or attacking inputs accepted per second, a little above `CLIENT_FPS` for clock drift.
+const ACTIONS_PER_SEC: f32 = CLIENT_FPS * 1.1;
+
+/// Moving or attacking inputs that may arrive at once after a network hiccup.
+const ACTIONS_BURST: f32 = 20.0;
+
+/// How much sooner than its cooldown a projectile may come, for inputs bunched up by the network.
+const JITTER_ALLOWANCE: Duration = Duration::from_millis(100);
+
+/// What a hero can do during one of its actions, from its `act_*.txt`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ActionDef {
+    /// Hit box, the sprite size less the `rebate` margins.
+    pub w: i16,
+    pub h: i16,
+    /// Drawn size, which gates are checked against.
+    pub wd: i16,
+    pub hd: i16,
+    pub step: i16,
+    pub damage: i16,
+    pub lifelessid: Option<i16>,
+    /// Time between two projectiles, the length of the animation.
+    pub cooldown: Duration,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct CharDef {
+    pub healt: i16,
+    pub stamina: i16,
+    /// How hard the hero is to push around.
+    pub mass: f32,
+    pub actions: Vec<ActionDef>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct LifelessDef {
+    pub w: i16,
+    pub h: i16,
+    pub step: i16,
+    pub damage: i16,
+}
+
+/// Heroes and projectiles definitions, the same the client loads.
+#[derive(Debug, Default)]
+pub struct Rules {
+    chars: HashMap<i16, CharDef>,
+    lifeless: HashMap<i16, LifelessDef>,
+}
+
+fn load_list<T>(path: &str, load: impl Fn(&ConfigFile) -> T) -> HashMap<i16, T> {
+    let list = ConfigFile::load(Path::new(path));
+    let mut defs = HashMap::new();
+
+    for id in 1.. {
+        match list.get_string(&id.to_string()) {
+            Some(path) => defs.insert(id, load(&ConfigFile::load(path))),
+            None => break,
+        };
+    }
+
+    defs
+}
+
+impl ActionDef {
+    fn load(config_file: &ConfigFile, (scale_w, scale_h): (f32, f32)) -> ActionDef {
+        let size_x: f32 = config_file.get("size_x").expect("size_x not found.");
+        let size_y: f32 = config_file.get("size_y").expect("size_y not found.");
+        let qt_sprites: f32 = config_file.get("qt_sprites").expect("qt_sprites not found.");
+        let fps: f32 = config_file.get("fps").expect("fps not found.");
+
+        let (wd, hd) = ((size_x * scale_w) as i16, (size_y * scale_h) as i16);
+
+        ActionDef {
+            w: wd - config_file.get("rebatex").unwrap_or(0),
+            h: hd - config_file.get("rebatey").unwrap_or(0),
+            wd,
+            hd,
+            step: config_file.get("stepx").expect("stepx not found."),
+            damage: config_file.get("damage").expect("damage not found."),
+            lifelessid: config_file.get::<i16>("lifelessid").filter(|id| *id > 0),
+            // the animation unlocks on its last sprite
+            cooldown: if fps > 0.0 { Duration::from_secs_f32((qt_sprites - 1.0).max(0.0) / fps) } else { Duration::from_secs(0) },
+        }
+    }
+}
+
+fn load_actions(config_file: &ConfigFile) -> Vec<ActionDef> {
+    let scale = (
+        config_file.get("scale_w").expect("scale_w not found."),
+        config_file.get("scale_h").expect("scale_h not found."),
+    );
+    let action_number = config_file.get("action_number").expect("action_number not found.");
+
+    (0..action_number)
+        .map(|i: i32| {
+            let path = config_file.get_string(&format!("act_{}", i)).expect("act not found.");
+            ActionDef::load(&ConfigFile::load(path), scale)
+        })
+        .collect()
+}
+
+impl Rules {
+    pub fn load() -> Rules {
+        let chars = load_list("assets/Configs/Chars.txt", |config_file| CharDef {
+            healt: config_file.get("healtfull").expect("healtfull not found."),
+            stamina: config_file.get("staminafull").expect("staminafull not found."),
+            mass: config_file.get("mass").unwrap_or(DEFAULT_MASS),
+            actions: load_actions(config_file),
+        });
+
+        let lifeless = load_list("assets/Configs/Lifeless.txt", |config_file| {
+            let action = &load_actions(config_file)[0];
+            LifelessDef { w: action.w, h: action.h, step: action.step, damage: action.damage }
+        });
+
+        Rules { chars, lifeless }
+    }
+
+    pub fn char_def(&self, numchar: i16) -> Option<&CharDef> {
+        self.chars.get(&numchar)
+    }
+}
+
+/// Something a client tried that its hero is not allowed to do.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Violation {
+    UnknownChar(i16),
+    UnknownMap(i16),
+    UnknownAction(i16),
+    TooFast,
+}
+
+impl fmt::Display for Violation {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Violation::UnknownChar(numchar) => write!(f, "unknown char {}", numchar),
+            Violation::UnknownMap(idmap) => write!(f, "unknown map {}", idmap),
+            Violation::UnknownAction(a) => write!(f, "unknown action {}", a),
+            Violation::TooFast => write!(f, "acting faster than allowed"),
+        }
+    }
+}
+
+/// A lifeless fired by a hero, flying straight until it hits a wall.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Projectile {
+    pub lifelessid: i16,
+    pub x: i16,
+    pub y: i16,
+    pub w: i16,
+    pub h: i16,
+    pub d: i16,
+    pub step: i16,
+    pub damage: i16,
+}
+
+/// What the hero does with one input.
+#[derive(Debug, PartialEq)]
+pub struct Checked {
+    /// Action taken, one of `ACTION_*`.
+    pub a: i16,
+    pub action: ActionDef,
+    /// Whether the hero may move one step.
+    pub moves: bool,
+    /// Whether the hero may strike.
+    pub attacks: bool,
+    /// Projectile fired with this input, at the hero's position.
+    pub fired: Option<Projectile>,
+    /// What was dropped from the input.
+    pub violations: Vec<Violation>,
+}
+
+/// Tracks what a client did, to hold its hero to the rules.
+pub struct Guard {
+    numchar: i16,
+    last_input: Instant,
+    /// Moving or attacking inputs the client may send right now.
+    actions: f32,
+    last_spawn: Option<Instant>,
+}
+
+impl Guard {
+    pub fn new(rules: &Rules, numchar: i16, now: Instant) -> Result<Guard, Violation> {
+        rules.char_def(numchar).ok_or(Violation::UnknownChar(numchar))?;
+
+        Ok(Guard {
+            numchar,
+            last_input: now,
+            actions: ACTIONS_BURST,
+            last_spawn: None,
+        })
+    }
+
+    /// Works out what `hero`, with `flying` projectiles, does with `command`.
+    ///
+    /// Fails when nothing of it can be used, otherwise the violations are what was dropped.
+    pub fn check(&mut self, rules: &Rules, hero: &PacketCharInfo, flying: usize, command: &InputCommand, now: Instant) -> Result<Checked, Violation> {
+        let char_def = rules.char_def(self.numchar).ok_or(Violation::UnknownChar(self.numchar))?;
+
+        // exhausted heroes stand still
+        let a = if hero.stamina <= 0 { ACTION_IDLE } else { command.action() } as i16;
+        let action = char_def.actions.get(a as usize).ok_or(Violation::UnknownAction(a))?;
+
+        let elapsed = now.saturating_duration_since(self.last_input);
+        self.last_input = now;
+
+        let mut violations = Vec::new();
+
+        // one step or strike per input, at the client's frame rate at most
+        self.actions = (self.actions + elapsed.as_secs_f32() * ACTIONS_PER_SEC).min(ACTIONS_BURST);
+
+        let mut moves = action.step > 0 && command.d2 != 0;
+        let mut attacks = a == ACTION_ATTACK as i16;
+        if moves || attacks {
+            if self.actions >= 1.0 {
+                self.actions -= 1.0;
+            } else {
+                moves = false;
+                attacks = false;
+                violations.push(Violation::TooFast);
+            }
+        }
+
+        let fired =
+            match action.lifelessid.and_then(|id| rules.lifeless.get(&id).map(|def| (id, def))) {
+                Some((lifelessid, def)) if attacks && flying < MAXCHARLIFELESS && self.ready(action, now) => {
+                    self.last_spawn = Some(now);
+
+                    Some(Projectile {
+                        lifelessid,
+                        x: hero.x,
+                        y: hero.y,
+                        w: def.w,
+                        h: def.h,
+                        d: command.facing().map_or(hero.d, |d| d as i16),
+                        step: def.step,
+                        damage: def.damage,
+                    })
+                },
+                _ => None,
+            };
+
+        Ok(Checked { a, action: action.clone(), moves, attacks, fired, violations })
+    }
+
+    /// Whether the animation of the last projectile is over at `now`.
+    fn ready(&self, action: &ActionDef, now: Instant) -> bool {
+        match self.last_spawn {
+            Some(last_spawn) => now.saturating_duration_since(last_spawn) + JITTER_ALLOWANCE >= action.cooldown,
+            None => true,
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use heredian_lib::*;
+    use heredian_lib::protocol::{BUTTON_ATTACK, BUTTON_RUN};
+
+    fn rules() -> Rules {
+        let action = |step, lifelessid| ActionDef {
+            w: 30,
+            h: 30,
+            wd: 40,
+            hd: 40,
+            step,
+            damage: 1,
+            lifelessid,
+            cooldown: Duration::from_millis(300),
+        };
+
+        let mut rules = Rules::default();
+        rules.chars.insert(1, CharDef {
+            healt: 1000,
+            stamina: 100,
+            mass: 20.0,
+            actions: vec![action(0, None), action(1, None), action(5, None), action(0, Some(1))],
+        });
+        rules.lifeless.insert(1, LifelessDef { w: 20, h: 20, step: 2, damage: 1 });
+        rules
+    }
+
+    fn command(d2: i32, buttons: u8) -> InputCommand {
+        InputCommand { d2: d2 as u8, buttons }
+    }
+
+    #[test]
+    fn loads_server_configs() {
+        let rules = Rules::load();
+        let james = rules.char_def(1).unwrap();
+
+        assert_eq!(james.healt, 1000);
+        assert_eq!(james.mass, 20.0);
+        assert_eq!(james.actions[2].step, 5);
+        assert_eq!((james.actions[1].w, james.actions[1].h), (46 - 8, 39 - 4));
+        assert_eq!((james.actions[1].wd, james.actions[1].hd), (46, 39));
+        assert_eq!(james.actions[3].lifelessid, Some(1));
+        assert_eq!(rules.lifeless[&2].damage, 300);
+        assert_eq!(rules.char_def(5), None);
+    }
+
+    #[test]
+    fn moves_are_rate_limited() {
+        let rules = rules();
+        let start = Instant::now();
+        let mut guard = Guard::new(&rules, 1, start).unwrap();
+
+        assert_eq!(Guard::new(&rules, 9, start).err(), Some(Violation::UnknownChar(9)));
+
+        let hero = PacketCharInfo { stamina: 100, ..PacketCharInfo::default() };
+        let run = command(DIRECTION_DOWN, BUTTON_RUN);
+
+        for _ in 0..ACTIONS_BURST as usize {
+            let checked = guard.check(&rules, &hero, 0, &run, start).unwrap();
+            assert!(checked.moves);
+            assert_eq!((checked.a, checked.action.step), (ACTION_RUN as i16, 5));
+        }
+
+        let checked = guard.check(&rules, &hero, 0, &run, start).unwrap();
+        assert!(!checked.moves);
+        assert_eq!(checked.violations, vec![Violation::TooFast]);
+
+        let later = start + Duration::from_millis(100);
+        assert!(guard.check(&rules, &hero, 0, &run, later).unwrap().moves);
+
+        // nothing held, or no stamina left, is standing still
+        let checked = guard.check(&rules, &hero, 0, &command(0, BUTTON_RUN), later).unwrap();
+        assert_eq!((checked.a, checked.moves), (ACTION_IDLE as i16, false));
+
+        let tired = PacketCharInfo { stamina: 0, ..hero };
+        let checked = guard.check(&rules, &tired, 0, &run, later).unwrap();
+        assert_eq!((checked.a, checked.moves), (ACTION_IDLE as i16, false));
+    }
+
+    #[test]
+    fn attacks_fire_projectiles() {
+        let rules = rules();
+        let start = Instant::now();
+        let mut guard = Guard::new(&rules, 1, start).unwrap();
+        let hero = PacketCharInfo { x: 100, y: 100, d: DIRECTION_UP as i16, stamina: 100, ..PacketCharInfo::default() };
+        let attack = command(0, BUTTON_ATTACK);
+
+        // walking fires nothing
+        let checked = guard.check(&rules, &hero, 0, &command(DIRECTION_LEFT, 0), start).unwrap();
+        assert_eq!(checked.fired, None);
+
+        // sizes and damage come from the definition
+        let checked = guard.check(&rules, &hero, 0, &attack, start).unwrap();
+        assert_eq!(checked.a, ACTION_ATTACK as i16);
+        assert_eq!(checked.fired, Some(Projectile { lifelessid: 1, x: 100, y: 100, w: 20, h: 20, d: DIRECTION_UP as i16, step: 2, damage: 1 }));
+
+        // then once per animation of 300ms, give or take the jitter, however many inputs come
+        let ms = |millis| start + Duration::from_millis(millis);
+        let fired = (1..=35).filter(|&i| guard.check(&rules, &hero, 1, &attack, ms(i * 1000 / 60)).unwrap().fired.is_some()).count();
+        assert_eq!(fired, 2);
+        assert!(guard.check(&rules, &hero, 1, &attack, ms(550)).unwrap().fired.is_none());
+        assert!(guard.check(&rules, &hero, 1, &attack, ms(600)).unwrap().fired.is_some());
+
+        // while the hero has room for more
+        assert!(guard.check(&rules, &hero, MAXCHARLIFELESS, &attack, ms(2000)).unwrap().fired.is_none());
+
+        let checked = guard.check(&rules, &hero, 0, &command(DIRECTION_RIGHT, BUTTON_ATTACK), ms(2000)).unwrap();
+        assert_eq!(checked.fired.map(|p| p.d), Some(DIRECTION_RIGHT as i16));
+    }
+
+    #[test]
+    fn attacks_are_rate_limited() {
+        let rules = rules();
+        let start = Instant::now();
+        let mut guard = Guard::new(&rules, 1, start).unwrap();
+        let hero = PacketCharInfo { stamina: 100, ..PacketCharInfo::default() };
+        let attack = command(0, BUTTON_ATTACK);
+
+        // strikes share the budget of the steps
+        for _ in 0..ACTIONS_BURST as usize / 2 {
+            assert!(guard.check(&rules, &hero, 0, &command(DIRECTION_DOWN, 0), start).unwrap().moves);
+            assert!(guard.check(&rules, &hero, 0, &attack, start).unwrap().attacks);
+        }
+
+        let checked = guard.check(&rules, &hero, MAXCHARLIFELESS, &attack, start).unwrap();
+        assert_eq!((checked.attacks, checked.fired), (false, None));
+        assert_eq!(checked.violations, vec![Violation::TooFast]);
+
+        // standing still costs nothing
+        assert!(guard.check(&rules, &hero, 0, &command(0, 0), start).unwrap().violations.is_empty());
+        assert!(guard.check(&rules, &hero, 0, &attack, start + Duration::from_millis(100)).unwrap().attacks);
+    }
+}
@@ -0,0 +1,217 @@
+use heredian_lib::PacketCharInfo;
+
+/// Heroes or enemies bucketed by map and by cell of a uniform grid, to find the ones
+/// around a place without going through them all.
+///
+/// Entities are known by their index in the list the grid was last built from, and are
+/// in every cell their hit box touches. Queries give candidates, which the caller checks.
+pub struct SpatialGrid {
+    cell: f32,
+    cols: i32,
+    rows: i32,
+    /// Entities in each cell of each map, `idmap - 1`.
+    maps: Vec<Vec<Vec<usize>>>,
+    /// How many entities are on each map.
+    counts: Vec<usize>,
+}
+
+impl SpatialGrid {
+    /// Grid of `cell` sized cells over `maps` maps of `size`.
+    pub fn new(cell: f32, (width, height): (f32, f32), maps: usize) -> SpatialGrid {
+        let cols = (width / cell).ceil().max(1.0) as i32;
+        let rows = (height / cell).ceil().max(1.0) as i32;
+
+        SpatialGrid {
+            cell,
+            cols,
+            rows,
+            maps: vec![vec![Vec::new(); (cols * rows) as usize]; maps],
+            counts: vec![0; maps],
+        }
+    }
+
+    /// Cell of a point, the border ones for points off the map.
+    fn cell_of(&self, x: f32, y: f32) -> (i32, i32) {
+        (((x / self.cell).floor() as i32).clamp(0, self.cols - 1), ((y / self.cell).floor() as i32).clamp(0, self.rows - 1))
+    }
+
+    fn map(&self, idmap: i16) -> Option<&Vec<Vec<usize>>> {
+        self.maps.get((idmap as usize).wrapping_sub(1))
+    }
+
+    /// Cells the box `(x, y, w, h)` touches, as two corners.
+    fn span(&self, (x, y, w, h): (f32, f32, f32, f32)) -> ((i32, i32), (i32, i32)) {
+        (self.cell_of(x, y), self.cell_of(x + w, y + h))
+    }
+
+    /// Forgets where everything was and puts `entities` in, known by their position in it.
+    pub fn rebuild<'a>(&mut self, entities: impl IntoIterator<Item = &'a PacketCharInfo>) {
+        for cells in self.maps.iter_mut() {
+            cells.iter_mut().for_each(Vec::clear);
+        }
+        self.counts.iter_mut().for_each(|count| *count = 0);
+
+        for (i, packet) in entities.into_iter().enumerate() {
+            let m = (packet.idmap as usize).wrapping_sub(1);
+            if m >= self.maps.len() {
+                continue;
+            }
+
+            let ((x1, y1), (x2, y2)) = self.span((packet.x as f32, packet.y as f32, packet.w as f32, packet.h as f32));
+            for row in y1..=y2 {
+                for col in x1..=x2 {
+                    self.maps[m][(row * self.cols + col) as usize].push(i);
+                }
+            }
+
+            self.counts[m] += 1;
+        }
+    }
+
+    /// Entities on `idmap` that may touch the box `(x, y, w, h)`, by index, each once.
+    pub fn near(&self, idmap: i16, area: (f32, f32, f32, f32)) -> Vec<usize> {
+        let cells = match self.map(idmap) {
+            Some(cells) => cells,
+            None => return Vec::new(),
+        };
+
+        let ((x1, y1), (x2, y2)) = self.span(area);
+        let mut found = Vec::new();
+
+        for row in y1..=y2 {
+            for col in x1..=x2 {
+                found.extend_from_slice(&cells[(row * self.cols + col) as usize]);
+            }
+        }
+
+        found.sort_unstable();
+        found.dedup();
+        found
+    }
+
+    /// Entities on `idmap` within `reach` of `(x, y)` on each axis, by index.
+    pub fn around(&self, idmap: i16, (x, y): (f32, f32), reach: f32) -> Vec<usize> {
+        self.near(idmap, (x - reach, y - reach, reach * 2.0, reach * 2.0))
+    }
+
+    /// The entity on `idmap` closest to `(x, y)` by `distance`, if one is within `reach`.
+    ///
+    /// `distance` is `None` for entities that don't count. Cells are searched in rings out
+    /// from the point, until no farther ring can hold anything closer, so `distance` has
+    /// to be at least the distance from the point to the entity's hit box.
+    pub fn nearest(&self, idmap: i16, (x, y): (f32, f32), reach: f32, distance: impl Fn(usize) -> Option<f32>) -> Option<(usize, f32)> {
+        let cells = self.map(idmap)?;
+        if self.counts[(idmap - 1) as usize] == 0 {
+            return None;
+        }
+
+        let (cx, cy) = self.cell_of(x, y);
+        let rings = ((reach / self.cell).ceil() as i32 + 1).min(self.cols.max(self.rows));
+        let mut best: Option<(usize, f32)> = None;
+
+        for r in 0..=rings {
+            // whatever is in ring r is at least (r - 1) cells away
+            if best.is_some_and(|(_, d)| d <= (r - 1) as f32 * self.cell) {
+                break;
+            }
+
+            for row in (cy - r).max(0)..=(cy + r).min(self.rows - 1) {
+                for col in (cx - r).max(0)..=(cx + r).min(self.cols - 1) {
+                    if (row - cy).abs() != r && (col - cx).abs() != r {
+                        continue;
+                    }
+
+                    for &i in cells[(row * self.cols + col) as usize].iter() {
+                        match distance(i) {
+                            Some(d) if d <= reach && best.is_none_or(|(b, bd)| d < bd || (d == bd && i < b)) => best = Some((i, d)),
+                            _ => (),
+                        }
+                    }
+                }
+            }
+        }
+
+        best
+    }
+}
+
+/// Mutable references to the items of `slice` at `indices`, which have to be sorted and unique.
+pub fn pick_mut<'a, T>(mut slice: &'a mut [T], indices: &[usize]) -> Vec<&'a mut T> {
+    let mut picked = Vec::with_capacity(indices.len());
+    let mut offset = 0;
+
+    for &i in indices {
+        let (item, rest) = std::mem::take(&mut slice)[i - offset..].split_first_mut().expect("index out of the slice");
+        picked.push(item);
+        slice = rest;
+        offset = i + 1;
+    }
+
+    picked
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn body(x: i16, y: i16, idmap: i16) -> PacketCharInfo {
+        PacketCharInfo { x, y, w: 10, h: 10, idmap, ..PacketCharInfo::default() }
+    }
+
+    fn centre_distance(entities: &[PacketCharInfo], (x, y): (f32, f32)) -> impl Fn(usize) -> Option<f32> + '_ {
+        move |i| {
+            let e = &entities[i];
+            Some((e.x as f32 + 5.0 - x).hypot(e.y as f32 + 5.0 - y))
+        }
+    }
+
+    #[test]
+    fn finds_what_is_around() {
+        let entities = [body(5, 5, 1), body(28, 5, 1), body(75, 75, 1), body(5, 5, 2), body(0, 0, 9)];
+        let mut grid = SpatialGrid::new(20.0, (100.0, 100.0), 2);
+        grid.rebuild(entities.iter());
+
+        // the second one straddles two cells, and is found once
+        assert_eq!(grid.near(1, (0.0, 0.0, 39.0, 19.0)), vec![0, 1]);
+        assert_eq!(grid.around(1, (80.0, 80.0), 5.0), vec![2]);
+        assert_eq!(grid.around(2, (10.0, 10.0), 1.0), vec![3]);
+        assert!(grid.around(9, (10.0, 10.0), 1.0).is_empty());
+
+        grid.rebuild(entities[2..].iter());
+        assert_eq!(grid.around(1, (80.0, 80.0), 5.0), vec![0]);
+        assert!(grid.around(1, (10.0, 10.0), 1.0).is_empty());
+    }
+
+    #[test]
+    fn nearest_within_reach() {
+        let entities = [body(5, 5, 1), body(60, 60, 1), body(90, 5, 1), body(0, 0, 2)];
+        let mut grid = SpatialGrid::new(20.0, (100.0, 100.0), 2);
+        grid.rebuild(entities.iter());
+
+        let point = (70.0, 70.0);
+        assert_eq!(grid.nearest(1, point, 100.0, centre_distance(&entities, point)).map(|(i, _)| i), Some(1));
+
+        let point = (95.0, 40.0);
+        assert_eq!(grid.nearest(1, point, 100.0, centre_distance(&entities, point)).map(|(i, _)| i), Some(2));
+        assert_eq!(grid.nearest(1, point, 10.0, centre_distance(&entities, point)), None);
+
+        // skipping some
+        let distance = centre_distance(&entities, point);
+        assert_eq!(grid.nearest(1, point, 100.0, |i| if i == 2 { None } else { distance(i) }).map(|(i, _)| i), Some(1));
+
+        let point = (10.0, 10.0);
+        assert_eq!(grid.nearest(2, point, 1000.0, centre_distance(&entities, point)).map(|(i, _)| i), Some(3));
+    }
+
+    #[test]
+    fn picks_apart() {
+        let mut items = [0, 1, 2, 3, 4];
+
+        for item in pick_mut(&mut items, &[1, 2, 4]) {
+            *item += 10;
+        }
+
+        assert_eq!(items, [0, 11, 12, 3, 14]);
+        assert!(pick_mut(&mut items, &[]).is_empty());
+    }
+}
@@ -0,0 +1,71 @@
+use std::path::Path;
+
+use heredian_lib::file_manager::ConfigFile;
+
+/// Passage to another map, crossed by stepping into its area.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Gate {
+    pub x1: i16,
+    pub y1: i16,
+    pub x2: i16,
+    pub y2: i16,
+    pub idmap: i16,
+    /// Where heroes come out on the other map.
+    pub ex: i16,
+    pub ey: i16,
+}
+
+/// Entrance and gates of a map, from the same `config.txt` the client loads.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Stage {
+    pub ex: i16,
+    pub ey: i16,
+    pub gates: Vec<Gate>,
+}
+
+impl Stage {
+    pub fn load(path: impl AsRef<Path>) -> Stage {
+        let config_file = ConfigFile::load(path.as_ref());
+        let num_gates = config_file.get("num_gates").expect("num_gates not found.");
+
+        let gates = (1..=num_gates)
+            .map(|i: i32| {
+                let get = |key: &str| config_file.get(&format!("gate{}_{}", i, key)).unwrap_or_else(|| panic!("gate{}_{} not found.", i, key));
+
+                Gate { x1: get("x1"), y1: get("y1"), x2: get("x2"), y2: get("y2"), idmap: get("map"), ex: get("ex"), ey: get("ey") }
+            })
+            .collect();
+
+        Stage {
+            ex: config_file.get("ex").expect("ex not found."),
+            ey: config_file.get("ey").expect("ey not found."),
+            gates,
+        }
+    }
+
+    /// Gate crossed by a hero drawn `(wd, hd)` at `(x, y)`, its feet inside the gate.
+    pub fn crossed_gate(&self, (x, y): (i16, i16), (wd, hd): (i16, i16)) -> Option<&Gate> {
+        self.gates.iter().find(|gate| {
+            x + wd >= gate.x1 && x <= gate.x2 && (gate.y1..=gate.y2).contains(&(y + hd))
+        })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn gates_of_a_stage() {
+        let stage = Stage::load("assets/Stages/Fase2/config.txt");
+
+        assert_eq!((stage.ex, stage.ey), (100, 100));
+        assert_eq!(stage.gates.len(), 2);
+        assert_eq!(stage.gates[0], Gate { x1: 0, y1: 55, x2: 3, y2: 105, idmap: 1, ex: 750, ey: 53 });
+
+        assert_eq!(stage.crossed_gate((100, 40), (40, 40)), None);
+        assert_eq!(stage.crossed_gate((2, 40), (40, 40)).map(|gate| gate.idmap), Some(1));
+        assert_eq!(stage.crossed_gate((2, 70), (40, 40)), None);
+        assert_eq!(stage.crossed_gate((760, 30), (40, 40)).map(|gate| gate.idmap), Some(3));
+    }
+}